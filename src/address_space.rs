@@ -1,22 +1,647 @@
-use std::collections::LinkedList;
-use std::iter::Map;
-use std::sync::Arc;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 
+use crate::cow::{CowShares, Sharing};
 use crate::data_source::DataSource;
+use crate::error::MappingError;
+use crate::permissions::Permissions;
+use crate::resident::ResidentSet;
+use crate::sources::{AnonymousSource, MemorySource};
+use crate::stats::AccessCounters;
+#[cfg(feature = "stats")]
+use crate::stats::AccessStats;
+use crate::sync::{Mutex, RwLock};
+use crate::virtual_address::VirtualAddress;
 
-type VirtualAddress = usize;
+/// The granularity at which mappings are placed and sized.
+pub const PAGE_SIZE: usize = 4096;
 
+/// The size of the pages that make up mappings added by `AddressSpace::add_mapping_huge`.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+// How many entries the standard library's `BTreeMap` keeps in each node, and roughly how big a
+// leaf node of mappings is: its entries plus a parent pointer and two `u16` counts. Neither is
+// promised by `BTreeMap`, so they're only good for estimates like `structure_bytes`.
+const BTREE_NODE_ENTRIES: usize = 11;
+const BTREE_NODE_BYTES: usize = BTREE_NODE_ENTRIES
+    * (core::mem::size_of::<VirtualAddress>() + core::mem::size_of::<MapEntry>())
+    + core::mem::size_of::<usize>()
+    + 2 * core::mem::size_of::<u16>();
+
+/// Round `span` up to a whole number of pages, or `None` if that would overflow.
+const fn page_align(span: usize) -> Option<usize> {
+    span.checked_next_multiple_of(PAGE_SIZE)
+}
+
+/// Check that `span` bytes of `source` from `offset` on are something that can be mapped: at
+/// least one byte, and all within the source. This is checked before `span` is rounded up to
+/// whole pages.
+fn check_span(source: &dyn DataSource, offset: usize, span: usize) -> Result<(), MappingError> {
+    if span == 0 {
+        return Err(MappingError::InvalidSpan);
+    }
+    let source_len = source.len();
+    match offset.checked_add(span) {
+        Some(needed) if needed <= source_len => Ok(()),
+        needed => Err(MappingError::SourceTooSmall {
+            source_len,
+            needed: needed.unwrap_or(usize::MAX),
+        }),
+    }
+}
+
+/// Check that a mapping of `span` bytes, a whole number of pages, could have been made of
+/// `source` from `offset` on, i.e. that its last page starts within the source. This is the
+/// check for a mapping whose span has already been rounded up.
+fn check_pages(source: &dyn DataSource, offset: usize, span: usize) -> Result<(), MappingError> {
+    check_span(source, offset, span.saturating_sub(PAGE_SIZE - 1))
+}
+
+/// Fill `buf` from `source` at `offset`, for the bytes mapped at `addr`. A read that fails, or
+/// stops short at the end of the source, is `Source` naming the first byte not read.
+fn read_source(
+    source: &dyn DataSource,
+    offset: usize,
+    buf: &mut [u8],
+    addr: VirtualAddress,
+) -> Result<(), MappingError> {
+    match source.read_at(offset, buf) {
+        Ok(read) if read == buf.len() => Ok(()),
+        Ok(read) => Err(MappingError::Source { addr: addr + read }),
+        Err(_) => Err(MappingError::Source { addr }),
+    }
+}
+
+/// Store `buf` in `source` at `offset`, for the bytes mapped at `addr`, failing like
+/// `read_source`.
+fn write_source(
+    source: &dyn DataSource,
+    offset: usize,
+    buf: &[u8],
+    addr: VirtualAddress,
+) -> Result<(), MappingError> {
+    match source.write_at(offset, buf) {
+        Ok(written) if written == buf.len() => Ok(()),
+        Ok(written) => Err(MappingError::Source {
+            addr: addr + written,
+        }),
+        Err(_) => Err(MappingError::Source { addr }),
+    }
+}
+
+/// One bit per page of a mapping, set for each page that has been written.
+#[derive(Clone, Default)]
+struct DirtyBitmap(Vec<u64>);
+
+impl DirtyBitmap {
+    fn set(&mut self, page: usize) {
+        let (word, bit) = (page / 64, page % 64);
+        if word >= self.0.len() {
+            self.0.resize(word + 1, 0);
+        }
+        self.0[word] |= 1 << bit;
+    }
+
+    /// The set pages, in ascending order.
+    fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(word, &bits)| {
+            (0..64)
+                .filter(move |bit| bits & (1 << bit) != 0)
+                .map(move |bit| word * 64 + bit)
+        })
+    }
+
+    /// Forget every page from `pages` on.
+    fn truncate(&mut self, pages: usize) {
+        *self = self.iter().filter(|&page| page < pages).collect();
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl Extend<usize> for DirtyBitmap {
+    fn extend<I: IntoIterator<Item = usize>>(&mut self, pages: I) {
+        for page in pages {
+            self.set(page);
+        }
+    }
+}
+
+impl FromIterator<usize> for DirtyBitmap {
+    fn from_iter<I: IntoIterator<Item = usize>>(pages: I) -> Self {
+        let mut bitmap = Self::default();
+        bitmap.extend(pages);
+        bitmap
+    }
+}
+
+/// The source behind a mapping, held without keeping it alive if `add_mapping_weak` made the
+/// mapping.
+#[derive(Clone)]
+enum Backing {
+    Strong(Arc<dyn DataSource>),
+    Weak(Weak<dyn DataSource>),
+}
+
+impl Backing {
+    /// The source, unless it was held weakly and has been dropped since.
+    fn upgrade(&self) -> Option<Arc<dyn DataSource>> {
+        match self {
+            Self::Strong(source) => Some(source.clone()),
+            Self::Weak(source) => source.upgrade(),
+        }
+    }
+
+    /// The source, or `SourceGone` naming `addr` if it has been dropped.
+    fn get(&self, addr: VirtualAddress) -> Result<Arc<dyn DataSource>, MappingError> {
+        self.upgrade().ok_or(MappingError::SourceGone { addr })
+    }
+
+    /// Call `f` on the source, failing with `SourceGone` naming `addr` if it has been dropped.
+    /// A strongly held source is lent out as it is, without touching its count.
+    fn with<T>(
+        &self,
+        addr: VirtualAddress,
+        f: impl FnOnce(&dyn DataSource) -> Result<T, MappingError>,
+    ) -> Result<T, MappingError> {
+        match self {
+            Self::Strong(source) => f(&**source),
+            Self::Weak(_) => f(&*self.get(addr)?),
+        }
+    }
+
+    /// Where the source is, the same pointer that upgrading gives, to tell whether two
+    /// mappings share it. A weakly held source keeps its address after it is dropped, since
+    /// the `Weak` keeps the allocation, so mappings of it can still be told apart.
+    fn as_ptr(&self) -> *const dyn DataSource {
+        match self {
+            Self::Strong(source) => Arc::as_ptr(source),
+            Self::Weak(source) => source.as_ptr(),
+        }
+    }
+
+    /// Whether this is `source` itself, rather than merely an equal source.
+    fn is<D: DataSource + ?Sized>(&self, source: &D) -> bool {
+        core::ptr::addr_eq(self.as_ptr(), source)
+    }
+}
+
+#[derive(Clone)]
 struct MapEntry {
-    source: Arc<dyn DataSource>,
+    source: Backing,
     offset: usize,
     span: usize,
-    addr: usize,
+    addr: VirtualAddress,
+    perms: Permissions,
+    /// The count of address spaces sharing `source`, if `fork` has shared it so that it must be
+    /// copied before it is written.
+    cow: Option<Arc<Sharing>>,
+    /// The number of bytes below `addr` kept unmapped as guard pages, so that running off the
+    /// bottom of the mapping faults instead of reaching whatever is below.
+    guard: usize,
+    /// The pages written through this mapping, counting from the page that holds `addr`.
+    dirty: DirtyBitmap,
+    stats: AccessCounters,
+    /// Whether this mapping was made of `HUGE_PAGE_SIZE` pages by `add_mapping_huge`.
+    huge: bool,
+    /// Whether `pin` has fixed this mapping where it is.
+    pinned: bool,
+    /// The label given to `add_mapping_tagged`, if any.
+    tag: Option<String>,
+}
+
+impl MapEntry {
+    /// The permissions this mapping currently allows: copy-on-write mappings are read-only
+    /// until their first write fault.
+    fn effective_perms(&self) -> Permissions {
+        if self.cow.is_some() {
+            self.perms - Permissions::WRITE
+        } else {
+            self.perms
+        }
+    }
+
+    /// The lowest address this mapping occupies, counting its guard pages.
+    fn guard_start(&self) -> VirtualAddress {
+        self.addr - self.guard
+    }
+
+    /// The base of the page that holds this mapping's first byte.
+    fn first_page(&self) -> VirtualAddress {
+        self.addr.align_down(PAGE_SIZE)
+    }
+
+    /// Which of this mapping's pages `addr` is in, counting from `first_page`.
+    fn page_index(&self, addr: VirtualAddress) -> usize {
+        (addr.align_down(PAGE_SIZE) - self.first_page()) / PAGE_SIZE
+    }
+
+    /// The number of pages this mapping touches.
+    fn page_count(&self) -> usize {
+        self.page_index(self.addr + self.span - 1) + 1
+    }
+
+    /// Whether `next` picks up exactly where this mapping leaves off, so the two could be one.
+    fn continues_into(&self, next: &Self) -> bool {
+        self.addr + self.span == next.addr
+            && next.guard == 0
+            && matches!(
+                (&self.source, &next.source),
+                (Backing::Strong(_), Backing::Strong(_)) | (Backing::Weak(_), Backing::Weak(_))
+            )
+            && core::ptr::addr_eq(self.source.as_ptr(), next.source.as_ptr())
+            && self.offset + self.span == next.offset
+            && self.perms == next.perms
+            && match (&self.cow, &next.cow) {
+                (Some(sharing), Some(next)) => Arc::ptr_eq(sharing, next),
+                (sharing, next) => sharing.is_none() && next.is_none(),
+            }
+            && self.huge == next.huge
+            && self.pinned == next.pinned
+            && self.tag == next.tag
+    }
+
+    /// Check that this mapping allows `needed`, reporting a failure at `addr`.
+    fn require(&self, needed: Permissions, addr: VirtualAddress) -> Result<(), MappingError> {
+        if self.effective_perms().contains(needed) {
+            Ok(())
+        } else {
+            Err(MappingError::PermissionDenied { addr })
+        }
+    }
+}
+
+// How many mappings and reservations the search for free space has looked at on this thread,
+// so that tests can tell how much searching an allocation took.
+#[cfg(test)]
+std::thread_local! {
+    static RANGES_SCANNED: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+/// The base of the mapping that the last lookup found, so that runs of accesses to the same
+/// mapping can skip searching for it.
+///
+/// Nothing needs to invalidate this: a hit is only used once the mapping at the cached base has
+/// been checked to contain the address, so a stale base just costs a search.
+struct LookupCache {
+    last: AtomicUsize, // `NO_HIT` if there's nothing cached
+    /// How many lookups had to search the mappings.
+    #[cfg(test)]
+    misses: AtomicUsize,
+}
+
+impl LookupCache {
+    const NO_HIT: usize = usize::MAX;
+
+    const fn new() -> Self {
+        Self {
+            last: AtomicUsize::new(Self::NO_HIT),
+            #[cfg(test)]
+            misses: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Clone for LookupCache {
+    fn clone(&self) -> Self {
+        Self::new()
+    }
+}
+
+/// The seeded random number generator behind `AddressSpace::with_aslr`: SplitMix64, whose
+/// state only ever has a constant added to it, so that it can be advanced through `&self`.
+struct Aslr {
+    state: AtomicU64,
+}
+
+impl Aslr {
+    const GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+    const fn new(seed: u64) -> Self {
+        Self {
+            state: AtomicU64::new(seed),
+        }
+    }
+
+    /// A number below `bound`, which must not be zero.
+    fn below(&self, bound: u64) -> u64 {
+        let mut z = self
+            .state
+            .fetch_add(Self::GAMMA, Ordering::Relaxed)
+            .wrapping_add(Self::GAMMA);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        (z ^ (z >> 31)) % bound
+    }
+}
+
+impl Clone for Aslr {
+    /// The clone carries on from the same point, so it makes the same choices.
+    fn clone(&self) -> Self {
+        Self::new(self.state.load(Ordering::Relaxed))
+    }
+}
+
+/// How `add_mapping` chooses which free gap to place a mapping in, among those big enough.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocPolicy {
+    /// The lowest gap.
+    #[default]
+    FirstFit,
+    /// The smallest gap, leaving big gaps for big mappings.
+    BestFit,
+    /// The largest gap, leaving the biggest possible gap behind.
+    WorstFit,
+    /// The first gap at or above where the last mapping placed this way ended, going back
+    /// to the bottom only when nothing above fits, so that adding many mappings in a row
+    /// doesn't search past all the earlier ones each time. `AddressSpace::trim` moves the
+    /// starting point back down.
+    NextFit,
+}
+
+/// Which end of the address space `add_mapping` allocates from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocDirection {
+    /// Prefer low addresses, placing each mapping at the bottom of its gap.
+    #[default]
+    BottomUp,
+    /// Prefer high addresses, placing each mapping at the top of its gap, like most `mmap`
+    /// implementations.
+    TopDown,
+}
+
+/// What `AddressSpace::read` does with bytes that aren't mapped and that the fault handler
+/// doesn't map either.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnmappedReadPolicy {
+    /// Fail with `Fault` at the first such byte.
+    #[default]
+    Fault,
+    /// Read them as zeros and carry on with the rest of the range.
+    ZeroFill,
+    /// Leave their part of the buffer as it was and carry on with the rest of the range.
+    Skip,
+}
+
+/// Configures every option of a new `AddressSpace` in one place, as an alternative to
+/// `AddressSpace::new` followed by its `with_*` methods. Options that aren't set keep the
+/// defaults of `AddressSpace::new`, and the name defaults to empty.
+///
+/// ```
+/// # use reedos_address_space::{AddressSpace, AllocDirection, AllocPolicy};
+/// let addr_space = AddressSpace::builder()
+///     .name("process")
+///     .ceiling(1 << 32)
+///     .policy(AllocPolicy::BestFit)
+///     .direction(AllocDirection::TopDown)
+///     .wx_policy(true)
+///     .max_mappings(1024)
+///     .build();
+/// assert_eq!(addr_space.name(), "process");
+/// ```
+#[derive(Clone, Debug)]
+pub struct AddressSpaceBuilder {
+    name: String,
+    ceiling: usize,
+    policy: AllocPolicy,
+    direction: AllocDirection,
+    wx_policy: bool,
+    max_mappings: usize,
+    resident_budget: usize,
+    allow_aliasing: bool,
+    aslr: Option<u64>,
+    unmapped_reads: UnmappedReadPolicy,
+}
+
+impl AddressSpaceBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            name: String::new(),
+            ceiling: AddressSpace::MAX_ADDRESS,
+            policy: AllocPolicy::default(),
+            direction: AllocDirection::default(),
+            wx_policy: false,
+            max_mappings: usize::MAX,
+            resident_budget: usize::MAX,
+            allow_aliasing: true,
+            aslr: None,
+            unmapped_reads: UnmappedReadPolicy::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// See `AddressSpace::with_ceiling`.
+    #[must_use]
+    pub const fn ceiling(mut self, ceiling: usize) -> Self {
+        self.ceiling = ceiling;
+        self
+    }
+
+    /// See `AddressSpace::with_policy`.
+    #[must_use]
+    pub const fn policy(mut self, policy: AllocPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// See `AddressSpace::with_direction`.
+    #[must_use]
+    pub const fn direction(mut self, direction: AllocDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// See `AddressSpace::with_wx_policy`.
+    #[must_use]
+    pub const fn wx_policy(mut self, enabled: bool) -> Self {
+        self.wx_policy = enabled;
+        self
+    }
+
+    /// See `AddressSpace::with_max_mappings`.
+    #[must_use]
+    pub const fn max_mappings(mut self, max: usize) -> Self {
+        self.max_mappings = max;
+        self
+    }
+
+    /// See `AddressSpace::with_resident_budget`.
+    #[must_use]
+    pub const fn resident_budget(mut self, bytes: usize) -> Self {
+        self.resident_budget = bytes;
+        self
+    }
+
+    /// See `AddressSpace::with_allow_aliasing`.
+    #[must_use]
+    pub const fn allow_aliasing(mut self, allow: bool) -> Self {
+        self.allow_aliasing = allow;
+        self
+    }
+
+    /// See `AddressSpace::with_aslr`.
+    #[must_use]
+    pub const fn aslr(mut self, seed: u64) -> Self {
+        self.aslr = Some(seed);
+        self
+    }
+
+    /// See `AddressSpace::with_unmapped_read_policy`.
+    #[must_use]
+    pub const fn unmapped_read_policy(mut self, policy: UnmappedReadPolicy) -> Self {
+        self.unmapped_reads = policy;
+        self
+    }
+
+    /// Create an empty `AddressSpace` configured as this builder says.
+    #[must_use]
+    pub fn build(self) -> AddressSpace {
+        let addr_space = AddressSpace::new(&self.name)
+            .with_ceiling(self.ceiling)
+            .with_policy(self.policy)
+            .with_direction(self.direction)
+            .with_wx_policy(self.wx_policy)
+            .with_max_mappings(self.max_mappings)
+            .with_resident_budget(self.resident_budget)
+            .with_allow_aliasing(self.allow_aliasing)
+            .with_unmapped_read_policy(self.unmapped_reads);
+        match self.aslr {
+            Some(seed) => addr_space.with_aslr(seed),
+            None => addr_space,
+        }
+    }
+}
+
+impl Default for AddressSpaceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A named range of an `AddressSpace`, like `.text` or `stack`, covering one or more
+/// mappings, as defined by `AddressSpace::define_segment`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub start: VirtualAddress,
+    /// The length of the segment, in bytes: always a multiple of `PAGE_SIZE`.
+    pub span: usize,
+}
+
+/// A snapshot of one mapping in an `AddressSpace`, as returned by `AddressSpace::iter`.
+#[derive(Clone)]
+pub struct MappingInfo {
+    /// The address the mapping starts at.
+    pub addr: VirtualAddress,
+    /// The length of the mapping, in bytes.
+    pub span: usize,
+    /// The offset into `source` that `addr` corresponds to.
+    pub offset: usize,
+    pub perms: Permissions,
+    /// The source behind the mapping, or `None` for a mapping made by
+    /// `AddressSpace::add_mapping_weak` whose source has since been dropped.
+    pub source: Option<Arc<dyn DataSource>>,
+    /// The label the mapping was added with by `AddressSpace::add_mapping_tagged`, if any.
+    pub tag: Option<String>,
+}
+
+impl From<&MapEntry> for MappingInfo {
+    fn from(entry: &MapEntry) -> Self {
+        Self {
+            addr: entry.addr,
+            span: entry.span,
+            offset: entry.offset,
+            perms: entry.perms,
+            source: entry.source.upgrade(),
+            tag: entry.tag.clone(),
+        }
+    }
+}
+
+/// One mapping of an `AddressSpace` as `AddressSpace::for_each_mapping_mut` hands it out: its
+/// offset and permissions can be changed, but not where it is or how big, which would disturb
+/// the placement of the others.
+pub struct MapEntryView {
+    addr: VirtualAddress,
+    span: usize,
+    source: Option<Arc<dyn DataSource>>,
+    /// The offset into the source that the mapping's first address corresponds to.
+    pub offset: usize,
+    pub perms: Permissions,
+}
+
+impl MapEntryView {
+    /// The address the mapping starts at.
+    #[must_use]
+    pub const fn addr(&self) -> VirtualAddress {
+        self.addr
+    }
+
+    /// The length of the mapping, in bytes.
+    #[must_use]
+    pub const fn span(&self) -> usize {
+        self.span
+    }
+
+    /// The source behind the mapping, or `None` if it was held weakly and has been dropped.
+    #[must_use]
+    pub fn source(&self) -> Option<&Arc<dyn DataSource>> {
+        self.source.as_ref()
+    }
 }
 
+/// The mappings of an `AddressSpace`, keyed by the address each starts at.
+type Mappings = BTreeMap<VirtualAddress, MapEntry>;
+
+/// Called when an access faults in a reserved region, with an `AddressSpace` to map something
+/// in and the faulting address. See `AddressSpace::on_fault`.
+type FaultHandler = dyn FnMut(&mut AddressSpace, VirtualAddress) -> Result<(), MappingError> + Send;
+
 /// An address space.
+///
+/// Methods that change the set of mappings take `&mut self`, so an `AddressSpace` that is shared
+/// must be wrapped in some form of lock by its owner. The exception is `read`, which may have the
+/// fault handler map pages in; the mappings and the record of which pages are resident sit
+/// behind locks of their own so that it can do so through `&self`.
+///
+/// Cloning an `AddressSpace` copies its mappings but not their data: the clone shares every
+/// `DataSource` with the original, so a write through either is visible in both. Use `fork` for
+/// a copy-on-write duplicate instead.
+#[derive(Clone)]
 pub struct AddressSpace {
     name: String,
-    mappings: LinkedList<MapEntry>, // see below for comments
+    mappings: RwLock<Mappings>,
+    ceiling: VirtualAddress,
+    reserved: BTreeMap<VirtualAddress, usize>, // demand-mappable ranges, base to span
+    on_fault: Option<Arc<Mutex<FaultHandler>>>,
+    wx_policy: bool,
+    allow_aliasing: bool,
+    policy: AllocPolicy,
+    direction: AllocDirection,
+    max_mappings: usize, // `usize::MAX` unless limited by `with_max_mappings`
+    segments: BTreeMap<String, Segment>,
+    lookup_cache: LookupCache,
+    cow_shares: CowShares,
+    resident: Mutex<ResidentSet>,
+    /// Just past the last mapping or reservation placed with `find_free`, or wherever `trim`
+    /// last put it.
+    next_fit: VirtualAddress,
+    aslr: Option<Aslr>,
+    unmapped_reads: UnmappedReadPolicy,
 }
 
 // comments about storing mappings
@@ -30,271 +655,6835 @@ pub struct AddressSpace {
 // from a crate (but remember it needs to be #no_std compatible), or even write your own.
 // See this ticket from Riley: https://github.com/dylanmc/cs393_vm_api/issues/10
 
+// We've gone with a BTreeMap keyed by base address: it lives in `alloc`, and finding the
+// mapping that contains an address is a logarithmic `range` query for its predecessor rather
+// than a walk down a list.
+
 impl AddressSpace {
+    /// One past the highest address usable by default: a 39-bit (Sv39-style) address space.
+    pub const MAX_ADDRESS: usize = 1 << 39;
+
     #[must_use]
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
-            mappings: LinkedList::new(),
+            mappings: RwLock::new(BTreeMap::new()),
+            ceiling: VirtualAddress::new(Self::MAX_ADDRESS),
+            reserved: BTreeMap::new(),
+            on_fault: None,
+            wx_policy: false,
+            allow_aliasing: true,
+            policy: AllocPolicy::FirstFit,
+            direction: AllocDirection::BottomUp,
+            max_mappings: usize::MAX,
+            segments: BTreeMap::new(),
+            lookup_cache: LookupCache::new(),
+            cow_shares: CowShares::default(),
+            resident: Mutex::new(ResidentSet::new()),
+            next_fit: VirtualAddress::new(0),
+            aslr: None,
+            unmapped_reads: UnmappedReadPolicy::Fault,
         }
     }
 
-    /// Add a mapping from a `DataSource` into this `AddressSpace`.
+    /// Start configuring a new `AddressSpace`; see `AddressSpaceBuilder`.
+    #[must_use]
+    pub fn builder() -> AddressSpaceBuilder {
+        AddressSpaceBuilder::new()
+    }
+
+    /// The name this `AddressSpace` was created with, or last renamed to.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// One past the highest address mappings may use; see `with_ceiling`.
+    #[must_use]
+    pub const fn ceiling(&self) -> VirtualAddress {
+        self.ceiling
+    }
+
+    /// Change the name of this `AddressSpace`.
+    pub fn rename(&mut self, name: &str) {
+        self.name = name.to_string();
+    }
+
+    /// Use `ceiling` instead of `MAX_ADDRESS` as the top of this `AddressSpace`, e.g. to simulate
+    /// a 32-bit or 48-bit layout.
     ///
-    /// # Errors
-    /// If the desired mapping is invalid.
-    pub fn add_mapping<D: DataSource>(
+    /// ```
+    /// # use reedos_address_space::AddressSpace;
+    /// let small = AddressSpace::new("32-bit space").with_ceiling(1 << 32);
+    /// ```
+    #[must_use]
+    pub fn with_ceiling(mut self, ceiling: usize) -> Self {
+        self.ceiling = VirtualAddress::new(ceiling);
+        self
+    }
+
+    /// Choose free gaps for new mappings according to `policy` instead of first-fit.
+    #[must_use]
+    pub fn with_policy(mut self, policy: AllocPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Allocate new mappings from the end of the address space given by `direction`.
+    #[must_use]
+    pub fn with_direction(mut self, direction: AllocDirection) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Have `read` treat unmapped bytes as `policy` says rather than faulting on them. Guard
+    /// pages still fault, since catching accesses to them is what they're for.
+    #[must_use]
+    pub fn with_unmapped_read_policy(mut self, policy: UnmappedReadPolicy) -> Self {
+        self.unmapped_reads = policy;
+        self
+    }
+
+    /// Whether to enforce W^X: if `enabled`, no mapping may be made both writable and
+    /// executable, and attempts to do so fail with `WxViolation`.
+    #[must_use]
+    pub fn with_wx_policy(mut self, enabled: bool) -> Self {
+        self.wx_policy = enabled;
+        self
+    }
+
+    /// Place mappings at random, like address space layout randomization: `find_free` (and so
+    /// `add_mapping` and everything else that finds room for itself) picks uniformly among all
+    /// the suitably aligned bases where the mapping would fit, whatever the `AllocPolicy` and
+    /// `AllocDirection`. The choices come from a generator seeded with `seed`, so the same
+    /// seed and the same sequence of calls give the same layout.
+    #[must_use]
+    pub fn with_aslr(mut self, seed: u64) -> Self {
+        self.aslr = Some(Aslr::new(seed));
+        self
+    }
+
+    /// Whether to let two mappings show the same bytes of one source, e.g. to map shared
+    /// memory twice so that writes through either address show up at the other. This is
+    /// allowed by default; if `allow` is false, adding a mapping whose source bytes overlap
+    /// another mapping's fails with `Aliased` instead. Use `aliases_of` to find aliases.
+    #[must_use]
+    pub fn with_allow_aliasing(mut self, allow: bool) -> Self {
+        self.allow_aliasing = allow;
+        self
+    }
+
+    /// Allow at most `max` mappings at once, like Linux's `vm.max_map_count`: once there are
+    /// that many, adding another fails with `TooManyMappings` until one is removed. Splitting a
+    /// mapping, e.g. with `protect` or `unmap`, is not limited.
+    #[must_use]
+    pub fn with_max_mappings(mut self, max: usize) -> Self {
+        self.max_mappings = max;
+        self
+    }
+
+    /// Keep at most `bytes` of what the fault handler maps resident at once, rounded down to
+    /// whole pages but at least one, to model a machine with little memory. When a fault would
+    /// bring in more, the least recently read or written pages are evicted first: dirty ones
+    /// are written back with `flush`, and then they are unmapped, so that the next access to
+    /// one faults it in again. The budget doesn't apply to anything mapped other than by the
+    /// fault handler.
+    #[must_use]
+    pub fn with_resident_budget(mut self, bytes: usize) -> Self {
+        self.resident
+            .get_mut()
+            .set_budget((bytes / PAGE_SIZE).max(1));
+        self
+    }
+
+    /// How many bytes of what the fault handler has mapped are currently resident, in whole
+    /// pages; see `with_resident_budget`.
+    #[must_use]
+    pub fn resident_bytes(&self) -> usize {
+        self.resident.lock().len() * PAGE_SIZE
+    }
+
+    /// Check that there is room for one more mapping under the limit set by
+    /// `with_max_mappings`.
+    fn check_room(&self) -> Result<(), MappingError> {
+        if self.mappings.read().len() >= self.max_mappings {
+            Err(MappingError::TooManyMappings)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Check that mapping `span` bytes of `source` from `offset` on wouldn't alias another
+    /// mapping, unless `with_allow_aliasing` says that's fine. Whatever is mapped in
+    /// `replacing` is about to be unmapped, so it doesn't count.
+    fn check_alias(
         &self,
-        source: &D,
+        source: *const dyn DataSource,
         offset: usize,
         span: usize,
-    ) -> Result<VirtualAddress, &str> {
-        todo!()
-        // let mut start_free = 0;
-        // let mut end_free = 2 ^ 39 - 1;
-        // let mut iter = self.mappings.iter();
-
-        // if iter.is_empty() {
-        //     let src: Arc<dyn DataSource> = { source };
-        //     let entry = MapEntry {
-        //         source: src,
-        //         offset: offset,
-        //         span: span,
-        //         addr: start_free,
-        //     };
-        //     self.mappings.push_back(entry);
-        //     return Ok(entry.addr);
-        // }
-
-        // loop {
-        //     if let Some(first_entry) = iter.next() {
-        //         start_free = first_entry.addr + first_entry.span;
-        //         if let Some(second_entry) = iter.next() {
-        //             end_free = second_entry.addr - 1;
-        //         } else {
-        //             end_free = 2 ^ 39 - 1;
-        //         }
-        // } else {
-        //     return Err("cannot fit data source in address space");
-        // }
-        // if end_free - start_free >= span {
-        //     let src: Arc<dyn DataSource> = { source };
-        //     let entry = MapEntry {
-        //         source: src,
-        //         offset: offset,
-        //         span: span,
-        //         addr: start_free,
-        //     };
-        //     self.mappings.push_back(entry);
-        //     return Ok(entry.addr);
-        // }
-    }
-
-    /// Add a mapping from `DataSource` into this `AddressSpace` starting at a specific address.
-    ///
-    /// # Errors
-    /// If there is insufficient room subsequent to `start`.
-    pub fn add_mapping_at<D: DataSource>(
-        &self,
-        source: &D,
+        replacing: Option<Range<VirtualAddress>>,
+    ) -> Result<(), MappingError> {
+        if self.allow_aliasing {
+            return Ok(());
+        }
+        let wanted = offset..offset.saturating_add(span);
+        let mappings = self.mappings.read();
+        for entry in Self::same_source(&mappings, source) {
+            let end = entry.addr + entry.span;
+            let kept = match &replacing {
+                Some(gone) => [
+                    entry.addr..end.min(gone.start),
+                    entry.addr.max(gone.end)..end,
+                ],
+                None => [entry.addr..end, end..end],
+            };
+            for piece in kept.into_iter().filter(|piece| piece.start < piece.end) {
+                let from = entry.offset + (piece.start - entry.addr);
+                if from < wanted.end && wanted.start < from + (piece.end - piece.start) {
+                    return Err(MappingError::Aliased { addr: entry.addr });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The ones of `mappings` backed by the source at `source` itself, in address order.
+    fn same_source(
+        mappings: &Mappings,
+        source: *const dyn DataSource,
+    ) -> impl Iterator<Item = &MapEntry> + '_ {
+        mappings
+            .values()
+            .filter(move |entry| core::ptr::addr_eq(entry.source.as_ptr(), source))
+    }
+
+    /// Check `perms` against the W^X policy, if it is enabled.
+    fn check_wx(&self, perms: Permissions) -> Result<(), MappingError> {
+        if self.wx_policy && perms.contains(Permissions::WRITE | Permissions::EXECUTE) {
+            Err(MappingError::WxViolation)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Add a mapping from a `DataSource` into this `AddressSpace`, accessible according to
+    /// `perms`.
+    ///
+    /// `span` is rounded up to a multiple of `PAGE_SIZE`, and the mapping is placed on a page
+    /// boundary.
+    ///
+    /// # Errors
+    /// `WxViolation` if `perms` breaks the W^X policy, `TooManyMappings` if there are already
+    /// as many mappings as `with_max_mappings` allows, `InvalidSpan` if `span` is zero,
+    /// `SourceTooSmall` if `offset + span` is past the end of `source`, or `NoSpace` if there
+    /// is no free region of at least `span` bytes.
+    pub fn add_mapping(
+        &mut self,
+        source: Arc<dyn DataSource>,
         offset: usize,
         span: usize,
-        start: VirtualAddress,
-    ) -> Result<(), &str> {
-        // let mut start_free;
-        // let mut end_free;
-        // let mut iter = self.mappings.iter();
-
-        // if iter.is_empty() {
-        //     let src: Arc<dyn DataSource> = { source };
-        //     let entry = MapEntry {
-        //         source: src,
-        //         offset: offset,
-        //         span: span,
-        //         addr: start,
-        //     };
-        //     self.mappings.push_back(entry);
-        //     return Ok(());
-        // }
-        // loop {
-        //     if let Some(first_entry) = iter.next() {
-        //         start_free = first_entry.addr + first_entry.span;
-        //         if let Some(second_entry) = iter.next() {
-        //             end_free = second_entry.addr - 1;
-        //         } else {
-        //             end_free = 2 ^ 39 - 1;
-        //         }
-        //     } else {
-        //         return Err("cannot fit data source in address space");
-        //     }
-        // if start_free <= start && start <= end_free {
-        //     if start + span <= end_free {
-        //         let src: Arc<dyn DataSource> = { source };
-        //         let entry = MapEntry {
-        //             source: src,
-        //             offset: offset,
-        //             span: span,
-        //             addr: start_free,
-        //         };
-        //         self.mappings.push_back(entry);
-        //         return Ok(());
-        //     } else {
-        //         return Err("cannot fit data source into address space");
-        //     }
-        // }
-        todo!()
-    }
-
-    /// Remove the mapping to `DataSource` that starts at the given address.
-    ///
-    /// # Errors
-    /// If the mapping could not be removed.
-    pub fn remove_mapping<D: DataSource>(
-        &self,
-        source: &D,
-        start: VirtualAddress,
-    ) -> Result<(), &str> {
-        todo!()
+        perms: Permissions,
+    ) -> Result<VirtualAddress, MappingError> {
+        self.check_wx(perms)?;
+        self.check_room()?;
+        check_span(&*source, offset, span)?;
+        let span = page_align(span).ok_or(MappingError::NoSpace)?;
+        self.check_alias(Arc::as_ptr(&source), offset, span, None)?;
+        let addr = self.find_free(span).ok_or(MappingError::NoSpace)?;
+        self.next_fit = addr + span;
+
+        self.insert(MapEntry {
+            source: Backing::Strong(source),
+            offset,
+            span,
+            addr,
+            perms,
+            cow: None,
+            guard: 0,
+            dirty: DirtyBitmap::default(),
+            stats: AccessCounters::new(),
+            huge: false,
+            pinned: false,
+            tag: None,
+        });
+        Ok(addr)
     }
 
-    /// Look up the DataSource and offset within that DataSource for a
-    /// VirtualAddress / AccessType in this AddressSpace
+    /// Like `add_mapping`, but label the mapping with `tag`, e.g. to say which allocation site
+    /// made it. The tag shows up in `MappingInfo::tag`, stays with every piece of the mapping
+    /// that survives `protect`, `unmap` and the like, and keeps the mapping from being merged
+    /// with differently tagged neighbours. See `mappings_with_tag`.
     ///
     /// # Errors
-    /// If this VirtualAddress does not have a valid mapping in &self,
-    /// or if this AccessType is not permitted by the mapping
-    pub fn get_source_for_addr<D: DataSource>(
-        &self,
-        addr: VirtualAddress,
-        access_type: FlagBuilder,
-    ) -> Result<(&D, usize), &str> {
-        todo!();
+    /// As for `add_mapping`.
+    pub fn add_mapping_tagged(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        perms: Permissions,
+        tag: &str,
+    ) -> Result<VirtualAddress, MappingError> {
+        let addr = self.add_mapping(source, offset, span, perms)?;
+        if let Some(entry) = self.mappings.get_mut().get_mut(&addr) {
+            entry.tag = Some(tag.to_string());
+        }
+        Ok(addr)
     }
-}
 
-/// Build flags for address space maps.
-///
-/// We recommend using this builder type as follows:
-/// ```
-/// # use reedos_address_space::FlagBuilder;
-/// let flags = FlagBuilder::new()
-///     .toggle_read()
-///     .toggle_write();
-/// ```
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
-#[allow(clippy::struct_excessive_bools)] // clippy is wrong: bools are more readable than enums
-                                         // here because these directly correspond to yes/no
-                                         // hardware flags
-pub struct FlagBuilder {
-    // TODO: should there be some sanity checks that conflicting flags are never toggled? can we do
-    // this at compile-time? (the second question is maybe hard)
-    read: bool,
-    write: bool,
-    execute: bool,
-    cow: bool,
-    private: bool,
-    shared: bool,
-}
+    /// Like `add_mapping`, but without keeping `source` alive: once every other `Arc` to it
+    /// has been dropped, accesses through the mapping fail with `SourceGone`, e.g. for a
+    /// cache whose entries can be thrown away while they are still mapped. The mapping stays
+    /// in place until it is unmapped.
+    ///
+    /// # Errors
+    /// As for `add_mapping`.
+    pub fn add_mapping_weak(
+        &mut self,
+        source: &Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        perms: Permissions,
+    ) -> Result<VirtualAddress, MappingError> {
+        let addr = self.add_mapping(source.clone(), offset, span, perms)?;
+        if let Some(entry) = self.mappings.get_mut().get_mut(&addr) {
+            entry.source = Backing::Weak(Arc::downgrade(source));
+        }
+        Ok(addr)
+    }
 
-/// Create a constructor and toggler for a `FlagBuilder` object. Will capture attributes, including documentation
-/// comments and apply them to the generated constructor.
-macro_rules! flag {
-    (
-        $flag:ident,
-        $toggle:ident
-    ) => {
-        #[doc=concat!("Turn on only the ", stringify!($flag), " flag.")]
-        #[must_use]
-        pub fn $flag() -> Self {
-            Self {
-                $flag: true,
-                ..Self::default()
+    /// Add a mapping of `span` bytes of zero-filled memory, like `mmap` with `MAP_ANONYMOUS`,
+    /// without the caller supplying a source. It is backed by a fresh `AnonymousSource` covering
+    /// the whole rounded-up span, so pages read as zeros and only take up memory once written.
+    ///
+    /// # Errors
+    /// As for `add_mapping`.
+    pub fn add_mapping_anonymous(
+        &mut self,
+        span: usize,
+        perms: Permissions,
+    ) -> Result<VirtualAddress, MappingError> {
+        let len = page_align(span).ok_or(MappingError::NoSpace)?;
+        self.add_mapping(Arc::new(AnonymousSource::new(len)), 0, len, perms)
+    }
+
+    /// Copy `[start, start + span)` into a new private, writable mapping backed by an
+    /// `AnonymousSource`, placed as `add_mapping` would place it, and return its base. The
+    /// range may cross any number of mappings, and needn't be writable itself.
+    ///
+    /// # Errors
+    /// `InvalidSpan` if `span` is zero, the errors of `read` if the range can't be read, or
+    /// those of `add_mapping` if there's no room for the copy.
+    pub fn clone_region(
+        &mut self,
+        start: VirtualAddress,
+        span: usize,
+    ) -> Result<VirtualAddress, MappingError> {
+        if span == 0 {
+            return Err(MappingError::InvalidSpan);
+        }
+        let bytes = self.read_vec(start, span)?;
+        let len = page_align(span).ok_or(MappingError::NoSpace)?;
+        let copy = AnonymousSource::new(len);
+        write_source(&copy, 0, &bytes, start)?;
+        self.add_mapping(
+            Arc::new(copy),
+            0,
+            len,
+            Permissions::READ | Permissions::WRITE,
+        )
+    }
+
+    /// Add a mapping for each `(source, offset, span)` in `specs`, all accessible according to
+    /// `perms`, as if by `add_mapping` in order. Either every mapping is added, in which case
+    /// their bases are returned in the same order, or none is. A batch that fails leaves the
+    /// space as it was, so later mappings are placed as if it had never been tried.
+    ///
+    /// # Errors
+    /// The first error of `add_mapping`, after removing the mappings added before it.
+    pub fn add_mappings<I>(
+        &mut self,
+        specs: I,
+        perms: Permissions,
+    ) -> Result<Vec<VirtualAddress>, MappingError>
+    where
+        I: IntoIterator<Item = (Arc<dyn DataSource>, usize, usize)>,
+    {
+        // where the next mapping would go, so that a failed batch doesn't move it
+        let next_fit = self.next_fit;
+        let aslr = self.aslr.clone();
+        let mut added = Vec::new();
+        for (source, offset, span) in specs {
+            match self.add_mapping(source, offset, span, perms) {
+                Ok(addr) => added.push(addr),
+                Err(err) => {
+                    for addr in added {
+                        self.mappings.get_mut().remove(&addr);
+                    }
+                    self.next_fit = next_fit;
+                    self.aslr = aslr;
+                    return Err(err);
+                }
             }
         }
+        Ok(added)
+    }
 
-        #[doc=concat!("Toggle the ", stringify!($flag), " flag.")]
-        #[must_use]
-        pub const fn $toggle(self) -> Self {
-            Self {
-                $flag: !self.$flag,
-                ..self
+    /// Add a mapping from `DataSource` into this `AddressSpace` starting at a specific address,
+    /// accessible according to `perms`.
+    ///
+    /// As with `add_mapping`, `span` is rounded up to a multiple of `PAGE_SIZE`.
+    ///
+    /// # Errors
+    /// `WxViolation` if `perms` breaks the W^X policy, `TooManyMappings`, `InvalidSpan` and
+    /// `SourceTooSmall` as for `add_mapping`, `Unaligned` if `start` is not a multiple of `PAGE_SIZE`, `OutOfRange` if the mapping
+    /// would extend past the top of the address space, or `Overlap` naming the first existing
+    /// mapping that intersects `[start, start + span)`, counting its guard pages.
+    pub fn add_mapping_at(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        start: VirtualAddress,
+        perms: Permissions,
+    ) -> Result<(), MappingError> {
+        self.check_wx(perms)?;
+        self.check_room()?;
+        check_span(&*source, offset, span)?;
+        let span = page_align(span).ok_or(MappingError::OutOfRange { addr: start })?;
+        self.check_free(start, span, 0)?;
+        self.check_alias(Arc::as_ptr(&source), offset, span, None)?;
+
+        self.insert(MapEntry {
+            source: Backing::Strong(source),
+            offset,
+            span,
+            addr: start,
+            perms,
+            cow: None,
+            guard: 0,
+            dirty: DirtyBitmap::default(),
+            stats: AccessCounters::new(),
+            huge: false,
+            pinned: false,
+            tag: None,
+        });
+        Ok(())
+    }
+
+    /// Like `add_mapping_at`, but if the mapping doesn't fit at `start`, suggest where it could
+    /// go instead: the lowest page-aligned address at or above `start` with room for it, or
+    /// `None` if there is none. Returns `start` on success.
+    ///
+    /// # Errors
+    /// The errors of `add_mapping_at`, with a suggestion if the failure was `Overlap` or
+    /// `OutOfRange`. Other errors wouldn't be fixed by moving the mapping, and come with `None`.
+    pub fn try_add_mapping_at(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        start: VirtualAddress,
+        perms: Permissions,
+    ) -> Result<VirtualAddress, (MappingError, Option<VirtualAddress>)> {
+        match self.add_mapping_at(source, offset, span, start, perms) {
+            Ok(()) => Ok(start),
+            Err(err @ (MappingError::Overlap { .. } | MappingError::OutOfRange { .. })) => {
+                Err((err, self.find_free_from(span, PAGE_SIZE, start)))
             }
+            Err(err) => Err((err, None)),
         }
-    };
-}
+    }
 
-impl FlagBuilder {
-    /// Create a new `FlagBuilder` with all flags toggled off.
-    #[must_use]
-    pub fn new() -> Self {
-        Self::default()
+    /// Add a mapping at the highest page-aligned base at or below `hint` where it fits, e.g. for
+    /// a stack that should sit just under some address and grow down from there. This is the
+    /// downward counterpart of the suggestion `try_add_mapping_at` makes. Returns the base.
+    ///
+    /// # Errors
+    /// `WxViolation`, `TooManyMappings`, `InvalidSpan`, `SourceTooSmall` or `Aliased` as for
+    /// `add_mapping`, or `NoSpace` if the mapping fits nowhere with its base at or below `hint`.
+    pub fn add_mapping_below(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        hint: VirtualAddress,
+        perms: Permissions,
+    ) -> Result<VirtualAddress, MappingError> {
+        self.check_wx(perms)?;
+        self.check_room()?;
+        check_span(&*source, offset, span)?;
+        let span = page_align(span).ok_or(MappingError::NoSpace)?;
+        let highest = hint.align_down(PAGE_SIZE);
+        let mappings = self.mappings.read();
+        let start = self
+            .free_ranges(&mappings)
+            .take_while(|gap| gap.start <= highest)
+            .filter_map(|gap| {
+                let lowest = gap.start.checked_align_up(PAGE_SIZE)?;
+                let base = gap
+                    .end
+                    .checked_sub(span)?
+                    .align_down(PAGE_SIZE)
+                    .min(highest);
+                (lowest <= base).then_some(base)
+            })
+            .last()
+            .ok_or(MappingError::NoSpace)?;
+        drop(mappings);
+        self.add_mapping_at(source, offset, span, start, perms)?;
+        Ok(start)
     }
 
-    flag!(read, toggle_read);
-    flag!(write, toggle_write);
-    flag!(execute, toggle_execute);
-    flag!(cow, toggle_cow);
-    flag!(private, toggle_private);
-    flag!(shared, toggle_shared);
+    /// Like `add_mapping_at`, but replace whatever is already mapped in `[start, start + span)`
+    /// instead of failing, like `mmap` with `MAP_FIXED`. Mappings that straddle either end of
+    /// the range are trimmed as by `unmap`, and a mapping whose guard pages reach into the
+    /// range loses them.
+    ///
+    /// # Errors
+    /// `WxViolation`, `TooManyMappings`, `InvalidSpan`, `SourceTooSmall`, `Unaligned` or
+    /// `OutOfRange` as for `add_mapping_at`, in which case nothing is unmapped. The limit on
+    /// mappings counts what is left once the range has been cleared, so a mapping can be
+    /// replaced even when the space is full.
+    pub fn map_fixed(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        start: VirtualAddress,
+        perms: Permissions,
+    ) -> Result<(), MappingError> {
+        self.check_wx(perms)?;
+        check_span(&*source, offset, span)?;
+        if !start.is_aligned(PAGE_SIZE) {
+            return Err(MappingError::Unaligned { addr: start });
+        }
+        let span = page_align(span).ok_or(MappingError::OutOfRange { addr: start })?;
+        let end = start
+            .checked_add(span)
+            .ok_or(MappingError::OutOfRange { addr: start })?;
+        // count what is left once the range is cleared: mappings wholly inside it go, and one
+        // that holds all of it is split in two
+        let mappings = self.mappings.get_mut();
+        let removed = mappings
+            .range(start..end)
+            .filter(|(_, entry)| entry.addr + entry.span <= end)
+            .count();
+        let split = mappings
+            .range(..start)
+            .next_back()
+            .is_some_and(|(_, entry)| end < entry.addr + entry.span);
+        if mappings.len() - removed + usize::from(split) >= self.max_mappings {
+            return Err(MappingError::TooManyMappings);
+        }
+        self.check_alias(Arc::as_ptr(&source), offset, span, Some(start..end))?;
+        self.unmap(start, span)?;
+        if let Some((_, next)) = self.mappings.get_mut().range_mut(end..).next() {
+            next.guard = next.guard.min(next.addr - end);
+        }
+
+        self.insert(MapEntry {
+            source: Backing::Strong(source),
+            offset,
+            span,
+            addr: start,
+            perms,
+            cow: None,
+            guard: 0,
+            dirty: DirtyBitmap::default(),
+            stats: AccessCounters::new(),
+            huge: false,
+            pinned: false,
+            tag: None,
+        });
+        Ok(())
+    }
+
+    /// Check that a mapping of `span` bytes, with `guard` bytes of guard pages below it, could
+    /// be placed at `start`.
+    fn check_free(
+        &self,
+        start: VirtualAddress,
+        span: usize,
+        guard: usize,
+    ) -> Result<(), MappingError> {
+        if !start.is_aligned(PAGE_SIZE) {
+            return Err(MappingError::Unaligned { addr: start });
+        }
+        let end = start
+            .checked_add(span)
+            .filter(|&end| end <= self.ceiling && start.as_usize() >= guard)
+            .ok_or(MappingError::OutOfRange { addr: start })?;
+        let low = start - guard;
+        let mappings = self.mappings.read();
+        let before = mappings
+            .range(..low)
+            .next_back()
+            .filter(|(_, entry)| low < entry.addr + entry.span);
+        // the next mapping up may be clear of the range itself but not its guard pages
+        let after = || {
+            mappings
+                .range(low..)
+                .next()
+                .filter(|(_, entry)| entry.guard_start() < end)
+        };
+        if let Some((&addr, _)) = before.or_else(after) {
+            return Err(MappingError::Overlap { addr });
+        }
+        Ok(())
+    }
+
+    /// Like `add_mapping`, but also keep the `guard_pages` pages immediately below the mapping
+    /// unmapped, so that an access that runs off the bottom of it, like a stack overflow, fails
+    /// with `GuardPage` rather than reaching another mapping. Returns the address of the
+    /// mapping itself, above its guard pages. The guard pages go when the mapping is removed.
+    ///
+    /// # Errors
+    /// As for `add_mapping`, with the guard pages counted as part of the space needed.
+    pub fn add_mapping_guarded(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        guard_pages: usize,
+        perms: Permissions,
+    ) -> Result<VirtualAddress, MappingError> {
+        self.check_wx(perms)?;
+        self.check_room()?;
+        check_span(&*source, offset, span)?;
+        let span = page_align(span).ok_or(MappingError::NoSpace)?;
+        self.check_alias(Arc::as_ptr(&source), offset, span, None)?;
+        let guard = guard_pages
+            .checked_mul(PAGE_SIZE)
+            .ok_or(MappingError::NoSpace)?;
+        let total = guard.checked_add(span).ok_or(MappingError::NoSpace)?;
+        let addr = self.find_free(total).ok_or(MappingError::NoSpace)? + guard;
+        self.next_fit = addr + span;
+
+        self.insert(MapEntry {
+            source: Backing::Strong(source),
+            offset,
+            span,
+            addr,
+            perms,
+            cow: None,
+            guard,
+            dirty: DirtyBitmap::default(),
+            stats: AccessCounters::new(),
+            huge: false,
+            pinned: false,
+            tag: None,
+        });
+        Ok(addr)
+    }
+
+    /// Like `add_mapping`, but make the mapping out of huge pages: `span` is rounded up to a
+    /// multiple of `HUGE_PAGE_SIZE`, and the mapping is placed on a `HUGE_PAGE_SIZE` boundary.
+    ///
+    /// # Errors
+    /// As for `add_mapping`, except that `source` must cover the whole of the rounded-up span
+    /// to avoid `SourceTooSmall`.
+    pub fn add_mapping_huge(
+        &mut self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        perms: Permissions,
+    ) -> Result<VirtualAddress, MappingError> {
+        self.check_wx(perms)?;
+        self.check_room()?;
+        if span == 0 {
+            return Err(MappingError::InvalidSpan);
+        }
+        let span = span
+            .checked_next_multiple_of(HUGE_PAGE_SIZE)
+            .ok_or(MappingError::NoSpace)?;
+        check_span(&*source, offset, span)?;
+        self.check_alias(Arc::as_ptr(&source), offset, span, None)?;
+        let addr = self
+            .find_free_aligned(span, HUGE_PAGE_SIZE)
+            .ok_or(MappingError::NoSpace)?;
+        self.next_fit = addr + span;
+
+        self.insert(MapEntry {
+            source: Backing::Strong(source),
+            offset,
+            span,
+            addr,
+            perms,
+            cow: None,
+            guard: 0,
+            dirty: DirtyBitmap::default(),
+            stats: AccessCounters::new(),
+            huge: true,
+            pinned: false,
+            tag: None,
+        });
+        Ok(addr)
+    }
 
+    /// Find where `add_mapping` would place a mapping of `span` bytes, without adding it.
+    ///
+    /// Returns `None` if nothing fits.
     #[must_use]
-    /// Combine two `FlagBuilder`s by boolean or-ing each of their flags.
+    pub fn find_free(&self, span: usize) -> Option<VirtualAddress> {
+        self.find_free_aligned(span, PAGE_SIZE)
+    }
+
+    /// Find an address that is a multiple of `align` (and of `PAGE_SIZE`) with room for a
+    /// mapping of `span` bytes after it, choosing among the free gaps according to this
+    /// `AddressSpace`'s `AllocPolicy` and `AllocDirection`. `align` must be a power of two.
     ///
-    /// This is, somewhat counter-intuitively, named `and`, so that the following code reads
-    /// correctly:
+    /// Returns `None` if nothing fits.
+    #[must_use]
+    pub fn find_free_aligned(&self, span: usize, align: usize) -> Option<VirtualAddress> {
+        use core::cmp::Reverse;
+
+        let span = page_align(span)?;
+        let align = align.max(PAGE_SIZE);
+        let top_down = self.direction == AllocDirection::TopDown;
+        let fit = |gap: Range<VirtualAddress>| {
+            let base = if top_down {
+                gap.end.checked_sub(span)?.align_down(align)
+            } else {
+                gap.start.checked_align_up(align)?
+            };
+            let fits =
+                base >= gap.start && base.checked_add(span).is_some_and(|end| end <= gap.end);
+            fits.then_some((gap.end - gap.start, base))
+        };
+        let mappings = self.mappings.read();
+        if let Some(aslr) = &self.aslr {
+            return self.random_base(&mappings, aslr, span, align);
+        }
+        if self.policy == AllocPolicy::NextFit {
+            return self
+                .free_ranges_from(&mappings, self.next_fit)
+                .find_map(fit)
+                .or_else(|| self.free_ranges(&mappings).find_map(fit))
+                .map(|(_, base)| base);
+        }
+        let mut candidates = self.free_ranges(&mappings).filter_map(fit);
+        // `min_by_key` keeps the first of equally good gaps and `max_by_key` the last, so that
+        // ties go to the gap nearest the end we allocate from
+        let chosen = match (self.policy, top_down) {
+            (AllocPolicy::FirstFit | AllocPolicy::NextFit, false) => candidates.next(),
+            (AllocPolicy::FirstFit | AllocPolicy::NextFit, true) => candidates.last(),
+            (AllocPolicy::BestFit, false) => candidates.min_by_key(|&(len, _)| len),
+            (AllocPolicy::BestFit, true) => candidates.max_by_key(|&(len, _)| Reverse(len)),
+            (AllocPolicy::WorstFit, false) => candidates.min_by_key(|&(len, _)| Reverse(len)),
+            (AllocPolicy::WorstFit, true) => candidates.max_by_key(|&(len, _)| len),
+        };
+        chosen.map(|(_, base)| base)
+    }
+
+    /// Pick one of the multiples of `align` with room for `span` bytes after them at random.
+    fn random_base(
+        &self,
+        mappings: &Mappings,
+        aslr: &Aslr,
+        span: usize,
+        align: usize,
+    ) -> Option<VirtualAddress> {
+        // the first base in each gap that fits, and how many bases in it fit
+        let slots: Vec<_> = self
+            .free_ranges(mappings)
+            .filter_map(|gap| {
+                let first = gap.start.checked_align_up(align)?;
+                let room = gap.end.as_usize().checked_sub(first.as_usize())?;
+                let count = room.checked_sub(span)? / align + 1;
+                Some((first, count as u64))
+            })
+            .collect();
+        let total: u64 = slots.iter().map(|&(_, count)| count).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut pick = aslr.below(total);
+        for (first, count) in slots {
+            if pick < count {
+                return Some(first + pick as usize * align);
+            }
+            pick -= count;
+        }
+        None
+    }
+
+    /// Find the lowest address at or above `min_addr` that is a multiple of `align` (and of
+    /// `PAGE_SIZE`) with room for a mapping of `span` bytes after it, e.g. to keep clear of the
+    /// NULL page. Unlike `find_free_aligned`, this ignores the `AllocPolicy` and
+    /// `AllocDirection`. `align` must be a power of two.
     ///
-    /// ```
-    /// # use reedos_address_space::FlagBuilder;
-    /// let read = FlagBuilder::read();
-    /// let execute = FlagBuilder::execute();
-    /// let new = read.and(execute);
-    /// assert_eq!(new, FlagBuilder::new().toggle_read().toggle_execute());
-    /// ```
-    pub const fn and(self, other: Self) -> Self {
-        let read = self.read || other.read;
-        let write = self.write || other.write;
-        let execute = self.execute || other.execute;
-        let cow = self.cow || other.cow;
-        let private = self.private || other.private;
-        let shared = self.shared || other.shared;
+    /// Returns `None` if nothing fits.
+    #[must_use]
+    pub fn find_free_from(
+        &self,
+        span: usize,
+        align: usize,
+        min_addr: VirtualAddress,
+    ) -> Option<VirtualAddress> {
+        let span = page_align(span)?;
+        let align = align.max(PAGE_SIZE);
+        self.free_ranges(&self.mappings.read()).find_map(|gap| {
+            let base = gap.start.max(min_addr).checked_align_up(align)?;
+            base.checked_add(span)
+                .is_some_and(|end| end <= gap.end)
+                .then_some(base)
+        })
+    }
 
-        Self {
-            read,
-            write,
-            execute,
-            cow,
-            private,
-            shared,
+    /// Find the lowest page-aligned address with room for a mapping of `span` bytes that lies
+    /// entirely within `[start, end)`, e.g. to place a mapping at random within a region.
+    /// Gaps that reach outside the window count only for the part inside it.
+    ///
+    /// Returns `None` if nothing fits in the window.
+    #[must_use]
+    pub fn free_in(
+        &self,
+        start: VirtualAddress,
+        end: VirtualAddress,
+        span: usize,
+    ) -> Option<VirtualAddress> {
+        let span = page_align(span)?;
+        self.free_ranges_from(&self.mappings.read(), start)
+            .take_while(|gap| gap.start < end)
+            .find_map(|gap| {
+                let base = gap.start.checked_align_up(PAGE_SIZE)?;
+                base.checked_add(span)
+                    .is_some_and(|top| top <= gap.end.min(end))
+                    .then_some(base)
+            })
+    }
+
+    /// The start and length of every free stretch of this `AddressSpace`, neither mapped nor
+    /// reserved, in ascending order: from the gap below the first mapping (starting at 0) to the
+    /// gap between the last mapping and the ceiling. Guard pages count as mapped. Mappings that
+    /// touch leave no gap between them.
+    pub fn gaps(&self) -> impl Iterator<Item = (VirtualAddress, usize)> + '_ {
+        let mappings = self.mappings.read();
+        let gaps: Vec<_> = self
+            .free_ranges(&mappings)
+            .filter(|gap| !gap.is_empty())
+            .map(|gap| (gap.start, gap.end - gap.start))
+            .collect();
+        gaps.into_iter()
+    }
+
+    /// The free stretches of `gaps` as ranges, some of which may be empty, given this space's
+    /// `mappings`.
+    fn free_ranges<'a>(
+        &'a self,
+        mappings: &'a Mappings,
+    ) -> impl Iterator<Item = Range<VirtualAddress>> + 'a {
+        self.free_ranges_from(mappings, VirtualAddress::new(0))
+    }
+
+    /// The free stretches at or above `floor`, the first cut short at `floor`, without looking
+    /// at anything that lies wholly below it.
+    fn free_ranges_from<'a>(
+        &'a self,
+        mappings: &'a Mappings,
+        floor: VirtualAddress,
+    ) -> impl Iterator<Item = Range<VirtualAddress>> + 'a {
+        // only the last mapping and the last reservation below `floor` can reach past it
+        let mut mappings = mappings
+            .range(..floor)
+            .next_back()
+            .into_iter()
+            .chain(mappings.range(floor..))
+            .map(|(_, entry)| entry.guard_start()..entry.addr + entry.span)
+            .peekable();
+        let mut reserved = self
+            .reserved
+            .range(..floor)
+            .next_back()
+            .into_iter()
+            .chain(self.reserved.range(floor..))
+            .map(|(&base, &span)| base..base + span)
+            .peekable();
+        let ceiling = self.ceiling;
+        let mut prev_end = Some(floor);
+        // both are in order of where they start, so merge them as they go
+        core::iter::from_fn(move || {
+            let end = prev_end?;
+            let next = match (mappings.peek(), reserved.peek()) {
+                (Some(mapping), Some(reservation)) if reservation.start < mapping.start => {
+                    reserved.next()
+                }
+                (Some(_), _) => mappings.next(),
+                (None, _) => reserved.next(),
+            };
+            let Some(range) = next else {
+                prev_end = None;
+                return Some(end..ceiling.max(end));
+            };
+            #[cfg(test)]
+            RANGES_SCANNED.with(|scanned| scanned.set(scanned.get() + 1));
+            // reservations may overlap mappings, so keep track of the furthest end seen so far
+            prev_end = Some(end.max(range.end));
+            Some(end..range.start.max(end))
+        })
+    }
+
+    /// The last byte of the highest mapping, or `None` if nothing is mapped.
+    #[must_use]
+    pub fn highest_mapped_address(&self) -> Option<VirtualAddress> {
+        self.mappings
+            .read()
+            .values()
+            .next_back()
+            .map(|entry| entry.addr + (entry.span - 1))
+    }
+
+    /// Forget about whatever used to be mapped above the highest mapping, e.g. after removing
+    /// the top mappings (`compact` does this itself). The mappings are untouched, but the point just past the
+    /// last mapping or reservation that was placed for the caller is pulled back to just above
+    /// the highest mapping (or to 0), so that allocating carries on packing from there.
+    pub fn trim(&mut self) {
+        self.next_fit = self
+            .highest_mapped_address()
+            .map_or(VirtualAddress::new(0), |last| last + 1);
+    }
+
+    /// The number of mappings in this `AddressSpace`.
+    #[must_use]
+    pub fn mapping_count(&self) -> usize {
+        self.mappings.read().len()
+    }
+
+    /// Whether this `AddressSpace` has no mappings.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.mappings.read().is_empty()
+    }
+
+    /// Remove every mapping, dropping this `AddressSpace`'s references to their sources,
+    /// along with the segments naming them and the record of which pages are resident.
+    /// Reservations, the fault handler and the settings the space was made with are kept,
+    /// and the next mapping is placed as in a new space.
+    pub fn clear(&mut self) {
+        self.mappings.get_mut().clear();
+        self.segments.clear();
+        self.cow_shares = CowShares::default();
+        self.resident.get_mut().clear();
+        self.next_fit = VirtualAddress::new(0);
+        self.lookup_cache = LookupCache::new();
+    }
+
+    /// The total size of all mappings, in bytes.
+    #[must_use]
+    pub fn total_mapped_bytes(&self) -> usize {
+        self.mappings.read().values().map(|entry| entry.span).sum()
+    }
+
+    /// The size of the largest region that is neither mapped nor reserved, counting the gap
+    /// below the first mapping and the gap between the last mapping and the ceiling.
+    #[must_use]
+    pub fn largest_free_gap(&self) -> usize {
+        self.free_ranges(&self.mappings.read())
+            .map(|gap| gap.end - gap.start)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// How scattered the free space is, from 0 when it is all one gap to nearly 1 when it is
+    /// spread over many small ones: one minus the share of the free bytes that lie in the
+    /// largest gap. A space with no free bytes at all counts as 0, since there's nothing to
+    /// be scattered.
+    #[must_use]
+    pub fn fragmentation(&self) -> f64 {
+        let (total, largest) = self
+            .free_ranges(&self.mappings.read())
+            .map(|gap| gap.end - gap.start)
+            .fold((0, 0), |(total, largest), len| {
+                (total + len, largest.max(len))
+            });
+        if total == 0 {
+            return 0.0;
         }
+        1.0 - largest as f64 / total as f64
     }
 
+    /// An estimate of the memory used by the structure that holds the mappings, not counting
+    /// their sources or dirty bitmaps: the number of B-tree nodes it would take to hold them
+    /// all if every node were full, times the size of a node. There is no `shrink_to_fit`,
+    /// because `BTreeMap` frees each node as soon as it empties.
     #[must_use]
-    /// Turn off all flags in self that are on in other.
+    pub fn structure_bytes(&self) -> usize {
+        self.mappings.read().len().div_ceil(BTREE_NODE_ENTRIES) * BTREE_NODE_BYTES
+    }
+
+    /// Record a new mapping. Callers are responsible for checking that `entry` fits.
+    fn insert(&mut self, entry: MapEntry) {
+        self.mappings.get_mut().insert(entry.addr, entry);
+    }
+
+    /// Remove the mapping to `DataSource` that starts at the given address, as long as `source`
+    /// is the very source behind it, so that a caller can only remove mappings it made. A
+    /// different source with the same contents doesn't count.
     ///
-    /// You can think of this as `self &! other` on each field.
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`, or `SourceMismatch` if the mapping there is
+    /// backed by a different source, in which case nothing is removed.
+    pub fn remove_mapping<D: DataSource + ?Sized>(
+        &mut self,
+        source: &D,
+        start: VirtualAddress,
+    ) -> Result<(), MappingError> {
+        let entry = self
+            .mappings
+            .get_mut()
+            .get(&start)
+            .ok_or(MappingError::NotFound { addr: start })?;
+        if !entry.source.is(source) {
+            return Err(MappingError::SourceMismatch { addr: start });
+        }
+        self.mappings.get_mut().remove(&start);
+        Ok(())
+    }
+
+    /// Back the mapping that starts at `start` with `new_offset` on of `new_source` instead,
+    /// keeping its range and permissions, e.g. to pick up a file that has been reloaded. The
+    /// new bytes are seen from the next access on. The mapping is no longer copy-on-write,
+    /// since the new source isn't shared with a fork, and none of its pages count as dirty.
     ///
-    /// ```
-    /// # use reedos_address_space::FlagBuilder;
-    /// let read_execute = FlagBuilder::read().toggle_execute();
-    /// let execute = FlagBuilder::execute();
-    /// let new = read_execute.but_not(execute);
-    /// assert_eq!(new, FlagBuilder::new().toggle_read());
-    /// ```
-    pub const fn but_not(self, other: Self) -> Self {
-        let read = self.read && !other.read;
-        let write = self.write && !other.write;
-        let execute = self.execute && !other.execute;
-        let cow = self.cow && !other.cow;
-        let private = self.private && !other.private;
-        let shared = self.shared && !other.shared;
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`, `SourceTooSmall` if the new source from
+    /// `new_offset` on doesn't reach into the mapping's last page, or `Aliased` if the new bytes are mapped
+    /// elsewhere and aliasing isn't allowed. In each case the mapping is left as it was.
+    pub fn remap_source(
+        &mut self,
+        start: VirtualAddress,
+        new_source: Arc<dyn DataSource>,
+        new_offset: usize,
+    ) -> Result<(), MappingError> {
+        let span = self
+            .mappings
+            .get_mut()
+            .get(&start)
+            .ok_or(MappingError::NotFound { addr: start })?
+            .span;
+        check_pages(&*new_source, new_offset, span)?;
+        self.check_alias(
+            Arc::as_ptr(&new_source),
+            new_offset,
+            span,
+            Some(start..start + span),
+        )?;
+        let entry = self
+            .mappings
+            .get_mut()
+            .get_mut(&start)
+            .ok_or(MappingError::NotFound { addr: start })?;
+        entry.source = Backing::Strong(new_source);
+        entry.offset = new_offset;
+        entry.cow = None;
+        entry.dirty = DirtyBitmap::default();
+        Ok(())
+    }
 
-        Self {
-            read,
-            write,
-            execute,
-            cow,
-            private,
-            shared,
-        }
+    /// Remove the mapping that starts at `start`, whatever backs it, and return it.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`.
+    pub fn remove_mapping_at(
+        &mut self,
+        start: VirtualAddress,
+    ) -> Result<MappingInfo, MappingError> {
+        self.mappings
+            .get_mut()
+            .remove(&start)
+            .map(|entry| MappingInfo::from(&entry))
+            .ok_or(MappingError::NotFound { addr: start })
+    }
+
+    /// Extend the mapping that starts at `start` in place to `new_span` bytes, rounded up to a
+    /// multiple of `PAGE_SIZE`. A `new_span` no larger than the current span changes nothing.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`, `NoSpace` if anything is mapped or reserved
+    /// in the way or the mapping would pass the top of the address space, `SourceTooSmall` if
+    /// `offset + new_span` is past the end of the mapping's source, `SourceGone` if the
+    /// mapping's source was held weakly and has been dropped, or `Aliased` if the new pages
+    /// would show source bytes mapped elsewhere and aliasing isn't allowed.
+    pub fn grow_mapping(
+        &mut self,
+        start: VirtualAddress,
+        new_span: usize,
+    ) -> Result<(), MappingError> {
+        let entry = self
+            .mappings
+            .get_mut()
+            .get(&start)
+            .cloned()
+            .ok_or(MappingError::NotFound { addr: start })?;
+        let wanted = new_span;
+        let new_span = page_align(new_span).ok_or(MappingError::NoSpace)?;
+        if new_span <= entry.span {
+            return Ok(());
+        }
+        // the new pages must be free as for `add_mapping_at`, and not reserved either
+        let old_end = start + entry.span;
+        let new_end = start.checked_add(new_span).ok_or(MappingError::NoSpace)?;
+        let reserved = self
+            .reserved
+            .range(..new_end)
+            .next_back()
+            .is_some_and(|(&base, &span)| old_end < base + span);
+        if reserved || self.check_free(old_end, new_end - old_end, 0).is_err() {
+            return Err(MappingError::NoSpace);
+        }
+        entry
+            .source
+            .with(start, |source| check_span(source, entry.offset, wanted))?;
+        self.check_alias(
+            entry.source.as_ptr(),
+            entry.offset + entry.span,
+            new_span - entry.span,
+            None,
+        )?;
+        if let Some(entry) = self.mappings.get_mut().get_mut(&start) {
+            entry.span = new_span;
+        }
+        Ok(())
+    }
+
+    /// Cut the mapping that starts at `start` down to `new_span` bytes, rounded up to a multiple
+    /// of `PAGE_SIZE`, unmapping the rest. Shrinking to zero removes the mapping altogether, and
+    /// a `new_span` no smaller than the current span changes nothing.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`.
+    pub fn shrink_mapping(
+        &mut self,
+        start: VirtualAddress,
+        new_span: usize,
+    ) -> Result<(), MappingError> {
+        // a span too big to round up is certainly no smaller than the current one
+        let new_span = page_align(new_span).unwrap_or(usize::MAX);
+        let entry = self
+            .mappings
+            .get_mut()
+            .get_mut(&start)
+            .ok_or(MappingError::NotFound { addr: start })?;
+        if new_span == 0 {
+            self.mappings.get_mut().remove(&start);
+        } else {
+            entry.span = entry.span.min(new_span);
+            entry.dirty.truncate(entry.page_count());
+        }
+        Ok(())
+    }
+
+    /// Copy every mapping of `other` into this `AddressSpace` at the same base, with the same
+    /// source, offset, permissions and guard pages, e.g. to overlay a shared library's layout
+    /// onto a process's. Either all of them are added or none is.
+    ///
+    /// # Errors
+    /// `WxViolation` if one of `other`'s mappings breaks this space's W^X policy,
+    /// `TooManyMappings` if there isn't room for all of them under `with_max_mappings`, or the
+    /// errors of `add_mapping_at` for the first that doesn't fit, e.g. `Overlap` naming the
+    /// mapping of this space that it collides with.
+    pub fn merge_from(&mut self, other: &Self) -> Result<(), MappingError> {
+        let theirs = other.mappings.read();
+        if theirs.len()
+            > self
+                .max_mappings
+                .saturating_sub(self.mappings.get_mut().len())
+        {
+            return Err(MappingError::TooManyMappings);
+        }
+        for entry in theirs.values() {
+            self.check_wx(entry.perms)?;
+            self.check_free(entry.addr, entry.span, entry.guard)?;
+            self.check_alias(entry.source.as_ptr(), entry.offset, entry.span, None)?;
+        }
+        for entry in theirs.values() {
+            if let Some(sharing) = &entry.cow {
+                self.cow_shares.join(sharing);
+            }
+            self.insert(entry.clone());
+        }
+        Ok(())
+    }
+
+    /// Move the mapping that starts at `old_start` so that it starts at `new_start` instead, like
+    /// `mremap(2)`, keeping its source, offset and permissions. The new range may overlap the
+    /// old one. Segments and resident pages move with the mapping.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `old_start`, `Pinned` if it is pinned, or the errors
+    /// of `add_mapping_at` if the mapping doesn't fit at `new_start`, in which case it stays
+    /// where it was.
+    pub fn move_mapping(
+        &mut self,
+        old_start: VirtualAddress,
+        new_start: VirtualAddress,
+    ) -> Result<(), MappingError> {
+        self.check_movable(old_start)?;
+        let mut entry = self
+            .mappings
+            .get_mut()
+            .remove(&old_start)
+            .ok_or(MappingError::NotFound { addr: old_start })?;
+        if let Err(err) = self.check_free(new_start, entry.span, entry.guard) {
+            self.insert(entry);
+            return Err(err);
+        }
+        // its resident pages and segments go with it
+        let moves = [(old_start..old_start + entry.span, new_start)];
+        self.resident.get_mut().relocate(&moves);
+        self.relocate_segments(&moves);
+        entry.addr = new_start;
+        self.insert(entry);
+        Ok(())
+    }
+
+    /// Move every mapping backed by `source` itself by `delta` bytes, keeping their offsets
+    /// into it, e.g. to load a shared library at a different base than before. Either all of
+    /// them move or none does; if `source` backs nothing, nothing happens. Segments and
+    /// resident pages move with the mappings.
+    ///
+    /// # Errors
+    /// `Pinned` naming the first of the mappings that is pinned, `OutOfRange` naming the
+    /// current start of a mapping that would move below zero, or the
+    /// errors of `add_mapping_at` for the first mapping that doesn't fit at its new start,
+    /// e.g. `Unaligned` if `delta` isn't a multiple of `PAGE_SIZE` or `Overlap` naming a
+    /// mapping in the way.
+    pub fn rebase(
+        &mut self,
+        source: &Arc<dyn DataSource>,
+        delta: isize,
+    ) -> Result<(), MappingError> {
+        let bases: Vec<_> = self
+            .mappings
+            .get_mut()
+            .values()
+            .filter(|entry| entry.source.is(&**source))
+            .map(|entry| entry.addr)
+            .collect();
+        for &addr in &bases {
+            self.check_movable(addr)?;
+        }
+        let moving: Vec<_> = bases
+            .iter()
+            .filter_map(|addr| self.mappings.get_mut().remove(addr))
+            .collect();
+        // the mappings move together, so they can only collide with those that stay put
+        let moved = moving
+            .iter()
+            .map(|entry| {
+                let start = entry
+                    .addr
+                    .as_usize()
+                    .checked_add_signed(delta)
+                    .map(VirtualAddress::new)
+                    .ok_or(MappingError::OutOfRange { addr: entry.addr })?;
+                self.check_free(start, entry.span, entry.guard)?;
+                Ok(start)
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match moved {
+            Ok(starts) => {
+                let moves: Vec<_> = moving
+                    .iter()
+                    .zip(&starts)
+                    .map(|(entry, &start)| (entry.addr..entry.addr + entry.span, start))
+                    .collect();
+                self.resident.get_mut().relocate(&moves);
+                self.relocate_segments(&moves);
+                for (mut entry, start) in moving.into_iter().zip(starts) {
+                    entry.addr = start;
+                    self.insert(entry);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                for entry in moving {
+                    self.insert(entry);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Move the mapping that starts at `old_start` to wherever `find_free` finds room for it
+    /// outside its current range, and return its new base.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `old_start`, `Pinned` if it is pinned, or `NoSpace`
+    /// if there's nowhere else for it to go.
+    pub fn move_mapping_auto(
+        &mut self,
+        old_start: VirtualAddress,
+    ) -> Result<VirtualAddress, MappingError> {
+        self.check_movable(old_start)?;
+        let span = self
+            .mappings
+            .get_mut()
+            .get(&old_start)
+            .ok_or(MappingError::NotFound { addr: old_start })?
+            .span;
+        let new_start = self.find_free(span).ok_or(MappingError::NoSpace)?;
+        self.move_mapping(old_start, new_start)?;
+        self.next_fit = new_start + span;
+        Ok(new_start)
+    }
+
+    /// Fix the mapping that starts at `start` where it is, for callers that hold on to
+    /// addresses in it: `move_mapping`, `move_mapping_auto` and `rebase` refuse to move it,
+    /// and `compact` packs the other mappings around it. Pieces split off it stay pinned.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`.
+    pub fn pin(&mut self, start: VirtualAddress) -> Result<(), MappingError> {
+        self.set_pinned(start, true)
+    }
+
+    /// Let the mapping that starts at `start` be moved again after `pin`.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`.
+    pub fn unpin(&mut self, start: VirtualAddress) -> Result<(), MappingError> {
+        self.set_pinned(start, false)
+    }
+
+    fn set_pinned(&mut self, start: VirtualAddress, pinned: bool) -> Result<(), MappingError> {
+        self.mappings
+            .get_mut()
+            .get_mut(&start)
+            .ok_or(MappingError::NotFound { addr: start })?
+            .pinned = pinned;
+        Ok(())
+    }
+
+    /// Check that the mapping starting at `start`, if there is one, isn't pinned.
+    fn check_movable(&self, start: VirtualAddress) -> Result<(), MappingError> {
+        if self
+            .mappings
+            .read()
+            .get(&start)
+            .is_some_and(|entry| entry.pinned)
+        {
+            Err(MappingError::Pinned { addr: start })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Iterate over the mappings in this `AddressSpace`, in ascending address order.
+    pub fn iter(&self) -> impl Iterator<Item = MappingInfo> + '_ {
+        let mappings: Vec<_> = self
+            .mappings
+            .read()
+            .values()
+            .map(MappingInfo::from)
+            .collect();
+        mappings.into_iter()
+    }
+
+    /// Call `f` on each mapping in ascending address order, letting it change the mapping's
+    /// offset and permissions, e.g. to rebase every view of a source or make them all
+    /// read-only. The changes are made only once every mapping has been visited, and only if
+    /// they all still hold up; otherwise nothing changes.
+    ///
+    /// # Errors
+    /// `WxViolation` if the new permissions of a mapping break the W^X policy, `OutOfRange`
+    /// naming the start of a mapping whose new offset runs it past the end of its source, or
+    /// `Aliased` naming a mapping whose source bytes a new offset would show again when
+    /// aliasing isn't allowed.
+    pub fn for_each_mapping_mut(
+        &mut self,
+        mut f: impl FnMut(&mut MapEntryView),
+    ) -> Result<(), MappingError> {
+        let mappings = self.mappings.read();
+        let mut views = Vec::with_capacity(mappings.len());
+        // where each view's source is, and whether the view's offset has changed
+        let mut moved = Vec::with_capacity(mappings.len());
+        for entry in mappings.values() {
+            let mut view = MapEntryView {
+                addr: entry.addr,
+                span: entry.span,
+                source: entry.source.upgrade(),
+                offset: entry.offset,
+                perms: entry.perms,
+            };
+            f(&mut view);
+            self.check_wx(view.perms)?;
+            // there is nothing to run past in a source that has been dropped
+            if let Some(source) = &view.source {
+                check_pages(&**source, view.offset, view.span)
+                    .map_err(|_| MappingError::OutOfRange { addr: view.addr })?;
+            }
+            moved.push((entry.source.as_ptr(), view.offset != entry.offset));
+            views.push(view);
+        }
+        drop(mappings);
+        // a new offset must keep clear of the others as they will be, not as they are
+        if !self.allow_aliasing {
+            let pending: Vec<_> = views.iter().zip(&moved).collect();
+            for &(view, &(source, _)) in pending.iter().filter(|(_, (_, moved))| *moved) {
+                let bytes = view.offset..view.offset + view.span;
+                let alias = pending.iter().find(|(other, (theirs, _))| {
+                    other.addr != view.addr
+                        && core::ptr::addr_eq(*theirs, source)
+                        && other.offset < bytes.end
+                        && bytes.start < other.offset + other.span
+                });
+                if let Some((other, _)) = alias {
+                    return Err(MappingError::Aliased { addr: other.addr });
+                }
+            }
+        }
+        for (entry, view) in self.mappings.get_mut().values_mut().zip(views) {
+            if entry.offset != view.offset {
+                entry.offset = view.offset;
+                // it may see source pages this space had stopped sharing
+                if let Some(sharing) = &entry.cow {
+                    self.cow_shares.join(sharing);
+                }
+            }
+            entry.perms = view.perms;
+        }
+        Ok(())
+    }
+
+    /// Every mapping backed by `source` itself (not merely an equal source), in ascending
+    /// address order, e.g. to find all the views of a file that has changed.
+    #[must_use]
+    pub fn mappings_for_source(&self, source: &Arc<dyn DataSource>) -> Vec<MappingInfo> {
+        self.mappings
+            .read()
+            .values()
+            .filter(|entry| entry.source.is(&**source))
+            .map(MappingInfo::from)
+            .collect()
+    }
+
+    /// The mappings labelled `tag` by `add_mapping_tagged`, in ascending address order.
+    pub fn mappings_with_tag<'a>(&'a self, tag: &'a str) -> impl Iterator<Item = MappingInfo> + 'a {
+        let tagged: Vec<_> = self
+            .mappings
+            .read()
+            .values()
+            .filter(|entry| entry.tag.as_deref() == Some(tag))
+            .map(MappingInfo::from)
+            .collect();
+        tagged.into_iter()
+    }
+
+    /// The bases of the other mappings that show some of the same source bytes as the mapping
+    /// that starts at `start`, in ascending order, so that a write through one can be seen
+    /// through the others. Empty if there are none, or no mapping starts at `start`.
+    #[must_use]
+    pub fn aliases_of(&self, start: VirtualAddress) -> Vec<VirtualAddress> {
+        let mappings = self.mappings.read();
+        let Some(mapping) = mappings.get(&start) else {
+            return Vec::new();
+        };
+        let bytes = mapping.offset..mapping.offset + mapping.span;
+        Self::same_source(&mappings, mapping.source.as_ptr())
+            .filter(|entry| {
+                entry.addr != start
+                    && entry.offset < bytes.end
+                    && bytes.start < entry.offset + entry.span
+            })
+            .map(|entry| entry.addr)
+            .collect()
+    }
+
+    /// Iterate, in ascending address order, over every mapping that overlaps
+    /// `[start, start + span)`, including those that only partly overlap it.
+    pub fn range_mappings(
+        &self,
+        start: VirtualAddress,
+        span: usize,
+    ) -> impl Iterator<Item = MappingInfo> + '_ {
+        let end = start
+            .checked_add(span)
+            .unwrap_or(VirtualAddress::new(usize::MAX));
+        let mappings = self.mappings.read();
+        let straddling = mappings
+            .range(..start)
+            .next_back()
+            .filter(|(_, entry)| start < entry.addr + entry.span && span > 0);
+        let overlapping: Vec<_> = straddling
+            .into_iter()
+            .chain(mappings.range(start..end))
+            .map(|(_, entry)| MappingInfo::from(entry))
+            .collect();
+        overlapping.into_iter()
+    }
+
+    /// Remove everything mapped in `[start, start + span)`, like `munmap`, with `span` rounded
+    /// up to a multiple of `PAGE_SIZE`. Mappings that straddle either end of the range are
+    /// trimmed, and a mapping that contains the whole range is split in two. Parts of the
+    /// range that are already unmapped are ignored.
+    ///
+    /// # Errors
+    /// `Unaligned` if `start` is not a multiple of `PAGE_SIZE`, or `OutOfRange` if the range
+    /// extends past the top of the address space.
+    pub fn unmap(&mut self, start: VirtualAddress, span: usize) -> Result<(), MappingError> {
+        if !start.is_aligned(PAGE_SIZE) {
+            return Err(MappingError::Unaligned { addr: start });
+        }
+        let end = page_align(span)
+            .and_then(|span| start.checked_add(span))
+            .filter(|&end| end <= self.ceiling)
+            .ok_or(MappingError::OutOfRange { addr: start })?;
+        Self::unmap_range(self.mappings.get_mut(), start, end);
+        Ok(())
+    }
+
+    /// Find the `DataSource` backing `addr`, and the offset of `addr` within that source.
+    ///
+    /// Returns `None` if `addr` is not mapped.
+    #[must_use]
+    pub fn translate(&self, addr: VirtualAddress) -> Option<(Arc<dyn DataSource>, usize)> {
+        let mappings = self.mappings.read();
+        let entry = self.entry_containing(&mappings, addr)?;
+        entry.stats.record_translate();
+        Some((entry.source.upgrade()?, entry.offset + (addr - entry.addr)))
+    }
+
+    /// Whether `addr` is mapped.
+    #[must_use]
+    pub fn contains(&self, addr: VirtualAddress) -> bool {
+        self.entry_containing(&self.mappings.read(), addr).is_some()
+    }
+
+    /// The mapping that contains `addr`, if any.
+    #[must_use]
+    pub fn mapping_at(&self, addr: VirtualAddress) -> Option<MappingInfo> {
+        self.entry_containing(&self.mappings.read(), addr)
+            .map(MappingInfo::from)
+    }
+
+    /// Read `buf.len()` bytes starting at `addr` from the sources mapped there.
+    ///
+    /// Faults in reserved regions are first offered to the handler registered with `on_fault`.
+    /// Whatever is still unmapped after that is dealt with according to the space's
+    /// `UnmappedReadPolicy`.
+    ///
+    /// # Errors
+    /// `Fault` naming the first unmapped address if the range is not entirely mapped and the
+    /// policy is to fault, `GuardPage` naming the first address in a guard page,
+    /// `PermissionDenied` if part of it is not readable, `Source` if a backing `DataSource`
+    /// fails, or any error from the fault handler. In every case, `buf` is left untouched from
+    /// the failing address onward.
+    pub fn read(&self, addr: VirtualAddress, buf: &mut [u8]) -> Result<(), MappingError> {
+        self.touch_resident(addr, buf.len());
+        let mut done = 0;
+        loop {
+            match self.read_mapped(addr + done, &mut buf[done..]) {
+                Err(MappingError::Fault { addr: fault }) if self.demand_map(fault)? => {
+                    done = fault - addr;
+                }
+                Err(MappingError::Fault { addr: fault })
+                    if self.unmapped_reads != UnmappedReadPolicy::Fault =>
+                {
+                    // the gap runs up to the next mapping, or its guard pages
+                    let gap_end = self
+                        .mappings
+                        .read()
+                        .range(fault..)
+                        .next()
+                        .map_or(buf.len(), |(_, entry)| {
+                            (entry.guard_start() - addr).min(buf.len())
+                        });
+                    if self.unmapped_reads == UnmappedReadPolicy::ZeroFill {
+                        buf[fault - addr..gap_end].fill(0);
+                    }
+                    done = gap_end;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Read `len` bytes starting at `addr` into a new `Vec`, as `read` does.
+    ///
+    /// # Errors
+    /// The errors of `read`, in which case nothing is returned.
+    pub fn read_vec(&self, addr: VirtualAddress, len: usize) -> Result<Vec<u8>, MappingError> {
+        let mut buf = vec![0; len];
+        self.read(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    /// Fetch instructions into `buf` starting at `addr`, as an emulator's instruction fetch
+    /// would: like `read`, but every byte must be executable rather than readable. The fault
+    /// handler isn't called, so the range must already be mapped.
+    ///
+    /// # Errors
+    /// `Fault` naming the first unmapped address if the range is not entirely mapped,
+    /// `NotExecutable` naming the first address whose mapping doesn't permit execution, or
+    /// `Source` if a backing `DataSource` fails. `buf` is left untouched from the failing
+    /// address onward.
+    pub fn fetch(&self, addr: VirtualAddress, buf: &mut [u8]) -> Result<(), MappingError> {
+        self.for_each_piece(addr, buf.len(), |entry, offset, piece| {
+            let at = addr + piece.start;
+            if !entry.effective_perms().contains(Permissions::EXECUTE) {
+                return Err(MappingError::NotExecutable { addr: at });
+            }
+            entry.source.with(at, |source| {
+                read_source(source, offset, &mut buf[piece.clone()], at)
+            })
+        })
+    }
+
+    /// Read into `buf` starting at `addr`, without calling the fault handler.
+    fn read_mapped(&self, addr: VirtualAddress, buf: &mut [u8]) -> Result<(), MappingError> {
+        self.for_each_piece(addr, buf.len(), |entry, offset, piece| {
+            let at = addr + piece.start;
+            entry.require(Permissions::READ, at)?;
+            let buf = &mut buf[piece.clone()];
+            entry.source.with(at, |source| {
+                // sources that hold the bytes in memory are copied from directly
+                if source.with_slice(offset, buf.len(), &mut |bytes| buf.copy_from_slice(bytes)) {
+                    return Ok(());
+                }
+                read_source(source, offset, buf, at)
+            })?;
+            entry.stats.record_read();
+            Ok(())
+        })
+    }
+
+    /// Write `buf` to the sources mapped starting at `addr`.
+    ///
+    /// Writes to copy-on-write mappings are resolved with `handle_cow_fault`, so only the
+    /// pages actually written are copied, and faults in reserved regions are first offered to
+    /// the handler registered with `on_fault`.
+    ///
+    /// # Errors
+    /// `Fault` naming the first unmapped address if the range is not entirely mapped,
+    /// `PermissionDenied` if part of it is not writable, `Source` if a backing `DataSource`
+    /// fails, or any error from the fault handler. Bytes before the failing address have
+    /// already been written.
+    pub fn write(&mut self, addr: VirtualAddress, buf: &[u8]) -> Result<(), MappingError> {
+        self.touch_resident(addr, buf.len());
+        let mut done = 0;
+        loop {
+            match self.write_mapped(addr + done, &buf[done..]) {
+                Err(MappingError::PermissionDenied { addr: fault })
+                    if self
+                        .entry_containing(&self.mappings.read(), fault)
+                        .is_some_and(|entry| {
+                            entry.cow.is_some() && entry.perms.contains(Permissions::WRITE)
+                        }) =>
+                {
+                    self.handle_cow_fault(fault)?;
+                    done = fault - addr;
+                }
+                Err(MappingError::Fault { addr: fault }) if self.demand_map(fault)? => {
+                    done = fault - addr;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Write all of `buf` starting at `addr`; the same as `write`, under the name that
+    /// `std::io::Write` users reach for.
+    ///
+    /// # Errors
+    /// The errors of `write`.
+    pub fn write_all(&mut self, addr: VirtualAddress, buf: &[u8]) -> Result<(), MappingError> {
+        self.write(addr, buf)
+    }
+
+    /// Claim `span` bytes of address space without backing them, like `VirtualAlloc` with
+    /// `MEM_RESERVE`, and return where. See `reserve_at`.
+    ///
+    /// # Errors
+    /// `InvalidSpan` if `span` is zero, or `NoSpace` if there is no free region of at least
+    /// `span` bytes.
+    pub fn reserve(&mut self, span: usize) -> Result<VirtualAddress, MappingError> {
+        if span == 0 {
+            return Err(MappingError::InvalidSpan);
+        }
+        let start = self.find_free(span).ok_or(MappingError::NoSpace)?;
+        self.reserve_at(start, span)?;
+        self.next_fit = start + self.reserved[&start];
+        Ok(start)
+    }
+
+    /// Claim `[start, start + span)` without backing it. `add_mapping` won't place mappings in
+    /// a reserved range, and accesses there fault until it is backed, either all at once by
+    /// `commit` or a piece at a time by the handler registered with `on_fault`, which is called
+    /// for every fault in a reserved range. `span` is rounded up to a multiple of `PAGE_SIZE`.
+    ///
+    /// # Errors
+    /// `InvalidSpan` if `span` is zero, `Unaligned` if `start` is not a multiple of
+    /// `PAGE_SIZE`, `OutOfRange` if the range extends past the top of the address space, or
+    /// `Overlap` naming an existing reservation that intersects it.
+    pub fn reserve_at(&mut self, start: VirtualAddress, span: usize) -> Result<(), MappingError> {
+        if span == 0 {
+            return Err(MappingError::InvalidSpan);
+        }
+        if !start.is_aligned(PAGE_SIZE) {
+            return Err(MappingError::Unaligned { addr: start });
+        }
+        let end = page_align(span)
+            .and_then(|span| start.checked_add(span))
+            .filter(|&end| end <= self.ceiling)
+            .ok_or(MappingError::OutOfRange { addr: start })?;
+        let span = end - start;
+        let before = self
+            .reserved
+            .range(..start)
+            .next_back()
+            .filter(|(&base, &reserved)| start < base + reserved);
+        if let Some((&addr, _)) = before.or_else(|| self.reserved.range(start..end).next()) {
+            return Err(MappingError::Overlap { addr });
+        }
+        self.reserved.insert(start, span);
+        Ok(())
+    }
+
+    /// Register `handler` to be called when `read` or `write` touch an unmapped address in a
+    /// reserved range, replacing any previous handler. The handler is given an `AddressSpace`
+    /// with this one's ceiling, policies and reservations but none of its mappings, so that it
+    /// can add a mapping for the faulting address; whatever it maps there is then moved into
+    /// this `AddressSpace`, and anything else it changes is dropped. If that maps the faulting
+    /// address, the access carries on from there, and otherwise it fails with `Fault` as usual.
+    /// Faults are handled one at a time, even when they come from reads on different threads.
+    ///
+    /// Clones and forks of this `AddressSpace` share its handler.
+    pub fn on_fault(
+        &mut self,
+        handler: impl FnMut(&mut Self, VirtualAddress) -> Result<(), MappingError> + Send + 'static,
+    ) {
+        self.on_fault = Some(Arc::new(Mutex::new(handler)));
+    }
+
+    /// Back the whole of the reserved range starting at `start` with `source` from `offset` on,
+    /// turning it into an ordinary mapping with `perms`, like `VirtualAlloc` with `MEM_COMMIT`.
+    ///
+    /// # Errors
+    /// `NotFound` if no reserved range starts at `start`, or the errors of `add_mapping_at`,
+    /// e.g. `Overlap` if the fault handler has already mapped part of the range. The range
+    /// stays reserved if committing fails.
+    pub fn commit(
+        &mut self,
+        start: VirtualAddress,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        perms: Permissions,
+    ) -> Result<(), MappingError> {
+        let span = *self
+            .reserved
+            .get(&start)
+            .ok_or(MappingError::NotFound { addr: start })?;
+        self.add_mapping_at(source, offset, span, start, perms)?;
+        self.reserved.remove(&start);
+        Ok(())
+    }
+
+    /// Whether `addr` lies in a reserved range.
+    fn is_reserved(&self, addr: VirtualAddress) -> bool {
+        self.reserved
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(&base, &span)| addr < base + span)
+    }
+
+    /// Give the fault handler a chance to map `addr`, if it is reserved. Returns whether it did,
+    /// so that the faulting access can be retried.
+    fn demand_map(&self, addr: VirtualAddress) -> Result<bool, MappingError> {
+        let reserved = self.is_reserved(addr);
+        let Some(handler) = self.on_fault.clone().filter(|_| reserved) else {
+            return Ok(false);
+        };
+        let mut handler = handler.lock();
+        // another read may have had the page mapped while this one waited for the handler
+        if self.contains(addr) {
+            return Ok(true);
+        }
+        let mut scratch = Self::new(&self.name).with_ceiling(self.ceiling.as_usize());
+        scratch.reserved = self.reserved.clone();
+        scratch.wx_policy = self.wx_policy;
+        scratch.allow_aliasing = self.allow_aliasing;
+        scratch.policy = self.policy;
+        scratch.direction = self.direction;
+        handler(&mut scratch, addr)?;
+        self.adopt(scratch.mappings.into_inner())?;
+        if !self.contains(addr) {
+            return Ok(false);
+        }
+        self.make_resident(addr)?;
+        Ok(true)
+    }
+
+    /// Move `mappings`, made by the fault handler, into this space, as long as they fit among
+    /// its own as `merge_from` would need.
+    fn adopt(&self, mappings: Mappings) -> Result<(), MappingError> {
+        if mappings.len() > self.max_mappings.saturating_sub(self.mapping_count()) {
+            return Err(MappingError::TooManyMappings);
+        }
+        for entry in mappings.values() {
+            self.check_free(entry.addr, entry.span, entry.guard)?;
+            self.check_alias(entry.source.as_ptr(), entry.offset, entry.span, None)?;
+        }
+        // faults are handled one at a time, so nothing can have been mapped since the checks
+        self.mappings.write().extend(mappings);
+        Ok(())
+    }
+
+    /// Count the pages of the mapping that the fault handler just made for `addr` as resident,
+    /// and evict the least recently used others until they fit the budget again.
+    fn make_resident(&self, addr: VirtualAddress) -> Result<(), MappingError> {
+        let faulted = addr.align_down(PAGE_SIZE);
+        let (gone, new): (Vec<_>, Vec<_>) = {
+            let mappings = self.mappings.read();
+            let resident = self.resident.lock();
+            let gone = resident
+                .pages()
+                .filter(|&page| self.entry_containing(&mappings, page).is_none())
+                .collect();
+            let new = self
+                .entry_containing(&mappings, addr)
+                .map(|entry| {
+                    (0..entry.page_count())
+                        .map(|page| entry.first_page() + page * PAGE_SIZE)
+                        .filter(|&page| page != faulted && !resident.contains(page))
+                        .collect()
+                })
+                .unwrap_or_default();
+            (gone, new)
+        };
+        let mut resident = self.resident.lock();
+        for page in gone {
+            resident.remove(page);
+        }
+        for page in new {
+            resident.insert(page);
+        }
+        resident.insert(faulted);
+        while resident.over_budget() {
+            let Some(page) = resident.oldest_except(faulted) else {
+                break;
+            };
+            // eviction takes the locks itself
+            drop(resident);
+            self.evict(page)?;
+            resident = self.resident.lock();
+        }
+        Ok(())
+    }
+
+    /// Write back whatever has been written to `page` and unmap it.
+    fn evict(&self, page: VirtualAddress) -> Result<(), MappingError> {
+        let pieces: Vec<_> = self
+            .range_mappings(page, PAGE_SIZE)
+            .map(|mapping| {
+                let start = mapping.addr.max(page);
+                (
+                    start,
+                    (mapping.addr + mapping.span).min(page + PAGE_SIZE) - start,
+                )
+            })
+            .collect();
+        for (start, len) in pieces {
+            self.flush(start, len)?;
+        }
+        Self::unmap_range(&mut self.mappings.write(), page, page + PAGE_SIZE);
+        self.resident.lock().remove(page);
+        Ok(())
+    }
+
+    /// Count an access to `[addr, addr + len)` as the latest use of the resident pages in it.
+    fn touch_resident(&self, addr: VirtualAddress, len: usize) {
+        let end = addr
+            .checked_add(len)
+            .unwrap_or(VirtualAddress::new(usize::MAX));
+        self.resident.lock().touch(addr.align_down(PAGE_SIZE)..end);
+    }
+
+    /// Write `buf` starting at `addr`, treating copy-on-write mappings as read-only, and mark
+    /// the pages written as dirty.
+    fn write_mapped(&mut self, addr: VirtualAddress, buf: &[u8]) -> Result<(), MappingError> {
+        let mut written = 0;
+        let result = self.for_each_piece(addr, buf.len(), |entry, offset, piece| {
+            let at = addr + piece.start;
+            entry.require(Permissions::WRITE, at)?;
+            entry.source.with(at, |source| {
+                write_source(source, offset, &buf[piece.clone()], at)
+            })?;
+            entry.stats.record_write();
+            written = piece.end;
+            Ok(())
+        });
+        self.mark_dirty(addr, written);
+        result
+    }
+
+    /// Mark every page in `[addr, addr + len)` dirty in the mappings that hold it.
+    fn mark_dirty(&mut self, addr: VirtualAddress, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let end = addr + len;
+        let low = self
+            .entry_containing(&self.mappings.read(), addr)
+            .map_or(addr, |entry| entry.addr);
+        for entry in self
+            .mappings
+            .get_mut()
+            .range_mut(low..end)
+            .map(|(_, entry)| entry)
+        {
+            let first = entry.page_index(addr.max(entry.addr));
+            let last = entry.page_index(end.min(entry.addr + entry.span) - 1);
+            for page in first..=last {
+                entry.dirty.set(page);
+            }
+        }
+    }
+
+    /// Make sure that everything written through `[start, start + span)` has reached the
+    /// storage behind it, like `msync`, by asking the source of each dirty page in the range to
+    /// `flush` it. Runs of consecutive dirty pages are flushed together. Pages stay dirty. An
+    /// empty range flushes nothing.
+    ///
+    /// # Errors
+    /// `NotFound` naming the first address in the range that isn't mapped, in which case
+    /// nothing is flushed, or `Source` naming the first page whose source failed to flush.
+    pub fn flush(&self, start: VirtualAddress, span: usize) -> Result<(), MappingError> {
+        if span == 0 {
+            return Ok(());
+        }
+        self.check_mapped(start, span)?;
+        let end = start + span;
+        let mappings = self.mappings.read();
+        let straddling = mappings.range(..start).next_back().map(|(_, entry)| entry);
+        let within = mappings.range(start..end).map(|(_, entry)| entry);
+        for entry in straddling.into_iter().chain(within) {
+            let first = entry.page_index(start.max(entry.addr));
+            let last = entry.page_index(end.min(entry.addr + entry.span) - 1);
+            let mut runs: Vec<Range<usize>> = Vec::new();
+            for page in entry
+                .dirty
+                .iter()
+                .filter(|page| (first..=last).contains(page))
+            {
+                match runs.last_mut() {
+                    Some(run) if run.end == page => run.end += 1,
+                    _ => runs.push(page..page + 1),
+                }
+            }
+            for run in runs {
+                let addr = (entry.first_page() + run.start * PAGE_SIZE).max(entry.addr);
+                let run_end =
+                    (entry.first_page() + run.end * PAGE_SIZE).min(entry.addr + entry.span);
+                let offset = entry.offset + (addr - entry.addr);
+                entry.source.with(addr, |source| {
+                    // the last page may run past the end of the source
+                    let len = (run_end - addr).min(source.len().saturating_sub(offset));
+                    source
+                        .flush(offset, len)
+                        .map_err(|_| MappingError::Source { addr })
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The bases of the pages written through the mapping that starts at `start`, in ascending
+    /// order, since it was made or last had `clear_dirty` called on it. Empty if no mapping
+    /// starts at `start`.
+    #[must_use]
+    pub fn dirty_pages(&self, start: VirtualAddress) -> Vec<VirtualAddress> {
+        self.mappings
+            .read()
+            .get(&start)
+            .map_or_else(Vec::new, |entry| {
+                entry
+                    .dirty
+                    .iter()
+                    .map(|page| entry.first_page() + page * PAGE_SIZE)
+                    .collect()
+            })
+    }
+
+    /// How often each mapping has been read, written and translated, in ascending address
+    /// order. A mapping that has been split keeps its counts so far in both halves.
+    #[cfg(feature = "stats")]
+    #[must_use]
+    pub fn access_stats(&self) -> Vec<(VirtualAddress, AccessStats)> {
+        self.mappings
+            .read()
+            .iter()
+            .map(|(&addr, entry)| (addr, entry.stats.get()))
+            .collect()
+    }
+
+    /// Mark every page of the mapping that starts at `start` clean again, e.g. once its
+    /// contents have been written back.
+    ///
+    /// # Errors
+    /// `NotFound` if no mapping starts at `start`.
+    pub fn clear_dirty(&mut self, start: VirtualAddress) -> Result<(), MappingError> {
+        self.mappings
+            .get_mut()
+            .get_mut(&start)
+            .ok_or(MappingError::NotFound { addr: start })?
+            .dirty
+            .clear();
+        Ok(())
+    }
+
+    /// Check that `addr` is mapped with at least the permissions in `needed`.
+    ///
+    /// # Errors
+    /// `Fault` if `addr` is not mapped, or `PermissionDenied` if its mapping lacks any of
+    /// `needed`.
+    pub fn check_access(
+        &self,
+        addr: VirtualAddress,
+        needed: Permissions,
+    ) -> Result<(), MappingError> {
+        let mappings = self.mappings.read();
+        self.entry_containing(&mappings, addr)
+            .ok_or_else(|| Self::fault_at(&mappings, addr))?
+            .require(needed, addr)
+    }
+
+    /// Change the permissions of every mapping in `[start, start + span)` to `perms`, like
+    /// `mprotect`, with `span` rounded up to a multiple of `PAGE_SIZE`. Mappings that straddle
+    /// either end of the range are split so that only the part inside the range changes.
+    ///
+    /// # Errors
+    /// `WxViolation` if `perms` breaks the W^X policy, `Unaligned` if `start` is not a
+    /// multiple of `PAGE_SIZE`, or `NotFound` naming the first unmapped address if the range is
+    /// not entirely mapped. In each case nothing is changed.
+    pub fn protect(
+        &mut self,
+        start: VirtualAddress,
+        span: usize,
+        perms: Permissions,
+    ) -> Result<(), MappingError> {
+        self.check_wx(perms)?;
+        if !start.is_aligned(PAGE_SIZE) {
+            return Err(MappingError::Unaligned { addr: start });
+        }
+        let span = page_align(span).ok_or(MappingError::NotFound { addr: start })?;
+        self.check_mapped(start, span)?;
+
+        let end = start + span;
+        Self::split_entry_at(self.mappings.get_mut(), start);
+        Self::split_entry_at(self.mappings.get_mut(), end);
+        for entry in self
+            .mappings
+            .get_mut()
+            .range_mut(start..end)
+            .map(|(_, entry)| entry)
+        {
+            entry.perms = perms;
+        }
+        Ok(())
+    }
+
+    /// Name `[start, start + span)` as the segment `name`, e.g. `.text` or `heap`, so that the
+    /// mappings in it can be managed together with `protect_segment`. `span` is rounded up to
+    /// a multiple of `PAGE_SIZE`, and a segment already called `name` is replaced. A segment
+    /// names a range rather than particular mappings, so it keeps covering the range however
+    /// its mappings are split or merged, and moves with them when they are moved.
+    ///
+    /// # Errors
+    /// `InvalidSpan` if `span` is zero, `Unaligned` if `start` is not a multiple of
+    /// `PAGE_SIZE`, `NotFound` naming the first address in the range that isn't mapped, or
+    /// `Overlap` naming the start of another segment that the range intersects.
+    pub fn define_segment(
+        &mut self,
+        name: &str,
+        start: VirtualAddress,
+        span: usize,
+    ) -> Result<(), MappingError> {
+        if span == 0 {
+            return Err(MappingError::InvalidSpan);
+        }
+        if !start.is_aligned(PAGE_SIZE) {
+            return Err(MappingError::Unaligned { addr: start });
+        }
+        let span = page_align(span).ok_or(MappingError::NotFound { addr: start })?;
+        self.check_mapped(start, span)?;
+        let end = start + span;
+        let overlapping = self.segments.values().find(|segment| {
+            segment.name != name && segment.start < end && start < segment.start + segment.span
+        });
+        if let Some(segment) = overlapping {
+            return Err(MappingError::Overlap {
+                addr: segment.start,
+            });
+        }
+        let segment = Segment {
+            name: name.to_string(),
+            start,
+            span,
+        };
+        self.segments.insert(segment.name.clone(), segment);
+        Ok(())
+    }
+
+    /// The segment called `name`, if there is one.
+    #[must_use]
+    pub fn segment(&self, name: &str) -> Option<&Segment> {
+        self.segments.get(name)
+    }
+
+    /// Forget the segment called `name` and return it, leaving its mappings as they are.
+    pub fn remove_segment(&mut self, name: &str) -> Option<Segment> {
+        self.segments.remove(name)
+    }
+
+    /// The mappings that make up the segment called `name`, in ascending address order, or
+    /// `None` if there is no such segment. Mappings that straddle either end of the segment are
+    /// included.
+    #[must_use]
+    pub fn segment_mappings(&self, name: &str) -> Option<Vec<MappingInfo>> {
+        let segment = self.segments.get(name)?;
+        Some(self.range_mappings(segment.start, segment.span).collect())
+    }
+
+    /// Change the permissions of the whole of the segment called `name` to `perms`, as by
+    /// `protect`.
+    ///
+    /// # Errors
+    /// `UnknownSegment` if there is no segment called `name`, or the errors of `protect`, e.g.
+    /// `NotFound` if part of the segment has been unmapped since it was defined.
+    pub fn protect_segment(&mut self, name: &str, perms: Permissions) -> Result<(), MappingError> {
+        let segment = self
+            .segments
+            .get(name)
+            .ok_or(MappingError::UnknownSegment)?;
+        self.protect(segment.start, segment.span, perms)
+    }
+
+    /// Move the segments along with the mappings in them, once each range `from` of `moves`
+    /// has moved to start at `to`. A segment whose mappings didn't all move together keeps only
+    /// the part that went with its start, so that it never covers what has moved in between.
+    fn relocate_segments(&mut self, moves: &[(Range<VirtualAddress>, VirtualAddress)]) {
+        let moved = |addr: VirtualAddress| {
+            moves
+                .iter()
+                .find(|(from, _)| from.contains(&addr))
+                .map_or(addr, |(from, to)| *to + (addr - from.start))
+        };
+        for segment in self.segments.values_mut() {
+            let start = moved(segment.start);
+            let end = segment.start + segment.span;
+            // the first address in the segment that didn't move as its start did
+            let cut = moves
+                .iter()
+                .flat_map(|(from, _)| [from.start, from.end])
+                .filter(|&at| {
+                    segment.start < at && at < end && moved(at) != start + (at - segment.start)
+                })
+                .min()
+                .unwrap_or(end);
+            segment.span = cut - segment.start;
+            segment.start = start;
+        }
+    }
+
+    /// Merge every pair of neighbouring mappings that continue one another: the second starts
+    /// where the first ends, is backed by the same `DataSource` at the following offset, and has
+    /// the same permissions. Returns the number of merges performed.
+    pub fn coalesce(&mut self) -> usize {
+        let mut merges = 0;
+        let mut merged: BTreeMap<VirtualAddress, MapEntry> = BTreeMap::new();
+        for (addr, entry) in core::mem::take(self.mappings.get_mut()) {
+            if let Some(mut prev) = merged.last_entry() {
+                if prev.get().continues_into(&entry) {
+                    let prev = prev.get_mut();
+                    let skipped = (entry.first_page() - prev.first_page()) / PAGE_SIZE;
+                    prev.dirty
+                        .extend(entry.dirty.iter().map(|page| page + skipped));
+                    prev.stats.absorb(&entry.stats);
+                    prev.span += entry.span;
+                    merges += 1;
+                    continue;
+                }
+            }
+            merged.insert(addr, entry);
+        }
+        *self.mappings.get_mut() = merged;
+        merges
+    }
+
+    /// Split the mapping containing `at` into `[base, at)` and `[at, base + span)`, so that
+    /// the two halves can be changed independently. Splitting at the start of a mapping does
+    /// nothing.
+    ///
+    /// # Errors
+    /// `Unaligned` if `at` is not page-aligned, or `NotFound` if `at` is not mapped.
+    pub fn split_mapping(&mut self, at: VirtualAddress) -> Result<(), MappingError> {
+        if !at.is_aligned(PAGE_SIZE) {
+            return Err(MappingError::Unaligned { addr: at });
+        }
+        if self.entry_containing(&self.mappings.read(), at).is_none() {
+            return Err(MappingError::NotFound { addr: at });
+        }
+        Self::split_entry_at(self.mappings.get_mut(), at);
+        Ok(())
+    }
+
+    /// Slide every mapping down so that they are packed together from address 0 upward, in
+    /// their current order, with only their guard pages between them. Pinned mappings stay
+    /// where they are, and the others are packed below and after them. Reservations stay put
+    /// too: nothing is moved into one, and mappings the fault handler made inside one don't
+    /// move. Segments and resident pages move with their mappings, and `add_mapping` carries
+    /// on from just above the packed mappings, as after `trim`. Returns the number of
+    /// mappings moved.
+    ///
+    /// This changes the addresses of mappings, so it is only safe when nothing holds on to
+    /// addresses in this `AddressSpace`.
+    pub fn compact(&mut self) -> usize {
+        let mut moves = Vec::new();
+        let mut next_free = VirtualAddress::new(0);
+        for (_, mut entry) in core::mem::take(self.mappings.get_mut()) {
+            let mut addr = entry.addr;
+            if !entry.pinned && !self.is_reserved(entry.addr) {
+                // step over any reservation that the mapping and its guard pages would overlap
+                let mut low = next_free;
+                while let Some((&base, &span)) = self
+                    .reserved
+                    .range(..low + entry.guard + entry.span)
+                    .next_back()
+                    .filter(|(&base, &span)| low < base + span)
+                {
+                    low = base + span;
+                }
+                // nothing ever moves up, so nothing lands on a pinned mapping further on
+                addr = addr.min(low + entry.guard);
+            }
+            if addr != entry.addr {
+                moves.push((entry.addr..entry.addr + entry.span, addr));
+                entry.addr = addr;
+            }
+            next_free = addr + entry.span;
+            self.insert(entry);
+        }
+        self.relocate_segments(&moves);
+        self.resident.get_mut().relocate(&moves);
+        self.trim();
+        moves.len()
+    }
+
+    /// Check the invariants every operation should preserve: mappings are sorted and
+    /// non-overlapping, each has a non-zero span that ends at or below the ceiling, and each
+    /// lies within its `DataSource`. Since spans are whole pages, a mapping may run into the
+    /// page containing the source's last byte, but no further.
+    ///
+    /// Meant for debugging and tests; it walks every mapping.
+    ///
+    /// # Errors
+    /// A description of the first violation found.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut prev_end = VirtualAddress::new(0);
+        for (&key, entry) in self.mappings.read().iter() {
+            let addr = entry.addr;
+            if key != addr {
+                return Err(format!("mapping at {addr:#x} is stored under {key:#x}"));
+            }
+            if entry.span == 0 {
+                return Err(format!("mapping at {addr:#x} is empty"));
+            }
+            if entry.guard > addr.as_usize() {
+                return Err(format!(
+                    "guard pages of mapping at {addr:#x} start below zero"
+                ));
+            }
+            if entry.guard_start() < prev_end {
+                return Err(format!(
+                    "mapping at {addr:#x} overlaps the previous mapping, which ends at {prev_end:#x}"
+                ));
+            }
+            let end = match addr.checked_add(entry.span) {
+                Some(end) if end <= self.ceiling => end,
+                _ => {
+                    return Err(format!(
+                        "mapping at {addr:#x} with span {:#x} extends past the ceiling {:#x}",
+                        entry.span, self.ceiling
+                    ))
+                }
+            };
+            // a source that has been dropped has no length to check against
+            let Some(source_len) = entry.source.upgrade().map(|source| source.len()) else {
+                prev_end = end;
+                continue;
+            };
+            if entry
+                .offset
+                .checked_add(entry.span)
+                .is_none_or(|source_end| source_end > page_align(source_len).unwrap_or(usize::MAX))
+            {
+                return Err(format!(
+                    "mapping at {addr:#x} covers source bytes {:#x}..{:#x}, but the source is only {source_len:#x} bytes",
+                    entry.offset,
+                    entry.offset.saturating_add(entry.span)
+                ));
+            }
+            prev_end = end;
+        }
+        Ok(())
+    }
+
+    /// Create a child `AddressSpace` with the same mappings as this one, like `fork(2)`.
+    ///
+    /// The child shares each mapping's `DataSource` with its parent, so every mapping in both
+    /// becomes copy-on-write: writable mappings are read-only until first written, at which
+    /// point the written page is copied privately into whichever space wrote it, unless no other
+    /// space still sees that page.
+    #[must_use]
+    pub fn fork(&mut self) -> Self {
+        // one count for each source, however many mappings it backs
+        let mut shared: Vec<_> = self
+            .mappings
+            .get_mut()
+            .values()
+            .filter_map(|entry| Some((entry.source.as_ptr(), entry.cow.clone()?)))
+            .collect();
+        for entry in self
+            .mappings
+            .get_mut()
+            .values_mut()
+            .filter(|entry| entry.cow.is_none())
+        {
+            let sharing = match shared
+                .iter()
+                .find(|(source, _)| core::ptr::addr_eq(*source, entry.source.as_ptr()))
+            {
+                Some((_, sharing)) => sharing.clone(),
+                None => {
+                    let sharing = self.cow_shares.share();
+                    shared.push((entry.source.as_ptr(), sharing.clone()));
+                    sharing
+                }
+            };
+            entry.cow = Some(sharing);
+        }
+        self.clone()
+    }
+
+    /// Resolve a write fault at `addr` in a copy-on-write mapping by giving the page containing
+    /// `addr` a private copy of its data, after which it is writable again. If every other
+    /// space that shared the page has already copied it (or gone), this one writes the
+    /// original in place instead.
+    ///
+    /// # Errors
+    /// `Fault` if `addr` is not mapped, `PermissionDenied` if its mapping is not copy-on-write
+    /// or does not permit writes at all, or `Source` if the shared data can't be read.
+    pub fn handle_cow_fault(&mut self, addr: VirtualAddress) -> Result<(), MappingError> {
+        let entry = self
+            .entry_containing(&self.mappings.read(), addr)
+            .cloned()
+            .ok_or(MappingError::Fault { addr })?;
+        let Some(sharing) = entry
+            .cow
+            .clone()
+            .filter(|_| entry.perms.contains(Permissions::WRITE))
+        else {
+            return Err(MappingError::PermissionDenied { addr });
+        };
+        let page_start = addr.align_down(PAGE_SIZE).max(entry.addr);
+        let page_end = (addr.align_down(PAGE_SIZE) + PAGE_SIZE).min(entry.addr + entry.span);
+        // the source's own pages that the faulting page touches, and those it covers whole
+        let source_start = entry.offset + (page_start - entry.addr);
+        let source_end = source_start + (page_end - page_start);
+        let touched = source_start / PAGE_SIZE..source_end.div_ceil(PAGE_SIZE);
+        let covered = source_start.div_ceil(PAGE_SIZE)..source_end / PAGE_SIZE;
+        if self.cow_shares.only_sharer(&sharing, touched) {
+            Self::split_entry_at(self.mappings.get_mut(), page_start);
+            Self::split_entry_at(self.mappings.get_mut(), page_end);
+            if let Some(page) = self.mappings.get_mut().get_mut(&page_start) {
+                page.cow = None;
+            }
+            return Ok(());
+        }
+        let mut data = vec![0; page_end - page_start];
+        entry.source.with(addr, |source| {
+            read_source(
+                source,
+                entry.offset + (page_start - entry.addr),
+                &mut data,
+                page_start,
+            )
+        })?;
+
+        Self::split_entry_at(self.mappings.get_mut(), page_start);
+        Self::split_entry_at(self.mappings.get_mut(), page_end);
+        if let Some(page) = self.mappings.get_mut().get_mut(&page_start) {
+            page.source = Backing::Strong(Arc::new(MemorySource::new(data)));
+            page.offset = 0;
+            page.cow = None;
+        }
+        // unless another of this space's mappings still shares them
+        let mappings = &self.mappings.get_mut();
+        self.cow_shares.leave(
+            &sharing,
+            covered.filter(|&page| {
+                !mappings.values().any(|entry| {
+                    entry
+                        .cow
+                        .as_ref()
+                        .is_some_and(|cow| Arc::ptr_eq(cow, &sharing))
+                        && entry.offset < (page + 1) * PAGE_SIZE
+                        && page * PAGE_SIZE < entry.offset + entry.span
+                })
+            }),
+        );
+        Ok(())
+    }
+
+    /// If `at` is strictly inside one of `mappings`, split that mapping into `[base, at)` and
+    /// `[at, base + span)`.
+    fn split_entry_at(mappings: &mut Mappings, at: VirtualAddress) {
+        let Some((_, entry)) = mappings
+            .range_mut(..at)
+            .next_back()
+            .filter(|(_, entry)| at < entry.addr + entry.span)
+        else {
+            return;
+        };
+        let front_span = at - entry.addr;
+        // a page that `at` falls in the middle of is part of both halves
+        let skipped = entry.page_index(at);
+        let back = MapEntry {
+            offset: entry.offset + front_span,
+            span: entry.span - front_span,
+            addr: at,
+            guard: 0,
+            // the counts so far stay with the front half, so they aren't counted twice if the
+            // halves are coalesced again
+            stats: AccessCounters::new(),
+            dirty: entry
+                .dirty
+                .iter()
+                .filter_map(|page| page.checked_sub(skipped))
+                .collect(),
+            ..entry.clone()
+        };
+        entry.span = front_span;
+        entry.dirty.truncate(entry.page_count());
+        mappings.insert(back.addr, back);
+    }
+
+    /// Remove everything in `mappings` within `[start, end)`, splitting those that straddle
+    /// either end.
+    fn unmap_range(mappings: &mut Mappings, start: VirtualAddress, end: VirtualAddress) {
+        Self::split_entry_at(mappings, start);
+        Self::split_entry_at(mappings, end);
+        let inside: Vec<VirtualAddress> =
+            mappings.range(start..end).map(|(&addr, _)| addr).collect();
+        for addr in inside {
+            mappings.remove(&addr);
+        }
+    }
+
+    /// Check that all of `[start, start + span)` is mapped, failing with `NotFound` naming the
+    /// first address that isn't.
+    fn check_mapped(&self, start: VirtualAddress, span: usize) -> Result<(), MappingError> {
+        self.for_each_piece(start, span, |_, _, _| Ok(()))
+            .map_err(|err| match err {
+                MappingError::Fault { addr } | MappingError::GuardPage { addr } => {
+                    MappingError::NotFound { addr }
+                }
+                err => err,
+            })
+    }
+
+    /// Split `[addr, addr + len)` into pieces that each lie within one mapping, and call `f` on
+    /// each in order with the mapping, the source offset of the piece, and the piece's range
+    /// relative to `addr`.
+    fn for_each_piece(
+        &self,
+        addr: VirtualAddress,
+        len: usize,
+        mut f: impl FnMut(&MapEntry, usize, Range<usize>) -> Result<(), MappingError>,
+    ) -> Result<(), MappingError> {
+        let mappings = self.mappings.read();
+        let mut done = 0;
+        while done < len {
+            let current = addr + done;
+            let entry = self
+                .entry_containing(&mappings, current)
+                .ok_or_else(|| Self::fault_at(&mappings, current))?;
+            let piece_len = (entry.addr + entry.span - current).min(len - done);
+            f(
+                entry,
+                entry.offset + (current - entry.addr),
+                done..done + piece_len,
+            )?;
+            done += piece_len;
+        }
+        Ok(())
+    }
+
+    /// The error for an access to `addr`, which is not in any of `mappings`: `GuardPage` if it
+    /// is one of a mapping's guard pages, and `Fault` otherwise.
+    fn fault_at(mappings: &Mappings, addr: VirtualAddress) -> MappingError {
+        let guarded = mappings
+            .range(addr..)
+            .next()
+            .is_some_and(|(_, entry)| entry.guard_start() <= addr);
+        if guarded {
+            MappingError::GuardPage { addr }
+        } else {
+            MappingError::Fault { addr }
+        }
+    }
+
+    /// The mapping of `mappings`, which are this space's, whose range `[addr, addr + span)`
+    /// contains `addr`, if any.
+    fn entry_containing<'a>(
+        &self,
+        mappings: &'a Mappings,
+        addr: VirtualAddress,
+    ) -> Option<&'a MapEntry> {
+        let contains = |entry: &&MapEntry| entry.addr <= addr && addr < entry.addr + entry.span;
+        let cached = self.lookup_cache.last.load(Ordering::Relaxed);
+        if let Some(entry) = mappings.get(&VirtualAddress::new(cached)).filter(contains) {
+            return Some(entry);
+        }
+        #[cfg(test)]
+        self.lookup_cache.misses.fetch_add(1, Ordering::Relaxed);
+        let entry = mappings
+            .range(..=addr)
+            .next_back()
+            .map(|(_, entry)| entry)
+            .filter(contains)?;
+        self.lookup_cache
+            .last
+            .store(entry.addr.as_usize(), Ordering::Relaxed);
+        Some(entry)
+    }
+
+    /// Look up the DataSource and offset within that DataSource for a
+    /// VirtualAddress / AccessType in this AddressSpace
+    ///
+    /// # Errors
+    /// If this VirtualAddress does not have a valid mapping in &self,
+    /// or if this AccessType is not permitted by the mapping
+    pub fn get_source_for_addr<D: DataSource>(
+        &self,
+        addr: VirtualAddress,
+        access_type: FlagBuilder,
+    ) -> Result<(&D, usize), &str> {
+        todo!();
+    }
+}
+
+impl IntoIterator for AddressSpace {
+    type Item = MappingInfo;
+    type IntoIter = IntoMappings;
+
+    /// Consume this `AddressSpace`, yielding its mappings in ascending address order.
+    fn into_iter(self) -> IntoMappings {
+        IntoMappings(self.mappings.into_inner().into_values())
+    }
+}
+
+/// The mappings of a consumed `AddressSpace`, in ascending address order.
+pub struct IntoMappings(alloc::collections::btree_map::IntoValues<VirtualAddress, MapEntry>);
+
+impl Iterator for IntoMappings {
+    type Item = MappingInfo;
+
+    fn next(&mut self) -> Option<MappingInfo> {
+        self.0.next().map(|entry| MappingInfo::from(&entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl fmt::Display for AddressSpace {
+    /// A line with the name of this `AddressSpace`, then one line per mapping in address order,
+    /// in the style of `/proc/<pid>/maps`. Huge-page mappings are marked `huge`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}:", self.name)?;
+        for entry in self.mappings.read().values() {
+            write!(
+                f,
+                "0x{:012x}-0x{:012x} {} offset=0x{:x}",
+                entry.addr,
+                entry.addr + entry.span,
+                entry.perms,
+                entry.offset
+            )?;
+            writeln!(f, "{}", if entry.huge { " huge" } else { "" })?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for AddressSpace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<Range<VirtualAddress>> = self
+            .mappings
+            .read()
+            .values()
+            .map(|entry| entry.addr..entry.addr + entry.span)
+            .collect();
+        f.debug_struct("AddressSpace")
+            .field("name", &self.name)
+            .field("ceiling", &self.ceiling)
+            .field("mappings", &ranges)
+            .finish()
+    }
+}
+
+/// Build flags for address space maps.
+///
+/// We recommend using this builder type as follows:
+/// ```
+/// # use reedos_address_space::FlagBuilder;
+/// let flags = FlagBuilder::new()
+///     .toggle_read()
+///     .toggle_write();
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[allow(clippy::struct_excessive_bools)] // clippy is wrong: bools are more readable than enums
+                                         // here because these directly correspond to yes/no
+                                         // hardware flags
+pub struct FlagBuilder {
+    // TODO: should there be some sanity checks that conflicting flags are never toggled? can we do
+    // this at compile-time? (the second question is maybe hard)
+    read: bool,
+    write: bool,
+    execute: bool,
+    cow: bool,
+    private: bool,
+    shared: bool,
+}
+
+/// Create a constructor and toggler for a `FlagBuilder` object. Will capture attributes, including documentation
+/// comments and apply them to the generated constructor.
+macro_rules! flag {
+    (
+        $flag:ident,
+        $toggle:ident
+    ) => {
+        #[doc=concat!("Turn on only the ", stringify!($flag), " flag.")]
+        #[must_use]
+        pub fn $flag() -> Self {
+            Self {
+                $flag: true,
+                ..Self::default()
+            }
+        }
+
+        #[doc=concat!("Toggle the ", stringify!($flag), " flag.")]
+        #[must_use]
+        pub const fn $toggle(self) -> Self {
+            Self {
+                $flag: !self.$flag,
+                ..self
+            }
+        }
+    };
+}
+
+impl FlagBuilder {
+    /// Create a new `FlagBuilder` with all flags toggled off.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    flag!(read, toggle_read);
+    flag!(write, toggle_write);
+    flag!(execute, toggle_execute);
+    flag!(cow, toggle_cow);
+    flag!(private, toggle_private);
+    flag!(shared, toggle_shared);
+
+    #[must_use]
+    /// Combine two `FlagBuilder`s by boolean or-ing each of their flags.
+    ///
+    /// This is, somewhat counter-intuitively, named `and`, so that the following code reads
+    /// correctly:
+    ///
+    /// ```
+    /// # use reedos_address_space::FlagBuilder;
+    /// let read = FlagBuilder::read();
+    /// let execute = FlagBuilder::execute();
+    /// let new = read.and(execute);
+    /// assert_eq!(new, FlagBuilder::new().toggle_read().toggle_execute());
+    /// ```
+    pub const fn and(self, other: Self) -> Self {
+        let read = self.read || other.read;
+        let write = self.write || other.write;
+        let execute = self.execute || other.execute;
+        let cow = self.cow || other.cow;
+        let private = self.private || other.private;
+        let shared = self.shared || other.shared;
+
+        Self {
+            read,
+            write,
+            execute,
+            cow,
+            private,
+            shared,
+        }
+    }
+
+    #[must_use]
+    /// Turn off all flags in self that are on in other.
+    ///
+    /// You can think of this as `self &! other` on each field.
+    ///
+    /// ```
+    /// # use reedos_address_space::FlagBuilder;
+    /// let read_execute = FlagBuilder::read().toggle_execute();
+    /// let execute = FlagBuilder::execute();
+    /// let new = read_execute.but_not(execute);
+    /// assert_eq!(new, FlagBuilder::new().toggle_read());
+    /// ```
+    pub const fn but_not(self, other: Self) -> Self {
+        let read = self.read && !other.read;
+        let write = self.write && !other.write;
+        let execute = self.execute && !other.execute;
+        let cow = self.cow && !other.cow;
+        let private = self.private && !other.private;
+        let shared = self.shared && !other.shared;
+
+        Self {
+            read,
+            write,
+            execute,
+            cow,
+            private,
+            shared,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "std")]
+    use crate::data_source::FileDataSource;
+    use crate::error::DataSourceError;
+
+    const P: usize = PAGE_SIZE;
+
+    const fn va(addr: usize) -> VirtualAddress {
+        VirtualAddress::new(addr)
+    }
+
+    #[cfg(feature = "std")]
+    fn source() -> Arc<dyn DataSource> {
+        Arc::new(FileDataSource::new("Cargo.toml").unwrap())
+    }
+
+    /// The same read-only bytes as `source`, for when there are no files to open.
+    #[cfg(not(feature = "std"))]
+    fn source() -> Arc<dyn DataSource> {
+        let bytes = include_bytes!("../Cargo.toml").to_vec();
+        Arc::new(crate::sources::ReadOnly::new(MemorySource::new(bytes)))
+    }
+
+    /// A read-only source of unbounded length whose byte at each offset is the offset's low byte.
+    struct Pattern;
+
+    impl DataSource for Pattern {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = (offset + i) as u8;
+            }
+            Ok(buf.len())
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+            Err(DataSourceError::ReadOnly)
+        }
+        fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+            Ok(())
+        }
+        fn len(&self) -> usize {
+            usize::MAX
+        }
+    }
+
+    /// A source long enough for any mapping, for tests that only care about layout.
+    fn unbounded() -> Arc<dyn DataSource> {
+        Arc::new(Pattern)
+    }
+
+    fn pattern(offset: usize, len: usize) -> Vec<u8> {
+        (offset..offset + len).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn max_address_is_39_bits() {
+        assert_eq!(AddressSpace::MAX_ADDRESS, 0x0080_0000_0000);
+        assert_eq!(
+            AddressSpace::new("space").ceiling,
+            va(AddressSpace::MAX_ADDRESS)
+        );
+    }
+
+    // with the old `2 ^ 39 - 1` ceiling (which is 32), none of these would fit
+    #[test]
+    fn span_near_half_the_space_fits() {
+        let mut addr_space = AddressSpace::new("big mapping");
+        let addr = addr_space
+            .add_mapping(unbounded(), 0, 1 << 38, Permissions::READ)
+            .unwrap();
+        assert_eq!(addr, va(0));
+
+        let addr = addr_space
+            .add_mapping(unbounded(), 0, (1 << 38) - 1, Permissions::READ)
+            .unwrap();
+        assert_eq!(addr, va(1 << 38));
+    }
+
+    #[test]
+    fn spans_fit_gaps_exactly_or_not_at_all() {
+        let mut addr_space = AddressSpace::new("exact fit");
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(4 * P), R)
+            .unwrap();
+        let tail = AddressSpace::MAX_ADDRESS - 5 * P;
+        assert_eq!(addr_space.largest_free_gap(), tail);
+        assert_eq!(
+            addr_space.add_mapping(unbounded(), 0, tail + 1, R),
+            Err(MappingError::NoSpace)
+        );
+        assert_eq!(addr_space.mapping_count(), 1);
+        assert_eq!(
+            addr_space.add_mapping(unbounded(), 0, tail, R),
+            Ok(va(5 * P))
+        );
+
+        assert_eq!(addr_space.largest_free_gap(), 4 * P);
+        assert_eq!(
+            addr_space.add_mapping(unbounded(), 0, 4 * P + 1, R),
+            Err(MappingError::NoSpace)
+        );
+        assert_eq!(addr_space.add_mapping(unbounded(), 0, 4 * P, R), Ok(va(0)));
+        assert_eq!(
+            addr_space.add_mapping(unbounded(), 0, 1, R),
+            Err(MappingError::NoSpace)
+        );
+    }
+
+    #[test]
+    fn first_mapping_lands_at_zero() {
+        let mut addr_space = AddressSpace::new("empty");
+        assert!(addr_space.mappings.read().is_empty());
+        assert_eq!(
+            addr_space
+                .add_mapping(source(), 0, 1, Permissions::READ)
+                .unwrap(),
+            va(0)
+        );
+        assert_eq!(addr_space.mappings.read().len(), 1);
+    }
+
+    #[test]
+    fn only_gap_is_below_first_mapping() {
+        let mut addr_space = AddressSpace::new("head gap").with_ceiling(0x2000);
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(0x1000), Permissions::READ)
+            .unwrap();
+        assert_eq!(
+            addr_space
+                .add_mapping(source(), 0, 100, Permissions::READ)
+                .unwrap(),
+            va(0)
+        );
+        assert_eq!(
+            addr_space.add_mapping(source(), 0, 1, Permissions::READ),
+            Err(MappingError::NoSpace)
+        );
+    }
+
+    #[test]
+    fn small_head_gap_is_skipped() {
+        let mut addr_space = AddressSpace::new("small head gap");
+        addr_space
+            .add_mapping_at(unbounded(), 0, 4 * P, va(P), Permissions::READ)
+            .unwrap();
+        assert_eq!(
+            addr_space
+                .add_mapping(unbounded(), 0, 2 * P, Permissions::READ)
+                .unwrap(),
+            va(5 * P)
+        );
+        assert_eq!(
+            addr_space
+                .add_mapping(unbounded(), 0, P, Permissions::READ)
+                .unwrap(),
+            va(0)
+        );
+        assert_eq!(
+            addr_space
+                .add_mapping(unbounded(), 0, P, Permissions::READ)
+                .unwrap(),
+            va(7 * P)
+        );
+    }
+
+    #[test]
+    fn spans_are_rounded_to_pages() {
+        let mut addr_space = AddressSpace::new("rounded");
+        assert_eq!(
+            addr_space
+                .add_mapping(source(), 0, 100, Permissions::READ)
+                .unwrap(),
+            va(0)
+        );
+        assert_eq!(addr_space.mappings.read().values().next().unwrap().span, P);
+        let addr = addr_space
+            .add_mapping(source(), 0, 100, Permissions::READ)
+            .unwrap();
+        assert_eq!(addr, va(P));
+        assert!(addr.is_aligned(PAGE_SIZE));
+    }
+
+    #[test]
+    fn protect_works_in_whole_pages() {
+        let mut addr_space = AddressSpace::new("protect pages");
+        let pat: Arc<dyn DataSource> = Arc::new(Pattern);
+        addr_space
+            .add_mapping_at(pat, 0, 2 * P, va(0), Permissions::READ)
+            .unwrap();
+        // like mprotect, the span is rounded up, so no mapping ends mid-page
+        addr_space.protect(va(0), 100, Permissions::NONE).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), P, 0, Permissions::NONE), (va(P), P, P, R)]
+        );
+        assert_eq!(
+            addr_space.protect(va(P + 100), P, RW),
+            Err(MappingError::Unaligned { addr: va(P + 100) })
+        );
+        assert_eq!(addr_space.mapping_count(), 2);
+    }
+
+    #[test]
+    fn unaligned_add_mapping_at_fails() {
+        let mut addr_space = AddressSpace::new("unaligned");
+        assert_eq!(
+            addr_space.add_mapping_at(source(), 0, 100, va(0x10), Permissions::READ),
+            Err(MappingError::Unaligned { addr: va(0x10) })
+        );
+        assert!(addr_space.mappings.read().is_empty());
+    }
+
+    #[test]
+    fn add_mapping_at_near_half_the_space() {
+        let mut addr_space = AddressSpace::new("big mapping at");
+        addr_space
+            .add_mapping_at(unbounded(), 0, 1 << 38, va(1 << 38), Permissions::READ)
+            .unwrap();
+        assert_eq!(
+            addr_space.mappings.read().values().next().unwrap().span,
+            1 << 38
+        );
+    }
+
+    /// Map three one-byte sources back to back, returning them alongside their addresses.
+    fn three_mappings(addr_space: &mut AddressSpace) -> Vec<(Arc<dyn DataSource>, VirtualAddress)> {
+        (0..3)
+            .map(|_| {
+                let src = source();
+                let addr = addr_space
+                    .add_mapping(src.clone(), 0, 1, Permissions::READ)
+                    .unwrap();
+                (src, addr)
+            })
+            .collect()
+    }
+
+    fn assert_sorted(addr_space: &AddressSpace) {
+        addr_space.validate().unwrap();
+        let addrs = addrs(addr_space);
+        assert!(
+            addrs.windows(2).all(|pair| pair[0] < pair[1]),
+            "mappings out of order: {addrs:x?}"
+        );
+    }
+
+    fn addrs(addr_space: &AddressSpace) -> Vec<VirtualAddress> {
+        addr_space
+            .mappings
+            .read()
+            .values()
+            .map(|entry| entry.addr)
+            .collect()
+    }
+
+    #[test]
+    fn remove_first_mapping() {
+        let mut addr_space = AddressSpace::new("remove first");
+        let maps = three_mappings(&mut addr_space);
+        addr_space.remove_mapping(&*maps[0].0, maps[0].1).unwrap();
+        assert_eq!(addrs(&addr_space), vec![maps[1].1, maps[2].1]);
+    }
+
+    #[test]
+    fn remove_middle_mapping() {
+        let mut addr_space = AddressSpace::new("remove middle");
+        let maps = three_mappings(&mut addr_space);
+        addr_space.remove_mapping(&*maps[1].0, maps[1].1).unwrap();
+        assert_eq!(addrs(&addr_space), vec![maps[0].1, maps[2].1]);
+    }
+
+    #[test]
+    fn remove_last_mapping() {
+        let mut addr_space = AddressSpace::new("remove last");
+        let maps = three_mappings(&mut addr_space);
+        addr_space.remove_mapping(&*maps[2].0, maps[2].1).unwrap();
+        assert_eq!(addrs(&addr_space), vec![maps[0].1, maps[1].1]);
+    }
+
+    #[test]
+    fn remove_missing_mapping() {
+        let mut addr_space = AddressSpace::new("remove missing");
+        let maps = three_mappings(&mut addr_space);
+        assert_eq!(
+            addr_space.remove_mapping(&*maps[0].0, va(1000)),
+            Err(MappingError::NotFound { addr: va(1000) })
+        );
+        assert_eq!(addr_space.mappings.read().len(), 3);
+    }
+
+    #[test]
+    fn remove_mapping_with_wrong_source() {
+        let mut addr_space = AddressSpace::new("remove wrong source");
+        let maps = three_mappings(&mut addr_space);
+        assert_eq!(
+            addr_space.remove_mapping(&*maps[0].0, maps[1].1),
+            Err(MappingError::SourceMismatch { addr: maps[1].1 })
+        );
+        assert_eq!(addrs(&addr_space), vec![maps[0].1, maps[1].1, maps[2].1]);
+    }
+
+    #[test]
+    fn remove_mapping_checks_identity_not_contents() {
+        let mut addr_space = AddressSpace::new("remove lookalike");
+        let mine = memory(P);
+        let lookalike = memory(P);
+        let addr = addr_space.add_mapping(mine.clone(), 0, P, R).unwrap();
+        assert_eq!(
+            addr_space.remove_mapping(&*lookalike, addr),
+            Err(MappingError::SourceMismatch { addr })
+        );
+        let mut buf = [0; 4];
+        addr_space.read(addr, &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(0, 4));
+
+        addr_space.remove_mapping(&*mine, addr).unwrap();
+        assert!(addr_space.translate(addr).is_none());
+        assert_eq!(addr_space.add_mapping_at(lookalike, 0, P, addr, R), Ok(()));
+    }
+
+    #[test]
+    fn for_each_mapping_mut_makes_everything_read_only() {
+        let mut addr_space = AddressSpace::new("read only");
+        for span in [P, 3 * P, 2 * P] {
+            addr_space.add_mapping(memory(span), 0, span, RW).unwrap();
+        }
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0x40 * P), RW)
+            .unwrap();
+        addr_space.write(va(0x40 * P), b"before").unwrap();
+        let mut seen = Vec::new();
+        addr_space
+            .for_each_mapping_mut(|view| {
+                seen.push((view.addr(), view.span()));
+                view.perms = R;
+            })
+            .unwrap();
+        assert_eq!(seen.len(), 4);
+        assert!(seen.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        for (addr, span) in seen {
+            assert_eq!(
+                addr_space.write(addr + span - 1, b"x"),
+                Err(MappingError::PermissionDenied {
+                    addr: addr + span - 1
+                })
+            );
+        }
+        assert_eq!(addr_space.validate(), Ok(()));
+    }
+
+    #[test]
+    fn for_each_mapping_mut_is_all_or_nothing() {
+        let mut addr_space = AddressSpace::new("rebase").with_wx_policy(true);
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.for_each_mapping_mut(|view| view.offset += P),
+            Err(MappingError::OutOfRange { addr: va(P) })
+        );
+        assert_eq!(
+            addr_space.for_each_mapping_mut(|view| view.perms = view.perms | Permissions::EXECUTE),
+            Err(MappingError::WxViolation)
+        );
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), P, 0, RW), (va(P), 2 * P, 0, R)]
+        );
+
+        addr_space
+            .for_each_mapping_mut(|view| {
+                if view.span() == P {
+                    view.offset = 3 * P;
+                }
+            })
+            .unwrap();
+        let mut buf = [0; 4];
+        addr_space.read(va(0), &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(3 * P, 4));
+    }
+
+    #[test]
+    fn for_each_mapping_mut_keeps_aliases_apart() {
+        let mut addr_space = AddressSpace::new("no aliases").with_allow_aliasing(false);
+        let shm = memory(4 * P);
+        addr_space
+            .add_mapping_at(shm.clone(), 0, P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(shm.clone(), 2 * P, P, va(4 * P), RW)
+            .unwrap();
+        assert_eq!(
+            addr_space.for_each_mapping_mut(|view| view.offset = P),
+            Err(MappingError::Aliased { addr: va(4 * P) })
+        );
+        assert_eq!(
+            addr_space.for_each_mapping_mut(|view| {
+                if view.addr() == va(0) {
+                    view.offset = 2 * P;
+                }
+            }),
+            Err(MappingError::Aliased { addr: va(4 * P) })
+        );
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), P, 0, RW), (va(4 * P), P, 2 * P, RW)]
+        );
+
+        // swapping the two offsets leaves them as far apart as before
+        addr_space
+            .for_each_mapping_mut(|view| view.offset = 2 * P - view.offset)
+            .unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), P, 2 * P, RW), (va(4 * P), P, 0, RW)]
+        );
+    }
+
+    #[test]
+    fn aliases_share_their_bytes() {
+        let mut addr_space = AddressSpace::new("shared memory");
+        let shm = memory(4 * P);
+        let first = addr_space.add_mapping(shm.clone(), 0, 2 * P, RW).unwrap();
+        let second = addr_space.add_mapping(shm.clone(), P, 2 * P, RW).unwrap();
+        let apart = addr_space.add_mapping(shm.clone(), 3 * P, P, RW).unwrap();
+        assert_eq!(addr_space.aliases_of(first), vec![second]);
+        assert_eq!(addr_space.aliases_of(second), vec![first]);
+        assert_eq!(addr_space.aliases_of(apart), vec![]);
+        assert_eq!(addr_space.aliases_of(va(0x100 * P)), vec![]);
+
+        // the second page of `first` is the first page of `second`
+        addr_space.write(first + P + 8, b"hello").unwrap();
+        assert_eq!(addr_space.read_vec(second + 8, 5).unwrap(), b"hello");
+        addr_space.write(second, b"back").unwrap();
+        assert_eq!(addr_space.read_vec(first + P, 4).unwrap(), b"back");
+    }
+
+    #[test]
+    fn aliasing_can_be_refused() {
+        let mut addr_space = AddressSpace::new("no aliases").with_allow_aliasing(false);
+        let shm = memory(4 * P);
+        let first = addr_space.add_mapping(shm.clone(), 0, 2 * P, RW).unwrap();
+        assert_eq!(
+            addr_space.add_mapping(shm.clone(), P, 2 * P, RW),
+            Err(MappingError::Aliased { addr: first })
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(shm.clone(), P + 1, 1, va(0x40 * P), RW),
+            Err(MappingError::Aliased { addr: first })
+        );
+        assert_eq!(addr_space.mapping_count(), 1);
+        // other bytes of the same source are fine, as is an equal source
+        addr_space
+            .add_mapping(shm.clone(), 2 * P, 2 * P, RW)
+            .unwrap();
+        addr_space.add_mapping(memory(4 * P), 0, 4 * P, RW).unwrap();
+
+        // a mapping that replaces the one it would alias doesn't alias it
+        addr_space
+            .map_fixed(shm.clone(), P, P, first + P, RW)
+            .unwrap();
+        assert_eq!(
+            addr_space.map_fixed(shm.clone(), P, P, first, RW),
+            Err(MappingError::Aliased { addr: first + P })
+        );
+        assert_eq!(addr_space.validate(), Ok(()));
+    }
+
+    #[test]
+    fn mappings_stay_sorted() {
+        let mut addr_space = AddressSpace::new("sorted");
+        let high = source();
+        addr_space
+            .add_mapping_at(high.clone(), 0, 0x100, va(0x8000), Permissions::READ)
+            .unwrap();
+        assert_sorted(&addr_space);
+        let low = source();
+        let low_addr = addr_space
+            .add_mapping(low.clone(), 0, 0x100, Permissions::READ)
+            .unwrap();
+        assert_sorted(&addr_space);
+        addr_space
+            .add_mapping_at(source(), 0, 0x100, va(0x4000), Permissions::READ)
+            .unwrap();
+        assert_sorted(&addr_space);
+        addr_space
+            .add_mapping(source(), 0, 0x100, Permissions::READ)
+            .unwrap();
+        assert_sorted(&addr_space);
+        addr_space.remove_mapping(&*low, low_addr).unwrap();
+        assert_sorted(&addr_space);
+        addr_space
+            .add_mapping(source(), 0, 0x80, Permissions::READ)
+            .unwrap();
+        assert_sorted(&addr_space);
+        addr_space.remove_mapping(&*high, va(0x8000)).unwrap();
+        assert_sorted(&addr_space);
+        addr_space
+            .add_mapping(source(), 0, 100, Permissions::READ)
+            .unwrap();
+        assert_sorted(&addr_space);
+        assert_eq!(addrs(&addr_space), vec![va(0), va(P), va(2 * P), va(4 * P)]);
+    }
+
+    #[test]
+    fn add_mapping_at_past_ceiling() {
+        let mut addr_space = AddressSpace::new("past ceiling").with_ceiling(4 * P);
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, 0x10, va(4 * P), Permissions::READ),
+            Err(MappingError::OutOfRange { addr: va(4 * P) })
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, P + 1, va(3 * P), Permissions::READ),
+            Err(MappingError::OutOfRange { addr: va(3 * P) })
+        );
+    }
+
+    #[test]
+    fn anonymous_mappings_fill_on_demand() {
+        let mut addr_space = AddressSpace::new("anonymous");
+        let gib = 1 << 30;
+        let addr = addr_space.add_mapping_anonymous(gib, RW).unwrap();
+        let mut buf = [0xff; 8];
+        addr_space.read(addr + gib - 8, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+        addr_space.write(addr + gib / 2 - 3, b"written").unwrap();
+        addr_space.read(addr + gib / 2 - 4, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0written");
+
+        // far more than could be allocated up front
+        let vast = addr_space.add_mapping_anonymous(256 * gib, R).unwrap();
+        addr_space.read(vast + 200 * gib, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+
+        // the tail of a partial page is zero-filled too
+        let small = addr_space.add_mapping_anonymous(100, RW).unwrap();
+        addr_space.read(small + P - 8, &mut buf).unwrap();
+        assert_eq!(buf, [0; 8]);
+        assert_eq!(
+            addr_space.add_mapping_anonymous(0, RW),
+            Err(MappingError::InvalidSpan)
+        );
+    }
+
+    #[test]
+    fn add_mappings_adds_every_spec() {
+        let mut addr_space = AddressSpace::new("batch");
+        let specs = [(unbounded(), 0, P), (unbounded(), P, 2 * P)];
+        assert_eq!(
+            addr_space.add_mappings(specs, R).unwrap(),
+            vec![va(0), va(P)]
+        );
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), P, 0, R), (va(P), 2 * P, P, R)]
+        );
+    }
+
+    #[test]
+    fn add_mappings_is_all_or_nothing() {
+        let mut addr_space = AddressSpace::new("batch").with_ceiling(4 * P);
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(3 * P), R)
+            .unwrap();
+        let specs = [
+            (unbounded(), 0, P),
+            (unbounded(), 0, P),
+            (unbounded(), 0, 2 * P),
+        ];
+        assert_eq!(
+            addr_space.add_mappings(specs, R),
+            Err(MappingError::NoSpace)
+        );
+        assert_eq!(layout(&addr_space), vec![(va(3 * P), P, 0, R)]);
+    }
+
+    #[test]
+    fn failed_add_mappings_does_not_move_placement() {
+        let next_fit = || {
+            AddressSpace::new("batch")
+                .with_ceiling(64 * P)
+                .with_policy(AllocPolicy::NextFit)
+        };
+        let randomized = || AddressSpace::new("batch").with_ceiling(64 * P).with_aslr(7);
+        for fresh in [&next_fit as &dyn Fn() -> AddressSpace, &randomized] {
+            let mut addr_space = fresh();
+            let specs = [(unbounded(), 0, P), (unbounded(), 0, 128 * P)];
+            assert_eq!(
+                addr_space.add_mappings(specs, R),
+                Err(MappingError::NoSpace)
+            );
+            assert_eq!(
+                addr_space.add_mapping(unbounded(), 0, P, R),
+                fresh().add_mapping(unbounded(), 0, P, R)
+            );
+        }
+    }
+
+    #[test]
+    fn overflowing_spans_are_rejected() {
+        let mut addr_space = AddressSpace::new("overflow").with_ceiling(usize::MAX);
+        let top = va(usize::MAX).align_down(P);
+        // `top + 2 * P` would wrap around to `P`
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, 2 * P, top, R),
+            Err(MappingError::OutOfRange { addr: top })
+        );
+        // and rounding this span up to a whole page would wrap around to 0
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, usize::MAX, va(P), R),
+            Err(MappingError::OutOfRange { addr: va(P) })
+        );
+        assert_eq!(
+            addr_space.add_mapping(unbounded(), 0, usize::MAX, R),
+            Err(MappingError::NoSpace)
+        );
+        assert_eq!(
+            addr_space.add_mapping_guarded(memory(P), 0, P, usize::MAX / P, R),
+            Err(MappingError::NoSpace)
+        );
+        assert_eq!(
+            addr_space.reserve_at(top, 2 * P),
+            Err(MappingError::OutOfRange { addr: top })
+        );
+        assert_eq!(
+            addr_space.unmap(top, 2 * P),
+            Err(MappingError::OutOfRange { addr: top })
+        );
+        assert!(addr_space.is_empty());
+        assert_eq!(addr_space.find_free(usize::MAX), None);
+
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.grow_mapping(va(P), usize::MAX),
+            Err(MappingError::NoSpace)
+        );
+        addr_space.shrink_mapping(va(P), usize::MAX).unwrap();
+        assert_eq!(layout(&addr_space), vec![(va(P), P, 0, R)]);
+    }
+
+    #[test]
+    fn zero_spans_are_rejected() {
+        let mut addr_space = AddressSpace::new("zero span");
+        assert_eq!(
+            addr_space.add_mapping(memory(P), 0, 0, R),
+            Err(MappingError::InvalidSpan)
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(memory(P), 0, 0, va(P), R),
+            Err(MappingError::InvalidSpan)
+        );
+        assert_eq!(
+            addr_space.add_mapping_guarded(memory(P), 0, 0, 1, R),
+            Err(MappingError::InvalidSpan)
+        );
+        assert_eq!(addr_space.reserve(0), Err(MappingError::InvalidSpan));
+        assert_eq!(
+            addr_space.reserve_at(va(P), 0),
+            Err(MappingError::InvalidSpan)
+        );
+        assert!(addr_space.is_empty());
+        assert_eq!(addr_space.largest_free_gap(), AddressSpace::MAX_ADDRESS);
+    }
+
+    #[test]
+    fn spans_past_the_end_of_the_source_are_rejected() {
+        let mut addr_space = AddressSpace::new("past source");
+        assert_eq!(
+            addr_space.add_mapping(memory(2 * P), P, 2 * P, R),
+            Err(MappingError::SourceTooSmall {
+                source_len: 2 * P,
+                needed: 3 * P
+            })
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(memory(2 * P), 3 * P, P, va(0), R),
+            Err(MappingError::SourceTooSmall {
+                source_len: 2 * P,
+                needed: 4 * P
+            })
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(memory(P), usize::MAX, 1, va(0), R),
+            Err(MappingError::SourceTooSmall {
+                source_len: P,
+                needed: usize::MAX
+            })
+        );
+        assert!(addr_space.is_empty());
+
+        addr_space.add_mapping(memory(2 * P), P, P, R).unwrap();
+    }
+
+    #[test]
+    fn spans_are_checked_before_rounding_to_pages() {
+        let mut addr_space = AddressSpace::new("short source");
+        let short = memory(100);
+        assert_eq!(
+            addr_space.add_mapping(short.clone(), 0, P, R),
+            Err(MappingError::SourceTooSmall {
+                source_len: 100,
+                needed: P
+            })
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(memory(P + 1), P, 2, va(0), R),
+            Err(MappingError::SourceTooSmall {
+                source_len: P + 1,
+                needed: P + 2
+            })
+        );
+        assert!(addr_space.is_empty());
+
+        // right up to the end is fine, and the mapping still takes a whole page
+        let addr = addr_space.add_mapping(short, 0, 100, R).unwrap();
+        assert_eq!(addr_space.mapping_at(addr).unwrap().span, P);
+        addr_space
+            .add_mapping_at(memory(P + 1), P, 1, va(4 * P), R)
+            .unwrap();
+    }
+
+    #[test]
+    fn spans_are_checked_against_the_source_length() {
+        let mut addr_space = AddressSpace::new("bounds");
+        let source: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![0; 4 * P]));
+        assert!(addr_space.add_mapping(source.clone(), P, 2 * P, R).is_ok());
+        assert!(addr_space.add_mapping(source.clone(), P, 3 * P, R).is_ok());
+        assert_eq!(
+            addr_space.add_mapping(source.clone(), P, 3 * P + 1, R),
+            Err(MappingError::SourceTooSmall {
+                source_len: 4 * P,
+                needed: 4 * P + 1
+            })
+        );
+        assert_eq!(
+            addr_space.map_fixed(source, 4 * P, 1, va(0), R),
+            Err(MappingError::SourceTooSmall {
+                source_len: 4 * P,
+                needed: 4 * P + 1
+            })
+        );
+        assert_eq!(addr_space.mapping_count(), 2);
+    }
+
+    #[test]
+    fn weak_mappings_report_their_source_gone() {
+        let mut addr_space = AddressSpace::new("weak");
+        let source = memory(2 * P);
+        let addr = addr_space.add_mapping_weak(&source, 0, 2 * P, RW).unwrap();
+        assert_eq!(addr_space.read_vec(addr + P, 4).unwrap(), pattern(P, 4));
+        addr_space.write(addr, b"cached").unwrap();
+
+        // another mapping of the source keeps it alive
+        let strong = addr_space.add_mapping(source.clone(), 0, P, R).unwrap();
+        drop(source);
+        assert_eq!(addr_space.read_vec(addr, 6).unwrap(), b"cached");
+
+        addr_space.unmap(strong, P).unwrap();
+        assert_eq!(
+            addr_space.read_vec(addr + 8, 4),
+            Err(MappingError::SourceGone { addr: addr + 8 })
+        );
+        assert_eq!(
+            addr_space.write(addr + P, b"gone"),
+            Err(MappingError::SourceGone { addr: addr + P })
+        );
+        assert!(addr_space.contains(addr));
+        addr_space.unmap(addr, 2 * P).unwrap();
+        assert!(addr_space.is_empty());
+    }
+
+    #[test]
+    fn weak_mappings_are_found_by_their_source() {
+        let mut addr_space = AddressSpace::new("weak lookups").with_allow_aliasing(false);
+        let source = memory(4 * P);
+        let first = addr_space.add_mapping_weak(&source, 0, P, R).unwrap();
+        let second = addr_space.add_mapping_weak(&source, P, P, R).unwrap();
+        addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+
+        let found = addr_space.mappings_for_source(&source);
+        assert_eq!(found.len(), 2);
+        assert!(found
+            .iter()
+            .all(|mapping| Arc::ptr_eq(mapping.source.as_ref().unwrap(), &source)));
+        let (translated, offset) = addr_space.translate(second + 8).unwrap();
+        assert!(Arc::ptr_eq(&translated, &source));
+        assert_eq!(offset, P + 8);
+        assert_eq!(
+            addr_space.add_mapping(source.clone(), 0, 2 * P, R),
+            Err(MappingError::Aliased { addr: first })
+        );
+        assert_eq!(
+            addr_space.remove_mapping(&*memory(P), first),
+            Err(MappingError::SourceMismatch { addr: first })
+        );
+        addr_space.remove_mapping(&*source, first).unwrap();
+        assert_eq!(addrs(&addr_space), vec![second, va(2 * P)]);
+
+        // once the source is gone there is nothing to hand out
+        drop(found);
+        drop(translated);
+        drop(source);
+        assert!(addr_space.translate(second).is_none());
+        assert!(addr_space.mapping_at(second).unwrap().source.is_none());
+        assert!(addr_space.contains(second));
+    }
+
+    #[test]
+    fn remap_source_swaps_what_a_mapping_shows() {
+        let mut addr_space = AddressSpace::new("reload");
+        let old = memory(2 * P);
+        let addr = addr_space.add_mapping(old.clone(), 0, 2 * P, R).unwrap();
+        assert_eq!(addr_space.read_vec(addr + 8, 4).unwrap(), pattern(8, 4));
+
+        // each page full of its number
+        let pages = [vec![1; P], vec![2; P], vec![3; P]].concat();
+        let new = Arc::new(MemorySource::new(pages));
+        addr_space.remap_source(addr, new.clone(), P).unwrap();
+        assert_eq!(addr_space.read_vec(addr + 8, 4).unwrap(), [2; 4]);
+        assert_eq!(addr_space.read_vec(addr + P, P).unwrap(), [3; P]);
+        assert_eq!(layout(&addr_space), vec![(addr, 2 * P, P, R)]);
+
+        assert_eq!(
+            addr_space.remap_source(addr, old.clone(), P),
+            Err(MappingError::SourceTooSmall {
+                source_len: 2 * P,
+                needed: 2 * P + 1
+            })
+        );
+        assert_eq!(
+            addr_space.remap_source(addr + P, old, 0),
+            Err(MappingError::NotFound { addr: addr + P })
+        );
+        let (source, _) = addr_space.translate(addr).unwrap();
+        assert!(core::ptr::addr_eq(Arc::as_ptr(&source), Arc::as_ptr(&new)));
+    }
+
+    #[test]
+    fn translate_in_and_out_of_range() {
+        let mut addr_space = AddressSpace::new("translate");
+        let src = unbounded();
+        addr_space
+            .add_mapping_at(src.clone(), 0x20, P, va(P), Permissions::READ)
+            .unwrap();
+
+        let (found, offset) = addr_space.translate(va(P)).unwrap();
+        assert!(Arc::ptr_eq(&found, &src));
+        assert_eq!(offset, 0x20);
+        assert_eq!(addr_space.translate(va(P + 0x80)).unwrap().1, 0xa0);
+        assert_eq!(addr_space.translate(va(2 * P - 1)).unwrap().1, P + 0x1f);
+
+        assert!(addr_space.translate(va(P - 1)).is_none());
+        assert!(addr_space.translate(va(2 * P)).is_none());
+        assert!(addr_space.translate(va(0)).is_none());
+    }
+
+    #[test]
+    fn translate_picks_the_right_mapping() {
+        let mut addr_space = AddressSpace::new("translate many");
+        let maps = three_mappings(&mut addr_space);
+        for (src, addr) in &maps {
+            let (found, offset) = addr_space.translate(*addr).unwrap();
+            assert!(Arc::ptr_eq(&found, src));
+            assert_eq!(offset, 0);
+        }
+        assert!(addr_space.translate(maps[2].1 + P).is_none());
+    }
+
+    #[test]
+    fn read_across_adjacent_mappings() {
+        let mut addr_space = AddressSpace::new("read adjacent");
+        addr_space
+            .add_mapping_at(Arc::new(Pattern), 0, P, va(P), Permissions::READ)
+            .unwrap();
+        addr_space
+            .add_mapping_at(Arc::new(Pattern), 0x42, P, va(2 * P), Permissions::READ)
+            .unwrap();
+
+        let mut buf = [0; 16];
+        addr_space.read(va(2 * P - 8), &mut buf).unwrap();
+        assert_eq!(buf[..8], pattern(P - 8, 8));
+        assert_eq!(buf[8..], pattern(0x42, 8));
+    }
+
+    #[test]
+    fn read_off_the_end_faults() {
+        let mut addr_space = AddressSpace::new("read off the end");
+        addr_space
+            .add_mapping_at(Arc::new(Pattern), 0, P, va(P), Permissions::READ)
+            .unwrap();
+
+        let mut buf = [0xaa; 16];
+        assert_eq!(
+            addr_space.read(va(2 * P - 8), &mut buf),
+            Err(MappingError::Fault { addr: va(2 * P) })
+        );
+        assert_eq!(buf[..8], pattern(P - 8, 8));
+        assert!(buf[8..].iter().all(|&byte| byte == 0xaa));
+
+        assert_eq!(
+            addr_space.read(va(0x900), &mut buf),
+            Err(MappingError::Fault { addr: va(0x900) })
+        );
+    }
+
+    #[test]
+    fn write_to_read_only_mapping_is_rejected() {
+        let mut addr_space = AddressSpace::new("read-only");
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(P), Permissions::READ)
+            .unwrap();
+        assert_eq!(
+            addr_space.write(va(P + 4), b"nope"),
+            Err(MappingError::PermissionDenied { addr: va(P + 4) })
+        );
+        assert_eq!(
+            addr_space.check_access(va(P + 4), Permissions::WRITE),
+            Err(MappingError::PermissionDenied { addr: va(P + 4) })
+        );
+        assert_eq!(
+            addr_space.check_access(va(P + 4), Permissions::READ),
+            Ok(())
+        );
+        assert_eq!(
+            addr_space.check_access(va(2 * P), Permissions::READ),
+            Err(MappingError::Fault { addr: va(2 * P) })
+        );
+    }
+
+    #[test]
+    fn read_from_write_only_mapping_is_rejected() {
+        let mut addr_space = AddressSpace::new("write-only");
+        addr_space
+            .add_mapping_at(Arc::new(Pattern), 0, P, va(P), Permissions::READ)
+            .unwrap();
+        addr_space
+            .add_mapping_at(Arc::new(Pattern), 0, P, va(2 * P), Permissions::WRITE)
+            .unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(
+            addr_space.read(va(2 * P - 8), &mut buf),
+            Err(MappingError::PermissionDenied { addr: va(2 * P) })
+        );
+    }
+
+    #[test]
+    fn write_to_read_only_file_fails_at_source() {
+        let mut addr_space = AddressSpace::new("write");
+        let rw = Permissions::READ | Permissions::WRITE;
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(P), rw)
+            .unwrap();
+        assert_eq!(
+            addr_space.write(va(P), b"nope"),
+            Err(MappingError::Source { addr: va(P) })
+        );
+        assert_eq!(
+            addr_space.write(va(P + 6), b"nope"),
+            Err(MappingError::Source { addr: va(P + 6) })
+        );
+    }
+
+    fn layout(addr_space: &AddressSpace) -> Vec<(VirtualAddress, usize, usize, Permissions)> {
+        addr_space.validate().unwrap();
+        addr_space
+            .mappings
+            .read()
+            .values()
+            .map(|entry| (entry.addr, entry.span, entry.offset, entry.perms))
+            .collect()
+    }
+
+    const R: Permissions = Permissions::READ;
+    const RW: Permissions = Permissions::READ.union(Permissions::WRITE);
+
+    #[test]
+    fn protect_exact_mapping() {
+        let mut addr_space = AddressSpace::new("protect exact");
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 4 * P, va(P), R)
+            .unwrap();
+        addr_space.protect(va(P), 4 * P, RW).unwrap();
+        assert_eq!(layout(&addr_space), vec![(va(P), 4 * P, 0, RW)]);
+    }
+
+    #[test]
+    fn protect_front_of_mapping() {
+        let mut addr_space = AddressSpace::new("protect front");
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 4 * P, va(P), R)
+            .unwrap();
+        addr_space.protect(va(P), P, RW).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(P), P, 0, RW), (va(2 * P), 3 * P, P, R)]
+        );
+    }
+
+    #[test]
+    fn protect_back_of_mapping() {
+        let mut addr_space = AddressSpace::new("protect back");
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 4 * P, va(P), R)
+            .unwrap();
+        addr_space.protect(va(4 * P), P, RW).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(P), 3 * P, 0, R), (va(4 * P), P, 3 * P, RW)]
+        );
+    }
+
+    #[test]
+    fn protect_middle_of_mapping() {
+        let mut addr_space = AddressSpace::new("protect middle");
+        addr_space
+            .add_mapping_at(memory(8 * P), 0x10, 4 * P, va(P), R)
+            .unwrap();
+        addr_space.protect(va(2 * P), P, RW).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![
+                (va(P), P, 0x10, R),
+                (va(2 * P), P, P + 0x10, RW),
+                (va(3 * P), 2 * P, 2 * P + 0x10, R)
+            ]
+        );
+        assert_eq!(addr_space.check_access(va(3 * P - 1), RW), Ok(()));
+        assert!(addr_space.check_access(va(3 * P), RW).is_err());
+    }
+
+    #[test]
+    fn protect_across_mappings() {
+        let mut addr_space = AddressSpace::new("protect across");
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 2 * P, va(P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 2 * P, va(3 * P), R)
+            .unwrap();
+        addr_space.protect(va(2 * P), 2 * P, RW).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![
+                (va(P), P, 0, R),
+                (va(2 * P), P, P, RW),
+                (va(3 * P), P, 0, RW),
+                (va(4 * P), P, P, R)
+            ]
+        );
+    }
+
+    #[test]
+    fn protect_unmapped_range_changes_nothing() {
+        let mut addr_space = AddressSpace::new("protect hole");
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(P), R)
+            .unwrap();
+        let hole = source();
+        addr_space
+            .add_mapping_at(hole.clone(), 0, 100, va(2 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(3 * P), R)
+            .unwrap();
+        addr_space.remove_mapping(&*hole, va(2 * P)).unwrap();
+        assert_eq!(
+            addr_space.protect(va(P), 3 * P, RW),
+            Err(MappingError::NotFound { addr: va(2 * P) })
+        );
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(P), P, 0, R), (va(3 * P), P, 0, R)]
+        );
+    }
+
+    #[test]
+    fn text_segment_rejects_writes() {
+        let mut addr_space = AddressSpace::new("segments");
+        let rx = R | Permissions::EXECUTE;
+        // the loader writes the code in, then seals it
+        let text = addr_space.add_mapping(memory(3 * P), 0, 2 * P, RW).unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, text + 2 * P, RW)
+            .unwrap();
+        let data = addr_space.add_mapping(memory(P), 0, P, RW).unwrap();
+        addr_space.write(text + 2 * P - 2, b"code").unwrap();
+
+        addr_space.define_segment(".text", text, 3 * P).unwrap();
+        addr_space.define_segment(".data", data, P).unwrap();
+        assert_eq!(
+            addr_space.segment(".text"),
+            Some(&Segment {
+                name: ".text".to_string(),
+                start: text,
+                span: 3 * P,
+            })
+        );
+        assert_eq!(addr_space.segment_mappings(".text").unwrap().len(), 2);
+
+        addr_space.protect_segment(".text", rx).unwrap();
+        assert_eq!(
+            addr_space.write(text + P, b"patch"),
+            Err(MappingError::PermissionDenied { addr: text + P })
+        );
+        addr_space.write(data, b"data").unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![
+                (text, 2 * P, 0, rx),
+                (text + 2 * P, P, 0, rx),
+                (data, P, 0, RW)
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_move_with_their_mappings() {
+        let mut addr_space = AddressSpace::new("segments");
+        let code = memory(3 * P);
+        addr_space
+            .add_mapping_at(code.clone(), 0, 2 * P, va(0x10 * P), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0x12 * P), RW)
+            .unwrap();
+        addr_space
+            .define_segment(".text", va(0x10 * P), 3 * P)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0x40 * P), RW)
+            .unwrap();
+        addr_space.define_segment("stack", va(0x40 * P), P).unwrap();
+
+        addr_space.move_mapping(va(0x40 * P), va(0x50 * P)).unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0x40 * P), RW)
+            .unwrap();
+        addr_space.protect_segment("stack", R).unwrap();
+        assert_eq!(
+            addr_space
+                .segment("stack")
+                .map(|stack| (stack.start, stack.span)),
+            Some((va(0x50 * P), P))
+        );
+        assert_eq!(addr_space.mapping_at(va(0x50 * P)).unwrap().perms, R);
+        assert_eq!(addr_space.mapping_at(va(0x40 * P)).unwrap().perms, RW);
+
+        // moving the code away from the rest of the segment leaves the segment behind it
+        addr_space.rebase(&code, 0x20 * P as isize).unwrap();
+        assert_eq!(
+            addr_space
+                .segment(".text")
+                .map(|text| (text.start, text.span)),
+            Some((va(0x30 * P), 2 * P))
+        );
+        addr_space.protect_segment(".text", R).unwrap();
+        assert_eq!(addr_space.mapping_at(va(0x30 * P)).unwrap().perms, R);
+        assert_eq!(addr_space.mapping_at(va(0x12 * P)).unwrap().perms, RW);
+    }
+
+    #[test]
+    fn define_segment_rejects_bad_ranges() {
+        let mut addr_space = AddressSpace::new("segments");
+        addr_space
+            .add_mapping_at(unbounded(), 0, 4 * P, va(0), RW)
+            .unwrap();
+        assert_eq!(
+            addr_space.define_segment("heap", va(0), 0),
+            Err(MappingError::InvalidSpan)
+        );
+        assert_eq!(
+            addr_space.define_segment("heap", va(1), P),
+            Err(MappingError::Unaligned { addr: va(1) })
+        );
+        assert_eq!(
+            addr_space.define_segment("heap", va(2 * P), 3 * P),
+            Err(MappingError::NotFound { addr: va(4 * P) })
+        );
+        addr_space.define_segment("heap", va(P), 2 * P).unwrap();
+        assert_eq!(
+            addr_space.define_segment("stack", va(2 * P), 2 * P),
+            Err(MappingError::Overlap { addr: va(P) })
+        );
+        // redefining a segment may move it over where it was
+        addr_space.define_segment("heap", va(2 * P), 1).unwrap();
+        assert_eq!(addr_space.segment("heap").map(|heap| heap.span), Some(P));
+        assert_eq!(
+            addr_space.protect_segment("stack", R),
+            Err(MappingError::UnknownSegment)
+        );
+        assert!(addr_space.segment_mappings("stack").is_none());
+
+        addr_space.unmap(va(2 * P), P).unwrap();
+        assert_eq!(
+            addr_space.protect_segment("heap", R),
+            Err(MappingError::NotFound { addr: va(2 * P) })
+        );
+        assert!(addr_space.remove_segment("heap").is_some());
+        assert_eq!(addr_space.segment("heap"), None);
+    }
+
+    #[test]
+    fn iter_in_address_order() {
+        let mut addr_space = AddressSpace::new("iter");
+        let high = unbounded();
+        addr_space
+            .add_mapping_at(high.clone(), 0x30, P, va(8 * P), RW)
+            .unwrap();
+        let low = unbounded();
+        addr_space.add_mapping(low.clone(), 0x10, 2 * P, R).unwrap();
+        let middle = unbounded();
+        addr_space.add_mapping(middle.clone(), 0x20, P, R).unwrap();
+
+        let infos: Vec<MappingInfo> = addr_space.iter().collect();
+        let summary: Vec<_> = infos
+            .iter()
+            .map(|info| (info.addr, info.span, info.offset, info.perms))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (va(0), 2 * P, 0x10, R),
+                (va(2 * P), P, 0x20, R),
+                (va(8 * P), P, 0x30, RW)
+            ]
+        );
+        assert!(Arc::ptr_eq(infos[0].source.as_ref().unwrap(), &low));
+        assert!(Arc::ptr_eq(infos[1].source.as_ref().unwrap(), &middle));
+        assert!(Arc::ptr_eq(infos[2].source.as_ref().unwrap(), &high));
+    }
+
+    #[test]
+    fn add_mapping_at_overlapping_front_edge() {
+        let mut addr_space = AddressSpace::new("overlap front");
+        addr_space
+            .add_mapping_at(unbounded(), 0, 4 * P, va(4 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, 2 * P, va(3 * P), R),
+            Err(MappingError::Overlap { addr: va(4 * P) })
+        );
+        assert_eq!(addr_space.mappings.read().len(), 1);
+    }
+
+    #[test]
+    fn add_mapping_at_overlapping_back_edge() {
+        let mut addr_space = AddressSpace::new("overlap back");
+        addr_space
+            .add_mapping_at(unbounded(), 0, 4 * P, va(4 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, 2 * P, va(7 * P), R),
+            Err(MappingError::Overlap { addr: va(4 * P) })
+        );
+        assert_eq!(addr_space.mappings.read().len(), 1);
+    }
+
+    #[test]
+    fn add_mapping_at_fully_contained() {
+        let mut addr_space = AddressSpace::new("overlap contained");
+        addr_space
+            .add_mapping_at(unbounded(), 0, 4 * P, va(4 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, P, va(5 * P), R),
+            Err(MappingError::Overlap { addr: va(4 * P) })
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(unbounded(), 0, 8 * P, va(2 * P), R),
+            Err(MappingError::Overlap { addr: va(4 * P) })
+        );
+        assert_eq!(addr_space.mappings.read().len(), 1);
+    }
+
+    #[test]
+    fn map_fixed_replaces_and_trims() {
+        let mut addr_space = AddressSpace::new("map fixed");
+        let replaced = memory(2 * P);
+        let trimmed = memory(4 * P);
+        let neighbour = memory(P);
+        addr_space
+            .add_mapping_at(trimmed.clone(), 0, 4 * P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(replaced, 0, 2 * P, va(5 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(neighbour.clone(), 0, P, va(8 * P), R)
+            .unwrap();
+
+        let new = memory(4 * P);
+        addr_space
+            .map_fixed(new.clone(), 0, 4 * P, va(3 * P), RW)
+            .unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![
+                (va(0), 3 * P, 0, R),
+                (va(3 * P), 4 * P, 0, RW),
+                (va(8 * P), P, 0, R)
+            ]
+        );
+        assert!(Arc::ptr_eq(
+            &addr_space.translate(va(0)).unwrap().0,
+            &trimmed
+        ));
+        assert!(Arc::ptr_eq(
+            &addr_space.translate(va(6 * P)).unwrap().0,
+            &new
+        ));
+        assert!(Arc::ptr_eq(
+            &addr_space.translate(va(8 * P)).unwrap().0,
+            &neighbour
+        ));
+    }
+
+    #[test]
+    fn map_fixed_takes_over_guard_pages() {
+        let mut addr_space = AddressSpace::new("map fixed guard");
+        let stack = addr_space
+            .add_mapping_guarded(memory(P), 0, P, 2, RW)
+            .unwrap();
+        assert_eq!(stack, va(2 * P));
+        addr_space.map_fixed(memory(P), 0, P, va(P), R).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(P), P, 0, R), (va(2 * P), P, 0, RW)]
+        );
+        // guard pages must sit directly below their mapping, so the one below the new mapping
+        // is given up too
+        assert_eq!(
+            addr_space.check_access(va(0), R),
+            Err(MappingError::Fault { addr: va(0) })
+        );
+    }
+
+    #[test]
+    fn map_fixed_counts_what_it_replaces_against_the_limit() {
+        let mut addr_space = AddressSpace::new("map fixed limit").with_max_mappings(2);
+        addr_space
+            .add_mapping_at(memory(3 * P), 0, 3 * P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(4 * P), R)
+            .unwrap();
+        addr_space
+            .map_fixed(memory(P), 0, P, va(4 * P), RW)
+            .unwrap();
+        // splitting the first mapping around the new one would make three
+        assert_eq!(
+            addr_space.map_fixed(memory(P), 0, P, va(P), RW),
+            Err(MappingError::TooManyMappings)
+        );
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), 3 * P, 0, R), (va(4 * P), P, 0, RW)]
+        );
+    }
+
+    #[test]
+    fn failed_map_fixed_changes_nothing() {
+        let mut addr_space = AddressSpace::new("map fixed errors").with_ceiling(4 * P);
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.map_fixed(memory(P), 0, P, va(1), R),
+            Err(MappingError::Unaligned { addr: va(1) })
+        );
+        assert_eq!(
+            addr_space.map_fixed(memory(8 * P), 0, 8 * P, va(0), R),
+            Err(MappingError::OutOfRange { addr: va(0) })
+        );
+        assert_eq!(
+            addr_space.map_fixed(memory(P), 0, 0, va(0), R),
+            Err(MappingError::InvalidSpan)
+        );
+        assert_eq!(layout(&addr_space), vec![(va(0), 2 * P, 0, R)]);
+    }
+
+    #[test]
+    fn add_mapping_at_in_free_gap() {
+        let mut addr_space = AddressSpace::new("free gap");
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(8 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(4 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(unbounded(), 0, 2 * P, va(2 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(0), R)
+            .unwrap();
+        assert_eq!(
+            addrs(&addr_space),
+            vec![va(0), va(P), va(2 * P), va(4 * P), va(8 * P)]
+        );
+    }
+
+    // add_mapping_at used to store the base of the gap containing `start` instead of `start`
+    #[test]
+    fn add_mapping_at_uses_requested_base() {
+        let mut addr_space = AddressSpace::new("requested base");
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(16 * P), R)
+            .unwrap();
+        let src = source();
+        addr_space
+            .add_mapping_at(src.clone(), 0, 100, va(8 * P), R)
+            .unwrap();
+
+        let (found, offset) = addr_space.translate(va(8 * P)).unwrap();
+        assert!(Arc::ptr_eq(&found, &src));
+        assert_eq!(offset, 0);
+        // the gap containing 8 * P starts right after the first mapping
+        assert!(addr_space.translate(va(P)).is_none());
+    }
+
+    fn memory(len: usize) -> Arc<dyn DataSource> {
+        Arc::new(MemorySource::new(pattern(0, len)))
+    }
+
+    #[test]
+    fn fork_isolates_child_writes() {
+        let mut parent = AddressSpace::new("parent");
+        let shared = memory(4 * P);
+        parent
+            .add_mapping_at(shared.clone(), 0, 4 * P, va(P), RW)
+            .unwrap();
+        let mut child = parent.fork();
+        assert_eq!(
+            parent.check_access(va(P), Permissions::WRITE),
+            Err(MappingError::PermissionDenied { addr: va(P) })
+        );
+        assert_eq!(
+            child.check_access(va(P), Permissions::WRITE),
+            Err(MappingError::PermissionDenied { addr: va(P) })
+        );
+
+        child.write(va(2 * P + 8), b"child").unwrap();
+
+        let mut buf = [0; 5];
+        child.read(va(2 * P + 8), &mut buf).unwrap();
+        assert_eq!(&buf, b"child");
+        parent.read(va(2 * P + 8), &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(P + 8, 5));
+
+        // only the written page was copied
+        assert_eq!(
+            layout(&child),
+            vec![
+                (va(P), P, 0, RW),
+                (va(2 * P), P, 0, RW),
+                (va(3 * P), 2 * P, 2 * P, RW)
+            ]
+        );
+        assert!(Arc::ptr_eq(
+            &child
+                .mappings
+                .read()
+                .values()
+                .next()
+                .unwrap()
+                .source
+                .upgrade()
+                .unwrap(),
+            &shared
+        ));
+        assert!(!Arc::ptr_eq(
+            &child
+                .entry_containing(&child.mappings.read(), va(2 * P))
+                .unwrap()
+                .source
+                .upgrade()
+                .unwrap(),
+            &shared
+        ));
+        assert_eq!(child.check_access(va(2 * P), Permissions::WRITE), Ok(()));
+        assert!(child.check_access(va(P), Permissions::WRITE).is_err());
+    }
+
+    #[test]
+    fn fork_isolates_parent_writes() {
+        let mut parent = AddressSpace::new("parent");
+        parent.add_mapping_at(memory(P), 0, P, va(0), RW).unwrap();
+        let child = parent.fork();
+
+        // the parent gets its own copy too, leaving the child with the original bytes
+        parent.write(va(0x10), b"parent").unwrap();
+        let mut buf = [0; 6];
+        child.read(va(0x10), &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(0x10, 6));
+        parent.read(va(0x10), &mut buf).unwrap();
+        assert_eq!(&buf, b"parent");
+    }
+
+    #[test]
+    fn write_across_cow_pages() {
+        let mut parent = AddressSpace::new("parent");
+        parent
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), RW)
+            .unwrap();
+        let mut child = parent.fork();
+        child.write(va(P - 2), b"abcd").unwrap();
+
+        let mut buf = [0; 4];
+        child.read(va(P - 2), &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        parent.read(va(P - 2), &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(P - 2, 4));
+    }
+
+    #[test]
+    fn cow_fault_on_read_only_mapping() {
+        let mut parent = AddressSpace::new("parent");
+        parent.add_mapping_at(memory(P), 0, P, va(0), R).unwrap();
+        let mut child = parent.fork();
+        assert_eq!(
+            child.write(va(0), b"no"),
+            Err(MappingError::PermissionDenied { addr: va(0) })
+        );
+        assert_eq!(
+            child.handle_cow_fault(va(0)),
+            Err(MappingError::PermissionDenied { addr: va(0) })
+        );
+        assert_eq!(
+            child.handle_cow_fault(va(P)),
+            Err(MappingError::Fault { addr: va(P) })
+        );
+    }
+
+    #[test]
+    fn cow_pages_are_copied_only_while_shared() {
+        let mut first = AddressSpace::new("first");
+        let shared = memory(2 * P);
+        first
+            .add_mapping_at(shared.clone(), 0, 2 * P, va(0), RW)
+            .unwrap();
+        let mut second = first.fork();
+        let mut third = second.fork();
+        let source_at = |addr_space: &AddressSpace, addr| {
+            addr_space
+                .entry_containing(&addr_space.mappings.read(), va(addr))
+                .unwrap()
+                .source
+                .upgrade()
+                .unwrap()
+        };
+
+        // the writer gets a copy, and the other two keep sharing the original
+        second.write(va(8), b"second").unwrap();
+        assert!(!Arc::ptr_eq(&source_at(&second, 0), &shared));
+        assert!(Arc::ptr_eq(&source_at(&first, 0), &shared));
+        assert!(Arc::ptr_eq(&source_at(&third, 0), &shared));
+        let mut buf = [0; 6];
+        first.read(va(8), &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(8, 6));
+        third.read(va(8), &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(8, 6));
+
+        // two still share the page, so the next writer copies it too, but the last doesn't
+        third.write(va(8), b"third!").unwrap();
+        assert!(!Arc::ptr_eq(&source_at(&third, 0), &shared));
+        first.write(va(8), b"first!").unwrap();
+        assert!(Arc::ptr_eq(&source_at(&first, 0), &shared));
+        for (addr_space, expected) in [
+            (&mut first, b"first!"),
+            (&mut second, b"second"),
+            (&mut third, b"third!"),
+        ] {
+            addr_space.read(va(8), &mut buf).unwrap();
+            assert_eq!(&buf, expected);
+        }
+
+        // nobody has written the second page, so all three still share it
+        second.write(va(P), b"x").unwrap();
+        assert!(!Arc::ptr_eq(&source_at(&second, P), &shared));
+        assert!(Arc::ptr_eq(&source_at(&third, P), &shared));
+    }
+
+    #[test]
+    fn cow_pages_of_a_dropped_fork_are_not_copied() {
+        let mut parent = AddressSpace::new("parent");
+        let shared = memory(P);
+        parent
+            .add_mapping_at(shared.clone(), 0, P, va(0), RW)
+            .unwrap();
+        let child = parent.fork();
+        let grandchild = child.clone();
+        drop(child);
+        parent.write(va(0), b"parent").unwrap();
+        assert!(!Arc::ptr_eq(
+            &parent
+                .entry_containing(&parent.mappings.read(), va(0))
+                .unwrap()
+                .source
+                .upgrade()
+                .unwrap(),
+            &shared
+        ));
+
+        drop(grandchild);
+        let mut parent = AddressSpace::new("parent");
+        parent
+            .add_mapping_at(shared.clone(), 0, P, va(0), RW)
+            .unwrap();
+        drop(parent.fork());
+        parent.write(va(0), b"parent").unwrap();
+        assert!(Arc::ptr_eq(
+            &parent
+                .entry_containing(&parent.mappings.read(), va(0))
+                .unwrap()
+                .source
+                .upgrade()
+                .unwrap(),
+            &shared
+        ));
+        assert_eq!(parent.check_access(va(0), Permissions::WRITE), Ok(()));
+    }
+
+    #[test]
+    fn clone_region_copies_across_mappings() {
+        let mut addr_space = AddressSpace::new("clone");
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(2 * P), R)
+            .unwrap();
+        let copy = addr_space.clone_region(va(P + 16), 2 * P - 32).unwrap();
+        assert_eq!(copy, va(3 * P));
+        assert_eq!(addr_space.mapping_at(copy).unwrap().span, 2 * P);
+
+        let mut expected = pattern(P + 16, P - 16);
+        expected.extend(pattern(0, P - 16));
+        assert_eq!(addr_space.read_vec(copy, 2 * P - 32).unwrap(), expected);
+        assert_eq!(addr_space.read_vec(copy + 2 * P - 32, 32).unwrap(), [0; 32]);
+
+        addr_space.write(copy, &[0xff; 2 * P]).unwrap();
+        assert_eq!(
+            addr_space.read_vec(va(P + 16), P - 16).unwrap(),
+            pattern(P + 16, P - 16)
+        );
+        assert_eq!(addr_space.read_vec(va(2 * P), P).unwrap(), pattern(0, P));
+
+        assert_eq!(
+            addr_space.clone_region(va(4 * P), 2 * P),
+            Err(MappingError::Fault { addr: va(5 * P) })
+        );
+        assert_eq!(
+            addr_space.clone_region(va(0), 0),
+            Err(MappingError::InvalidSpan)
+        );
+    }
+
+    #[test]
+    fn fetch_needs_execute() {
+        let mut addr_space = AddressSpace::new("emulator");
+        let rx = Permissions::READ | Permissions::EXECUTE;
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), rx)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(P), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(2 * P), Permissions::EXECUTE)
+            .unwrap();
+
+        let mut buf = [0; 8];
+        addr_space.fetch(va(0x10), &mut buf).unwrap();
+        assert_eq!(buf[..], pattern(0x10, 8));
+        assert_eq!(
+            addr_space.fetch(va(P - 4), &mut buf),
+            Err(MappingError::NotExecutable { addr: va(P) })
+        );
+        // what was fetched before the failure is kept
+        assert_eq!(buf[..4], pattern(P - 4, 4));
+        assert_eq!(
+            addr_space.fetch(va(P + 8), &mut buf),
+            Err(MappingError::NotExecutable { addr: va(P + 8) })
+        );
+
+        // execute-only memory can be fetched but not read
+        addr_space.fetch(va(2 * P), &mut buf).unwrap();
+        assert_eq!(
+            addr_space.read(va(2 * P), &mut buf),
+            Err(MappingError::PermissionDenied { addr: va(2 * P) })
+        );
+        assert_eq!(
+            addr_space.fetch(va(3 * P), &mut buf),
+            Err(MappingError::Fault { addr: va(3 * P) })
+        );
+    }
+
+    #[test]
+    fn read_vec_and_write_all() {
+        let mut addr_space = AddressSpace::new("convenience");
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(2 * P), RW)
+            .unwrap();
+        assert_eq!(
+            addr_space.read_vec(va(P - 3), 8).unwrap(),
+            pattern(P - 3, 8)
+        );
+        assert_eq!(addr_space.read_vec(va(0), 0).unwrap(), Vec::<u8>::new());
+
+        // across the boundary between the two mappings
+        addr_space.write_all(va(2 * P - 4), b"spanning").unwrap();
+        assert_eq!(addr_space.read_vec(va(2 * P - 4), 8).unwrap(), b"spanning");
+        assert_eq!(
+            addr_space.read_vec(va(3 * P - 2), 4),
+            Err(MappingError::Fault { addr: va(3 * P) })
+        );
+        assert_eq!(
+            addr_space.write_all(va(3 * P - 2), b"edge"),
+            Err(MappingError::Fault { addr: va(3 * P) })
+        );
+    }
+
+    #[test]
+    fn rebase_moves_a_library() {
+        let mut addr_space = AddressSpace::new("loader");
+        let library = memory(4 * P);
+        addr_space
+            .add_mapping_at(library.clone(), 0, P, va(0x10 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(library.clone(), 2 * P, 2 * P, va(0x12 * P), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0x11 * P), R)
+            .unwrap();
+
+        let delta = (0x20 * P).cast_signed();
+        addr_space.rebase(&library, delta).unwrap();
+        for (old, offset) in [
+            (0x10 * P + 8, 8),
+            (0x12 * P, 2 * P),
+            (0x13 * P + 1, 3 * P + 1),
+        ] {
+            let (source, at) = addr_space.translate(va(old + 0x20 * P)).unwrap();
+            assert!(Arc::ptr_eq(&source, &library));
+            assert_eq!(at, offset);
+            assert!(addr_space.translate(va(old)).is_none());
+        }
+        assert_eq!(
+            addrs(&addr_space),
+            vec![va(0x11 * P), va(0x30 * P), va(0x32 * P)]
+        );
+
+        // the way back down is blocked by the mapping at 0x11 pages, so nothing moves
+        let back = -(0x21 * P).cast_signed();
+        assert_eq!(
+            addr_space.rebase(&library, back),
+            Err(MappingError::Overlap { addr: va(0x11 * P) })
+        );
+        assert_eq!(
+            addr_space.rebase(&library, -(0x31 * P).cast_signed()),
+            Err(MappingError::OutOfRange { addr: va(0x30 * P) })
+        );
+        assert_eq!(
+            addr_space.rebase(&library, 8),
+            Err(MappingError::Unaligned {
+                addr: va(0x30 * P + 8)
+            })
+        );
+        assert_eq!(
+            addrs(&addr_space),
+            vec![va(0x11 * P), va(0x30 * P), va(0x32 * P)]
+        );
+    }
+
+    #[test]
+    fn unmap_hole_in_mapping() {
+        let mut addr_space = AddressSpace::new("unmap hole");
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 4 * P, va(P), R)
+            .unwrap();
+        addr_space.unmap(va(2 * P), 2 * P).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(P), P, 0, R), (va(4 * P), P, 3 * P, R)]
+        );
+        assert!(addr_space.translate(va(2 * P)).is_none());
+        assert!(addr_space.translate(va(4 * P - 1)).is_none());
+        assert_eq!(addr_space.translate(va(4 * P)).unwrap().1, 3 * P);
+    }
+
+    #[test]
+    fn unmap_works_in_whole_pages() {
+        let mut addr_space = AddressSpace::new("unmap pages");
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, 4 * P, va(0), R)
+            .unwrap();
+        // like munmap, the span is rounded up, so no mapping ends mid-page
+        addr_space.unmap(va(P), 100).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), P, 0, R), (va(2 * P), 2 * P, 2 * P, R)]
+        );
+        assert_eq!(
+            addr_space.unmap(va(2 * P + 100), P),
+            Err(MappingError::Unaligned {
+                addr: va(2 * P + 100)
+            })
+        );
+        assert_eq!(addr_space.mapping_count(), 2);
+    }
+
+    #[test]
+    fn unmap_across_mappings() {
+        let mut addr_space = AddressSpace::new("unmap sweep");
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 2 * P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, P, va(3 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, P, va(5 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 2 * P, va(6 * P), R)
+            .unwrap();
+        addr_space.unmap(va(P), 6 * P).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), P, 0, R), (va(7 * P), P, P, R)]
+        );
+    }
+
+    #[test]
+    fn unmap_empty_space_is_idempotent() {
+        let mut addr_space = AddressSpace::new("unmap nothing");
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(4 * P), R)
+            .unwrap();
+        addr_space.unmap(va(0), 4 * P).unwrap();
+        addr_space.unmap(va(0), 4 * P).unwrap();
+        addr_space.unmap(va(4 * P), P).unwrap();
+        addr_space.unmap(va(4 * P), P).unwrap();
+        assert!(addr_space.mappings.read().is_empty());
+        assert_eq!(
+            addr_space.unmap(va(AddressSpace::MAX_ADDRESS - P), 2 * P),
+            Err(MappingError::OutOfRange {
+                addr: va(AddressSpace::MAX_ADDRESS - P)
+            })
+        );
+    }
+
+    #[test]
+    fn many_mappings_translate() {
+        let mut addr_space = AddressSpace::new("many");
+        let src = unbounded();
+        for i in 0..10_000 {
+            addr_space
+                .add_mapping_at(src.clone(), i, P, va(2 * i * P), R)
+                .unwrap();
+        }
+        assert_eq!(addr_space.mappings.read().len(), 10_000);
+
+        // a simple LCG is plenty to scatter the lookups
+        let mut state: usize = 12345;
+        for _ in 0..100_000 {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1);
+            let addr = (state >> 16) % (20_000 * P);
+            let page = addr / P;
+            match addr_space.translate(va(addr)) {
+                Some((_, offset)) => {
+                    assert_eq!(page % 2, 0);
+                    assert_eq!(offset, page / 2 + addr % P);
+                }
+                None => assert_eq!(page % 2, 1),
+            }
+        }
+    }
+
+    #[test]
+    fn find_free_on_empty_space() {
+        let addr_space = AddressSpace::new("find free");
+        assert_eq!(addr_space.find_free(P), Some(va(0)));
+        assert_eq!(addr_space.find_free(AddressSpace::MAX_ADDRESS), Some(va(0)));
+        assert_eq!(addr_space.find_free(AddressSpace::MAX_ADDRESS + 1), None);
+    }
+
+    #[test]
+    fn find_free_does_not_insert() {
+        let mut addr_space = AddressSpace::new("find free twice");
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(0), R)
+            .unwrap();
+        let first = addr_space.find_free(100);
+        assert_eq!(first, Some(va(P)));
+        assert_eq!(addr_space.find_free(100), first);
+        assert_eq!(addr_space.mappings.read().len(), 1);
+        assert_eq!(addr_space.add_mapping(source(), 0, 100, R).ok(), first);
+    }
+
+    #[test]
+    fn find_free_aligned_skips_to_alignment() {
+        let mut addr_space = AddressSpace::new("find free aligned");
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(source(), 0, 100, va(17 * P), R)
+            .unwrap();
+        assert_eq!(addr_space.find_free_aligned(P, 16 * P), Some(va(16 * P)));
+        assert_eq!(
+            addr_space.find_free_aligned(2 * P, 16 * P),
+            Some(va(32 * P))
+        );
+        // alignments below a page still give page-aligned bases
+        assert_eq!(addr_space.find_free_aligned(P, 16), Some(va(P)));
+    }
+
+    #[test]
+    fn find_free_from_keeps_above_the_floor() {
+        let mut addr_space = AddressSpace::new("find free from");
+        assert_eq!(addr_space.find_free_from(P, P, va(P)), Some(va(P)));
+        assert_eq!(addr_space.find_free_from(P, P, va(P + 1)), Some(va(2 * P)));
+        assert_eq!(addr_space.find_free(P), Some(va(0)));
+
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(4 * P), R)
+            .unwrap();
+        // there is room at 0, but it's below the floor
+        assert_eq!(addr_space.find_free_from(P, P, va(P)), Some(va(2 * P)));
+        assert_eq!(addr_space.find_free_from(P, P, va(3 * P)), Some(va(3 * P)));
+    }
+
+    #[test]
+    fn find_free_from_skips_gaps_without_an_aligned_base() {
+        let mut addr_space = AddressSpace::new("find free from");
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(unbounded(), 0, P, va(4 * P), R)
+            .unwrap();
+        // the gap [P, 4P) is big enough, but has no 16-page-aligned base
+        assert_eq!(addr_space.find_free_from(P, P, va(P)), Some(va(P)));
+        assert_eq!(
+            addr_space.find_free_from(P, 16 * P, va(P)),
+            Some(va(16 * P))
+        );
+        assert_eq!(
+            addr_space.find_free_from(P, 16 * P, va(17 * P)),
+            Some(va(32 * P))
+        );
+        let top = va(AddressSpace::MAX_ADDRESS - P);
+        assert_eq!(addr_space.find_free_from(2 * P, P, top), None);
+    }
+
+    #[test]
+    fn coalesce_contiguous_pieces() {
+        let mut addr_space = AddressSpace::new("coalesce");
+        let src = memory(4 * P);
+        addr_space
+            .add_mapping_at(src.clone(), 0, P, va(P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(src.clone(), P, 2 * P, va(2 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(src, 3 * P, P, va(4 * P), R)
+            .unwrap();
+        assert_eq!(addr_space.coalesce(), 2);
+        assert_eq!(layout(&addr_space), vec![(va(P), 4 * P, 0, R)]);
+        assert_eq!(addr_space.coalesce(), 0);
+    }
+
+    #[test]
+    fn coalesce_leaves_discontinuities() {
+        let mut addr_space = AddressSpace::new("coalesce nothing");
+        let src = memory(8 * P);
+        // a gap in the address space
+        addr_space
+            .add_mapping_at(src.clone(), 0, P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(src.clone(), P, P, va(2 * P), R)
+            .unwrap();
+        // a gap in the source
+        addr_space
+            .add_mapping_at(src.clone(), 3 * P, P, va(3 * P), R)
+            .unwrap();
+        // different permissions
+        addr_space
+            .add_mapping_at(src, 4 * P, P, va(4 * P), RW)
+            .unwrap();
+        // a different source
+        addr_space
+            .add_mapping_at(memory(6 * P), 5 * P, P, va(5 * P), RW)
+            .unwrap();
+        assert_eq!(addr_space.coalesce(), 0);
+        assert_eq!(addr_space.mappings.read().len(), 5);
+    }
+
+    #[test]
+    fn coalesce_undoes_protect() {
+        let mut addr_space = AddressSpace::new("coalesce protect");
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, 4 * P, va(0), R)
+            .unwrap();
+        addr_space.protect(va(P), P, RW).unwrap();
+        addr_space.protect(va(P), P, R).unwrap();
+        assert_eq!(addr_space.mappings.read().len(), 3);
+        assert_eq!(addr_space.coalesce(), 2);
+        assert_eq!(layout(&addr_space), vec![(va(0), 4 * P, 0, R)]);
+    }
+
+    #[test]
+    fn split_mapping_in_the_middle() {
+        let mut addr_space = AddressSpace::new("split");
+        let src = memory(4 * P);
+        addr_space
+            .add_mapping_at(src.clone(), P, 3 * P, va(2 * P), RW)
+            .unwrap();
+        addr_space.split_mapping(va(3 * P)).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(2 * P), P, P, RW), (va(3 * P), 2 * P, 2 * P, RW)]
+        );
+        for addr in [2 * P, 3 * P - 1, 3 * P, 5 * P - 1] {
+            let (found, offset) = addr_space.translate(va(addr)).unwrap();
+            assert!(Arc::ptr_eq(&found, &src));
+            assert_eq!(offset, addr - P);
+        }
+        let mut buf = vec![0; 2 * P];
+        addr_space.read(va(2 * P + P / 2), &mut buf).unwrap();
+        assert_eq!(buf, pattern(P + P / 2, 2 * P));
+    }
+
+    #[test]
+    fn split_mapping_at_boundary_is_a_no_op() {
+        let mut addr_space = AddressSpace::new("split boundary");
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, P, va(P), R)
+            .unwrap();
+        let before = layout(&addr_space);
+        addr_space.split_mapping(va(0)).unwrap();
+        addr_space.split_mapping(va(P)).unwrap();
+        assert_eq!(layout(&addr_space), before);
+    }
+
+    #[test]
+    fn split_mapping_in_unmapped_space() {
+        let mut addr_space = AddressSpace::new("split nothing");
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.split_mapping(va(P)),
+            Err(MappingError::NotFound { addr: va(P) })
+        );
+        assert_eq!(
+            addr_space.split_mapping(va(P / 2)),
+            Err(MappingError::Unaligned { addr: va(P / 2) })
+        );
+        assert_eq!(addr_space.mappings.read().len(), 1);
+    }
+
+    #[test]
+    fn validate_after_every_mutation() {
+        let mut addr_space = AddressSpace::new("validate");
+        let src = memory(8 * P);
+        addr_space.validate().unwrap();
+        addr_space.add_mapping(src.clone(), 0, 3 * P, RW).unwrap();
+        addr_space.validate().unwrap();
+        addr_space
+            .add_mapping_at(src.clone(), 3 * P, 5 * P, va(3 * P), R)
+            .unwrap();
+        addr_space.validate().unwrap();
+        addr_space.protect(va(2 * P), 2 * P, R).unwrap();
+        addr_space.validate().unwrap();
+        addr_space.unmap(va(P), P).unwrap();
+        addr_space.validate().unwrap();
+        addr_space.split_mapping(va(5 * P)).unwrap();
+        addr_space.validate().unwrap();
+        let mut child = addr_space.fork();
+        child.write(va(0), &[1; 10]).unwrap();
+        child.validate().unwrap();
+        addr_space.coalesce();
+        addr_space.validate().unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_broken_invariants() {
+        let fresh = || {
+            let mut addr_space = AddressSpace::new("broken");
+            addr_space
+                .add_mapping_at(memory(2 * P), 0, P, va(0), R)
+                .unwrap();
+            addr_space
+                .add_mapping_at(memory(2 * P), 0, P, va(P), R)
+                .unwrap();
+            addr_space
+        };
+
+        let mut overlapping = fresh();
+        overlapping.mappings.get_mut().get_mut(&va(0)).unwrap().span = 2 * P;
+        assert!(overlapping.validate().unwrap_err().contains("overlaps"));
+
+        let mut empty = fresh();
+        empty.mappings.get_mut().get_mut(&va(P)).unwrap().span = 0;
+        assert!(empty.validate().unwrap_err().contains("empty"));
+
+        let mut past_source = fresh();
+        past_source
+            .mappings
+            .get_mut()
+            .get_mut(&va(P))
+            .unwrap()
+            .offset = 2 * P;
+        assert!(past_source.validate().unwrap_err().contains("source"));
+
+        let past_ceiling = fresh().with_ceiling(P);
+        assert!(past_ceiling.validate().unwrap_err().contains("ceiling"));
+
+        let mut misfiled = fresh();
+        let entry = misfiled.mappings.get_mut().remove(&va(P)).unwrap();
+        misfiled.mappings.get_mut().insert(va(3 * P), entry);
+        assert!(misfiled.validate().unwrap_err().contains("stored under"));
+    }
+
+    #[test]
+    fn clone_has_independent_mappings() {
+        let mut original = AddressSpace::new("original");
+        let src = memory(4 * P);
+        original
+            .add_mapping_at(src.clone(), 0, 4 * P, va(0), RW)
+            .unwrap();
+        let clone = original.clone();
+        assert_eq!(clone.name, "original");
+
+        original.unmap(va(P), P).unwrap();
+        original.add_mapping(memory(P), 0, P, R).unwrap();
+        assert_eq!(layout(&clone), vec![(va(0), 4 * P, 0, RW)]);
+        assert_ne!(layout(&original), layout(&clone));
+
+        // ...but the data behind them is shared
+        original.write(va(0), &[0xff; 4]).unwrap();
+        let mut buf = [0; 4];
+        clone.read(va(0), &mut buf).unwrap();
+        assert_eq!(buf, [0xff; 4]);
+        assert!(Arc::ptr_eq(&clone.translate(va(0)).unwrap().0, &src));
+    }
+
+    #[test]
+    fn display_like_proc_maps() {
+        let mut addr_space = AddressSpace::new("display");
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, P, va(P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(4 * P), 2 * P, 2 * P, va(0x7f_0000_0000), RW)
+            .unwrap();
+        let rendered = addr_space.to_string();
+        assert_eq!(
+            rendered,
+            "display:\n\
+             0x000000001000-0x000000002000 r-- offset=0x0\n\
+             0x007f00000000-0x007f00002000 rw- offset=0x2000\n"
+        );
+        assert_eq!(AddressSpace::new("empty").to_string(), "empty:\n");
+    }
+
+    #[test]
+    fn rename_shows_in_display() {
+        let mut addr_space = AddressSpace::new("before");
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(P), R)
+            .unwrap();
+        assert_eq!(addr_space.name(), "before");
+        addr_space.rename("after");
+        assert_eq!(addr_space.name(), "after");
+        assert!(addr_space
+            .to_string()
+            .starts_with("after:\n0x000000001000-"));
+    }
+
+    #[test]
+    fn debug_includes_name() {
+        let mut addr_space = AddressSpace::new("debugged");
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(P), R)
+            .unwrap();
+        let rendered = format!("{addr_space:?}");
+        assert!(rendered.contains("\"debugged\""), "{rendered}");
+        assert!(rendered.contains("0x1000..0x2000"), "{rendered}");
+    }
+
+    #[test]
+    fn statistics_on_empty_space() {
+        let addr_space = AddressSpace::new("empty stats");
+        assert_eq!(addr_space.mapping_count(), 0);
+        assert_eq!(addr_space.total_mapped_bytes(), 0);
+        assert_eq!(addr_space.largest_free_gap(), AddressSpace::MAX_ADDRESS);
+    }
+
+    #[test]
+    fn gaps_on_an_empty_space() {
+        let addr_space = AddressSpace::new("empty");
+        assert_eq!(
+            addr_space.gaps().collect::<Vec<_>>(),
+            vec![(va(0), AddressSpace::MAX_ADDRESS)]
+        );
+        let full = AddressSpace::new("full").with_ceiling(0);
+        assert_eq!(full.gaps().count(), 0);
+    }
+
+    #[test]
+    fn gaps_around_mappings() {
+        let mut addr_space = AddressSpace::new("gaps").with_ceiling(16 * P);
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(4 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.gaps().collect::<Vec<_>>(),
+            vec![(va(0), 4 * P), (va(5 * P), 11 * P)]
+        );
+
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(3 * P), 0, 3 * P, va(9 * P), R)
+            .unwrap();
+        let free: usize = addr_space.gaps().map(|(_, len)| len).sum();
+        assert_eq!(free + addr_space.total_mapped_bytes(), 16 * P);
+        assert_eq!(
+            addr_space.gaps().map(|(_, len)| len).max(),
+            Some(addr_space.largest_free_gap())
+        );
+
+        // reservations aren't free either
+        addr_space.reserve_at(va(13 * P), P).unwrap();
+        assert_eq!(
+            addr_space.gaps().collect::<Vec<_>>(),
+            vec![
+                (va(2 * P), 2 * P),
+                (va(5 * P), 4 * P),
+                (va(12 * P), P),
+                (va(14 * P), 2 * P)
+            ]
+        );
+    }
+
+    #[test]
+    fn next_fit_packs_like_first_fit_without_rescanning() {
+        let scanned = || RANGES_SCANNED.with(core::cell::Cell::get);
+        let mut layouts = Vec::new();
+        let mut scans = Vec::new();
+        for policy in [AllocPolicy::FirstFit, AllocPolicy::NextFit] {
+            let mut addr_space = AddressSpace::new("sequential").with_policy(policy);
+            let before = scanned();
+            for _ in 0..200 {
+                addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+            }
+            scans.push(scanned() - before);
+            layouts.push(layout(&addr_space));
+        }
+        assert_eq!(layouts[0], layouts[1]);
+        assert_eq!(layouts[1][199], (va(199 * P), P, 0, R));
+        // first fit looks at every earlier mapping each time, and next fit at one or so
+        assert_eq!(scans[0], (0..200).sum::<usize>());
+        assert!(scans[1] <= 200, "next fit scanned {}", scans[1]);
+    }
+
+    #[test]
+    fn next_fit_wraps_around() {
+        let mut addr_space = AddressSpace::new("wrap")
+            .with_ceiling(8 * P)
+            .with_policy(AllocPolicy::NextFit);
+        for _ in 0..4 {
+            addr_space.add_mapping(memory(2 * P), 0, 2 * P, R).unwrap();
+        }
+        addr_space.remove_mapping_at(va(2 * P)).unwrap();
+        // the freed gap is below where the last mapping ended, so it's only found by wrapping
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(2 * P)));
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(3 * P)));
+        assert_eq!(
+            addr_space.add_mapping(memory(P), 0, P, R),
+            Err(MappingError::NoSpace)
+        );
+
+        // a gap above the last allocation is preferred to a lower one
+        addr_space.remove_mapping_at(va(0)).unwrap();
+        addr_space.remove_mapping_at(va(6 * P)).unwrap();
+        addr_space.trim();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(6 * P), R)
+            .unwrap();
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(7 * P)));
+    }
+
+    #[test]
+    fn free_in_stays_inside_the_window() {
+        // free: [0, 3P), [4P, 5P), [8P, 13P)
+        let addr_space = gappy(AllocPolicy::FirstFit);
+        assert_eq!(
+            addr_space.free_in(va(0), va(16 * P), 4 * P),
+            Some(va(8 * P))
+        );
+        // the big gap is only partly inside the window
+        assert_eq!(addr_space.free_in(va(0), va(11 * P), 4 * P), None);
+        assert_eq!(addr_space.free_in(va(10 * P), va(16 * P), 4 * P), None);
+        assert_eq!(
+            addr_space.free_in(va(9 * P), va(13 * P), 4 * P),
+            Some(va(9 * P))
+        );
+        assert_eq!(addr_space.free_in(va(3 * P), va(8 * P), P), Some(va(4 * P)));
+        assert_eq!(addr_space.free_in(va(3 * P), va(8 * P), 2 * P), None);
+        assert_eq!(addr_space.free_in(va(1), va(3 * P), 2 * P), Some(va(P)));
+        assert_eq!(addr_space.free_in(va(P), va(2 * P), P + 1), None);
+    }
+
+    #[test]
+    fn trim_pulls_the_allocation_point_back() {
+        let mut addr_space = AddressSpace::new("trim").with_ceiling(16 * P);
+        assert_eq!(addr_space.highest_mapped_address(), None);
+        let bases: Vec<_> = (0..4)
+            .map(|_| addr_space.add_mapping(memory(2 * P), 0, 2 * P, R).unwrap())
+            .collect();
+        assert_eq!(bases, vec![va(0), va(2 * P), va(4 * P), va(6 * P)]);
+        assert_eq!(addr_space.highest_mapped_address(), Some(va(8 * P - 1)));
+        assert_eq!(addr_space.next_fit, va(8 * P));
+
+        addr_space.remove_mapping_at(va(6 * P)).unwrap();
+        assert_eq!(addr_space.next_fit, va(8 * P));
+        addr_space.trim();
+        assert_eq!(addr_space.next_fit, va(6 * P));
+        assert_eq!(addr_space.highest_mapped_address(), Some(va(6 * P - 1)));
+
+        // the freed tail is reused
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(6 * P)));
+        assert_eq!(addr_space.next_fit, va(7 * P));
+
+        addr_space.clear();
+        addr_space.trim();
+        assert_eq!(addr_space.next_fit, va(0));
+    }
+
+    #[test]
+    fn statistics_count_spans_and_gaps() {
+        let mut addr_space = AddressSpace::new("stats").with_ceiling(64 * P);
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(3 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, 100, va(4 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, 8 * P, va(20 * P), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(50 * P), R)
+            .unwrap();
+        assert_eq!(addr_space.mapping_count(), 4);
+        assert_eq!(addr_space.total_mapped_bytes(), 11 * P);
+        // gaps: [0, 3), [5, 20), [28, 50), [51, 64)
+        assert_eq!(addr_space.largest_free_gap(), 22 * P);
+
+        addr_space.unmap(va(0), 30 * P).unwrap();
+        assert_eq!(addr_space.mapping_count(), 1);
+        assert_eq!(addr_space.total_mapped_bytes(), P);
+        assert_eq!(addr_space.largest_free_gap(), 50 * P);
+
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), R)
+            .unwrap();
+        // now the tail is the biggest gap
+        addr_space.unmap(va(50 * P), P).unwrap();
+        assert_eq!(addr_space.largest_free_gap(), 63 * P);
+    }
+
+    #[test]
+    fn structure_bytes_follow_the_mapping_count() {
+        let mut addr_space = AddressSpace::new("footprint");
+        assert_eq!(addr_space.structure_bytes(), 0);
+        for i in 0..1000 {
+            addr_space
+                .add_mapping_at(unbounded(), 0, P, va(2 * i * P), R)
+                .unwrap();
+        }
+        let full = addr_space.structure_bytes();
+        assert!(full >= 1000 * core::mem::size_of::<MapEntry>());
+
+        addr_space.unmap(va(20 * P), 2000 * P).unwrap();
+        assert_eq!(addr_space.mapping_count(), 10);
+        let mut fresh = AddressSpace::new("fresh");
+        for i in 0..10 {
+            fresh
+                .add_mapping_at(unbounded(), 0, P, va(2 * i * P), R)
+                .unwrap();
+        }
+        assert_eq!(addr_space.structure_bytes(), fresh.structure_bytes());
+        assert!(addr_space.structure_bytes() * 50 < full);
+        addr_space.clear();
+        assert_eq!(addr_space.structure_bytes(), 0);
+    }
+
+    /// Reserve `[start, start + span)`, with a handler that maps one page of fresh memory per
+    /// fault and records where each fault happened.
+    fn demand_paged(
+        start: VirtualAddress,
+        span: usize,
+    ) -> (AddressSpace, Arc<Mutex<Vec<VirtualAddress>>>) {
+        let mut addr_space = AddressSpace::new("demand paged");
+        addr_space.reserve_at(start, span).unwrap();
+        let faults = Arc::new(Mutex::new(Vec::new()));
+        let seen = faults.clone();
+        addr_space.on_fault(move |addr_space, addr| {
+            seen.lock().push(addr);
+            let page = addr.align_down(P);
+            addr_space.add_mapping_at(memory(P), 0, P, page, RW)
+        });
+        (addr_space, faults)
+    }
+
+    #[test]
+    fn read_faults_in_reserved_pages() {
+        let (addr_space, faults) = demand_paged(va(4 * P), 8 * P);
+        let mut buf = vec![0; 2 * P];
+        addr_space.read(va(5 * P + 16), &mut buf).unwrap();
+        // one fault per page touched, each at the first byte accessed in that page
+        assert_eq!(*faults.lock(), vec![va(5 * P + 16), va(6 * P), va(7 * P)]);
+        assert_eq!(&buf[..P - 16], &pattern(16, P - 16)[..]);
+        assert_eq!(&buf[P - 16..2 * P - 16], &pattern(0, P)[..]);
+        assert_eq!(addrs(&addr_space), vec![va(5 * P), va(6 * P), va(7 * P)]);
+
+        // the pages are mapped now, so reading again doesn't fault
+        addr_space.read(va(5 * P + 16), &mut buf).unwrap();
+        assert_eq!(faults.lock().len(), 3);
+    }
+
+    #[test]
+    fn write_faults_in_reserved_pages() {
+        let (mut addr_space, faults) = demand_paged(va(0), 2 * P);
+        addr_space.write(va(P - 2), &[9; 4]).unwrap();
+        assert_eq!(*faults.lock(), vec![va(P - 2), va(P)]);
+        let mut buf = [0; 4];
+        addr_space.read(va(P - 2), &mut buf).unwrap();
+        assert_eq!(buf, [9; 4]);
+    }
+
+    #[test]
+    fn faults_outside_reservations_are_not_handled() {
+        let (addr_space, faults) = demand_paged(va(P), P);
+        let mut buf = [0; 8];
+        assert_eq!(
+            addr_space.read(va(2 * P), &mut buf),
+            Err(MappingError::Fault { addr: va(2 * P) })
+        );
+        // the reserved page is mapped, but the read runs off its end
+        assert_eq!(
+            addr_space.read(va(2 * P - 4), &mut buf),
+            Err(MappingError::Fault { addr: va(2 * P) })
+        );
+        assert_eq!(*faults.lock(), vec![va(2 * P - 4)]);
+    }
+
+    #[test]
+    fn fault_handler_that_maps_nothing() {
+        let mut addr_space = AddressSpace::new("lazy handler");
+        addr_space.reserve_at(va(0), P).unwrap();
+        let mut buf = [0; 8];
+        // reserved, but without a handler
+        assert_eq!(
+            addr_space.read(va(0), &mut buf),
+            Err(MappingError::Fault { addr: va(0) })
+        );
+        addr_space.on_fault(|_, _| Ok(()));
+        assert_eq!(
+            addr_space.read(va(0), &mut buf),
+            Err(MappingError::Fault { addr: va(0) })
+        );
+        addr_space.on_fault(|_, addr| Err(MappingError::NoSpace));
+        assert_eq!(addr_space.write(va(0), &buf), Err(MappingError::NoSpace));
+    }
+
+    #[test]
+    fn reserve_rejects_bad_ranges() {
+        let mut addr_space = AddressSpace::new("reserve").with_ceiling(8 * P);
+        addr_space.reserve_at(va(2 * P), 2 * P).unwrap();
+        assert_eq!(
+            addr_space.reserve_at(va(P / 2), P),
+            Err(MappingError::Unaligned { addr: va(P / 2) })
+        );
+        assert_eq!(
+            addr_space.reserve_at(va(7 * P), 2 * P),
+            Err(MappingError::OutOfRange { addr: va(7 * P) })
+        );
+        assert_eq!(
+            addr_space.reserve_at(va(P), 2 * P),
+            Err(MappingError::Overlap { addr: va(2 * P) })
+        );
+        assert_eq!(
+            addr_space.reserve_at(va(3 * P), P),
+            Err(MappingError::Overlap { addr: va(2 * P) })
+        );
+        addr_space.reserve_at(va(4 * P), P).unwrap();
+    }
+
+    #[test]
+    fn contains_and_mapping_at() {
+        let mut addr_space = AddressSpace::new("queries");
+        let src = memory(4 * P);
+        addr_space
+            .add_mapping_at(src.clone(), P, 2 * P, va(2 * P), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(4 * P), R)
+            .unwrap();
+
+        assert!(!addr_space.contains(va(2 * P - 1)));
+        assert!(addr_space.mapping_at(va(2 * P - 1)).is_none());
+
+        for addr in [2 * P, 3 * P + 5, 4 * P - 1] {
+            assert!(addr_space.contains(va(addr)));
+            let mapping = addr_space.mapping_at(va(addr)).unwrap();
+            assert_eq!(
+                (mapping.addr, mapping.span, mapping.offset, mapping.perms),
+                (va(2 * P), 2 * P, P, RW)
+            );
+            assert!(Arc::ptr_eq(mapping.source.as_ref().unwrap(), &src));
+        }
+
+        // the next mapping starts right where the first ends
+        assert_eq!(addr_space.mapping_at(va(4 * P)).unwrap().addr, va(4 * P));
+        assert!(!addr_space.contains(va(5 * P)));
+    }
+
+    #[test]
+    fn remove_mapping_at_returns_the_mapping() {
+        let mut addr_space = AddressSpace::new("remove at");
+        let maps = three_mappings(&mut addr_space);
+        let removed = addr_space.remove_mapping_at(maps[1].1).unwrap();
+        assert_eq!(removed.addr, maps[1].1);
+        assert_eq!((removed.span, removed.offset, removed.perms), (P, 0, R));
+        assert!(Arc::ptr_eq(removed.source.as_ref().unwrap(), &maps[1].0));
+        assert_eq!(addrs(&addr_space), vec![maps[0].1, maps[2].1]);
+
+        assert_eq!(
+            addr_space.remove_mapping_at(maps[1].1).err(),
+            Some(MappingError::NotFound { addr: maps[1].1 })
+        );
+        // only the base address identifies a mapping
+        assert_eq!(
+            addr_space.remove_mapping_at(maps[0].1 + 1).err(),
+            Some(MappingError::NotFound {
+                addr: maps[0].1 + 1
+            })
+        );
+    }
+
+    #[test]
+    fn grow_into_free_space() {
+        let mut addr_space = AddressSpace::new("grow");
+        addr_space
+            .add_mapping_at(memory(8 * P), P, P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(4 * P), R)
+            .unwrap();
+        addr_space.grow_mapping(va(0), 4 * P - 10).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), 4 * P, P, RW), (va(4 * P), P, 0, R)]
+        );
+        let mut buf = vec![0; 2 * P];
+        addr_space.read(va(2 * P), &mut buf).unwrap();
+        assert_eq!(buf, pattern(3 * P, 2 * P));
+
+        // growing to the current span or less does nothing
+        addr_space.grow_mapping(va(0), P).unwrap();
+        assert_eq!(addr_space.mapping_at(va(0)).unwrap().span, 4 * P);
+    }
+
+    #[test]
+    fn grow_stops_at_reservations_and_aliases() {
+        let mut addr_space = AddressSpace::new("grow reserved").with_allow_aliasing(false);
+        let shared = memory(8 * P);
+        addr_space
+            .add_mapping_at(shared.clone(), 0, P, va(0), RW)
+            .unwrap();
+        addr_space.reserve_at(va(4 * P), 2 * P).unwrap();
+        // right up to the reservation is fine, but not into it
+        addr_space.grow_mapping(va(0), 4 * P).unwrap();
+        assert_eq!(
+            addr_space.grow_mapping(va(0), 5 * P),
+            Err(MappingError::NoSpace)
+        );
+
+        addr_space
+            .add_mapping_at(shared, 6 * P, P, va(8 * P), R)
+            .unwrap();
+        addr_space.shrink_mapping(va(0), 2 * P).unwrap();
+        addr_space.reserved.clear();
+        assert_eq!(
+            addr_space.grow_mapping(va(0), 7 * P),
+            Err(MappingError::Aliased { addr: va(8 * P) })
+        );
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), 2 * P, 0, RW), (va(8 * P), P, 6 * P, R)]
+        );
+    }
+
+    #[test]
+    fn grow_blocked() {
+        let mut addr_space = AddressSpace::new("grow blocked").with_ceiling(16 * P);
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(2 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(8 * P), 0, P, va(12 * P), R)
+            .unwrap();
+        // by a neighbour
+        assert_eq!(
+            addr_space.grow_mapping(va(0), 3 * P),
+            Err(MappingError::NoSpace)
+        );
+        // by the ceiling
+        assert_eq!(
+            addr_space.grow_mapping(va(12 * P), 5 * P),
+            Err(MappingError::NoSpace)
+        );
+        // by the end of the source
+        assert_eq!(
+            addr_space.grow_mapping(va(2 * P), 2 * P),
+            Err(MappingError::SourceTooSmall {
+                source_len: P,
+                needed: 2 * P
+            })
+        );
+        assert_eq!(
+            addr_space.grow_mapping(va(5 * P), 2 * P),
+            Err(MappingError::NotFound { addr: va(5 * P) })
+        );
+
+        assert_eq!(
+            layout(&addr_space),
+            vec![
+                (va(0), P, 0, RW),
+                (va(2 * P), P, 0, R),
+                (va(12 * P), P, 0, R)
+            ]
+        );
+    }
+
+    #[test]
+    fn shrink_frees_space_for_reuse() {
+        let mut addr_space = AddressSpace::new("shrink");
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, 4 * P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(4 * P), R)
+            .unwrap();
+        addr_space.shrink_mapping(va(0), P + 1).unwrap();
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), 2 * P, 0, RW), (va(4 * P), P, 0, R)]
+        );
+        assert!(!addr_space.contains(va(2 * P)));
+        assert_eq!(
+            addr_space.add_mapping(memory(2 * P), 0, 2 * P, R),
+            Ok(va(2 * P))
+        );
+
+        addr_space.shrink_mapping(va(4 * P), 0).unwrap();
+        assert!(!addr_space.contains(va(4 * P)));
+        assert_eq!(
+            addr_space.shrink_mapping(va(4 * P), P),
+            Err(MappingError::NotFound { addr: va(4 * P) })
+        );
+    }
+
+    #[test]
+    fn move_mapping_keeps_source_and_offset() {
+        let mut addr_space = AddressSpace::new("move");
+        let src = memory(4 * P);
+        addr_space
+            .add_mapping_at(src.clone(), P, 2 * P, va(0), RW)
+            .unwrap();
+        addr_space.move_mapping(va(0), va(8 * P)).unwrap();
+        assert!(addr_space.translate(va(0)).is_none());
+        let (found, offset) = addr_space.translate(va(8 * P + 5)).unwrap();
+        assert!(Arc::ptr_eq(&found, &src));
+        assert_eq!(offset, P + 5);
+        assert_eq!(layout(&addr_space), vec![(va(8 * P), 2 * P, P, RW)]);
+
+        // onto part of its own old range
+        addr_space.move_mapping(va(8 * P), va(9 * P)).unwrap();
+        assert_eq!(layout(&addr_space), vec![(va(9 * P), 2 * P, P, RW)]);
+    }
+
+    #[test]
+    fn move_mapping_onto_a_neighbour() {
+        let mut addr_space = AddressSpace::new("move blocked").with_ceiling(8 * P);
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(4 * P), R)
+            .unwrap();
+        let before = layout(&addr_space);
+        assert_eq!(
+            addr_space.move_mapping(va(4 * P), va(0)),
+            Err(MappingError::Overlap { addr: va(0) })
+        );
+        assert_eq!(
+            addr_space.move_mapping(va(4 * P), va(7 * P)),
+            Err(MappingError::OutOfRange { addr: va(7 * P) })
+        );
+        assert_eq!(
+            addr_space.move_mapping(va(P), va(2 * P)),
+            Err(MappingError::NotFound { addr: va(P) })
+        );
+        assert_eq!(layout(&addr_space), before);
+    }
+
+    #[test]
+    fn move_mapping_auto_finds_a_new_home() {
+        let mut addr_space = AddressSpace::new("move auto").with_ceiling(4 * P);
+        let src = memory(2 * P);
+        addr_space
+            .add_mapping_at(src.clone(), 0, 2 * P, va(2 * P), R)
+            .unwrap();
+        assert_eq!(addr_space.move_mapping_auto(va(2 * P)), Ok(va(0)));
+        assert!(!addr_space.contains(va(2 * P)));
+        assert!(Arc::ptr_eq(&addr_space.translate(va(P)).unwrap().0, &src));
+
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(2 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.move_mapping_auto(va(0)),
+            Err(MappingError::NoSpace)
+        );
+        assert_eq!(
+            layout(&addr_space),
+            vec![(va(0), 2 * P, 0, R), (va(2 * P), P, 0, R)]
+        );
+    }
+
+    #[test]
+    fn merge_from_disjoint_space() {
+        let mut process = AddressSpace::new("process");
+        let src = memory(2 * P);
+        process
+            .add_mapping_at(src.clone(), 0, P, va(6 * P), RW)
+            .unwrap();
+        let mut library = AddressSpace::new("library");
+        library
+            .add_mapping_at(memory(P), 0, P, va(4 * P), R)
+            .unwrap();
+        // with its guard page at 0
+        library
+            .add_mapping_guarded(src.clone(), P, P, 1, RW)
+            .unwrap();
+
+        process.merge_from(&library).unwrap();
+        assert_eq!(
+            layout(&process),
+            vec![
+                (va(P), P, P, RW),
+                (va(4 * P), P, 0, R),
+                (va(6 * P), P, 0, RW)
+            ]
+        );
+        assert!(Arc::ptr_eq(&process.translate(va(P)).unwrap().0, &src));
+        assert_eq!(
+            process.read(va(0), &mut [0]),
+            Err(MappingError::GuardPage { addr: va(0) })
+        );
+        assert_eq!(library.mapping_count(), 2);
+    }
+
+    #[test]
+    fn merge_from_rejects_overlaps() {
+        let mut process = AddressSpace::new("process");
+        process.add_mapping_at(memory(P), 0, P, va(0), RW).unwrap();
+        process
+            .add_mapping_at(memory(P), 0, P, va(3 * P), RW)
+            .unwrap();
+        let mut library = AddressSpace::new("library");
+        library.add_mapping_at(memory(P), 0, P, va(P), R).unwrap();
+        library
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(2 * P), R)
+            .unwrap();
+
+        assert_eq!(
+            process.merge_from(&library),
+            Err(MappingError::Overlap { addr: va(3 * P) })
+        );
+        assert_eq!(addrs(&process), vec![va(0), va(3 * P)]);
+    }
+
+    #[test]
+    fn clear_removes_everything() {
+        let mut addr_space = AddressSpace::new("clear");
+        assert!(addr_space.is_empty());
+        let maps = three_mappings(&mut addr_space);
+        assert!(!addr_space.is_empty());
+        let counts: Vec<usize> = maps.iter().map(|(src, _)| Arc::strong_count(src)).collect();
+
+        addr_space.define_segment("first", maps[0].1, P).unwrap();
+
+        addr_space.clear();
+        assert_eq!(addr_space.mapping_count(), 0);
+        assert!(addr_space.is_empty());
+        for ((src, _), count) in maps.iter().zip(counts) {
+            assert_eq!(Arc::strong_count(src), count - 1);
+        }
+        assert_eq!(addr_space.segment("first"), None);
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(0)));
+    }
+
+    #[test]
+    fn resident_pages_follow_moved_mappings() {
+        let (addr_space, faults) = demand_paged(va(16 * P), 4 * P);
+        let mut addr_space = addr_space.with_resident_budget(2 * P);
+        let page = |n: usize| va((16 + n) * P);
+        addr_space.read(page(0), &mut [0; 4]).unwrap();
+        addr_space.read(page(1), &mut [0; 4]).unwrap();
+        addr_space.move_mapping(page(0), va(40 * P)).unwrap();
+        assert_eq!(addr_space.resident_bytes(), 2 * P);
+
+        // the moved page was used last, so the one left behind makes way for page 2
+        addr_space.read(va(40 * P), &mut [0; 4]).unwrap();
+        addr_space.read(page(2), &mut [0; 4]).unwrap();
+        assert_eq!(*faults.lock(), vec![page(0), page(1), page(2)]);
+        assert!(addr_space.contains(va(40 * P)));
+        assert!(!addr_space.contains(page(1)));
+        assert_eq!(addr_space.resident_bytes(), 2 * P);
+        assert_eq!(addr_space.mapping_count(), 2);
+
+        // and it is evicted from where it is now
+        addr_space.read(page(3), &mut [0; 4]).unwrap();
+        assert!(!addr_space.contains(va(40 * P)));
+        assert_eq!(addr_space.mapping_count(), 2);
+    }
+
+    #[test]
+    fn clear_forgets_resident_pages() {
+        let (addr_space, faults) = demand_paged(va(4 * P), 4 * P);
+        let mut addr_space = addr_space.with_resident_budget(2 * P);
+        addr_space.read(va(4 * P), &mut [0; 2 * P]).unwrap();
+        assert_eq!(addr_space.resident_bytes(), 2 * P);
+
+        addr_space.clear();
+        assert_eq!(addr_space.resident_bytes(), 0);
+        // the budget is kept, and the pages fault in again
+        addr_space.read(va(4 * P), &mut [0; 3 * P]).unwrap();
+        assert_eq!(addr_space.resident_bytes(), 2 * P);
+        assert_eq!(faults.lock().len(), 5);
+    }
+
+    #[test]
+    fn mappings_for_source_finds_every_view() {
+        let mut addr_space = AddressSpace::new("views");
+        let shared = memory(4 * P);
+        let other = memory(P);
+        addr_space
+            .add_mapping_at(shared.clone(), 0, P, va(8 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(other.clone(), 0, P, va(4 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(shared.clone(), 2 * P, 2 * P, va(0), RW)
+            .unwrap();
+
+        let views: Vec<_> = addr_space
+            .mappings_for_source(&shared)
+            .iter()
+            .map(|info| (info.addr, info.offset))
+            .collect();
+        assert_eq!(views, vec![(va(0), 2 * P), (va(8 * P), 0)]);
+        assert_eq!(addr_space.mappings_for_source(&other).len(), 1);
+        assert!(addr_space.mappings_for_source(&memory(4 * P)).is_empty());
+    }
+
+    #[test]
+    fn range_mappings_includes_partial_overlaps() {
+        let mut addr_space = AddressSpace::new("range");
+        for base in [0, 2 * P, 4 * P, 6 * P] {
+            addr_space
+                .add_mapping_at(memory(2 * P), 0, 2 * P, va(base), R)
+                .unwrap();
+        }
+        let bases = |start, span| -> Vec<VirtualAddress> {
+            addr_space
+                .range_mappings(start, span)
+                .map(|mapping| mapping.addr)
+                .collect()
+        };
+        // starts inside one mapping and ends inside another
+        assert_eq!(bases(va(P), 4 * P), vec![va(0), va(2 * P), va(4 * P)]);
+        // exactly one mapping
+        assert_eq!(bases(va(2 * P), 2 * P), vec![va(2 * P)]);
+        // one byte either side of it
+        assert_eq!(
+            bases(va(2 * P - 1), 2 * P + 2),
+            vec![va(0), va(2 * P), va(4 * P)]
+        );
+        assert_eq!(bases(va(3 * P), 0), Vec::<VirtualAddress>::new());
+        assert_eq!(bases(va(8 * P), P), Vec::<VirtualAddress>::new());
+    }
+
+    #[test]
+    fn wx_policy_rejects_writable_executable_mappings() {
+        let rwx = RW | Permissions::EXECUTE;
+        let mut strict = AddressSpace::new("w^x").with_wx_policy(true);
+        assert_eq!(
+            strict.add_mapping(memory(P), 0, P, rwx),
+            Err(MappingError::WxViolation)
+        );
+        assert_eq!(
+            strict.add_mapping_at(memory(P), 0, P, va(0), rwx),
+            Err(MappingError::WxViolation)
+        );
+        assert!(strict.is_empty());
+
+        strict.add_mapping_at(memory(P), 0, P, va(0), RW).unwrap();
+        assert_eq!(
+            strict.protect(va(0), P, rwx),
+            Err(MappingError::WxViolation)
+        );
+        strict.protect(va(0), P, R | Permissions::EXECUTE).unwrap();
+        assert_eq!(
+            layout(&strict),
+            vec![(va(0), P, 0, R | Permissions::EXECUTE)]
+        );
+    }
+
+    #[test]
+    fn wx_policy_off_by_default() {
+        let rwx = RW | Permissions::EXECUTE;
+        let mut lax = AddressSpace::new("w+x");
+        lax.add_mapping(memory(P), 0, P, rwx).unwrap();
+        lax.add_mapping_at(memory(P), 0, P, va(P), RW).unwrap();
+        lax.protect(va(P), P, R | Permissions::EXECUTE).unwrap();
+        lax.protect(va(P), P, rwx).unwrap();
+        let mut lax = lax.with_wx_policy(false);
+        lax.protect(va(0), 2 * P, rwx).unwrap();
+    }
+
+    #[test]
+    fn guard_pages_fault_distinctly() {
+        let mut addr_space = AddressSpace::new("guarded");
+        let addr = addr_space
+            .add_mapping_guarded(memory(2 * P), 0, 2 * P, 2, RW)
+            .unwrap();
+        assert_eq!(addr, va(2 * P));
+        let mut buf = [0; 8];
+        addr_space.read(addr, &mut buf).unwrap();
+        assert!(addr_space.translate(addr - 1).is_none());
+        assert_eq!(
+            addr_space.read(addr - 4, &mut buf),
+            Err(MappingError::GuardPage { addr: addr - 4 })
+        );
+        assert_eq!(
+            addr_space.write(va(0), &buf),
+            Err(MappingError::GuardPage { addr: va(0) })
+        );
+        assert_eq!(
+            addr_space.check_access(va(P), R),
+            Err(MappingError::GuardPage { addr: va(P) })
+        );
+        // off the top is an ordinary fault
+        assert_eq!(
+            addr_space.read(addr + 2 * P, &mut buf),
+            Err(MappingError::Fault { addr: addr + 2 * P })
+        );
+    }
+
+    #[test]
+    fn guard_pages_are_not_allocated() {
+        let mut addr_space = AddressSpace::new("guarded allocation");
+        let low = addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+        let stack = addr_space
+            .add_mapping_guarded(memory(P), 0, P, 1, RW)
+            .unwrap();
+        assert_eq!((low, stack), (va(0), va(2 * P)));
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(3 * P)));
+        assert_eq!(
+            addr_space.add_mapping_at(memory(P), 0, P, va(P), R),
+            Err(MappingError::Overlap { addr: va(2 * P) })
+        );
+        assert_eq!(
+            addr_space.grow_mapping(low, 2 * P),
+            Err(MappingError::NoSpace)
+        );
+
+        // removing the mapping frees its guard page too
+        addr_space.remove_mapping_at(stack).unwrap();
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(P)));
+        addr_space.validate().unwrap();
+    }
+
+    #[test]
+    fn guard_pages_stay_at_the_bottom() {
+        let mut addr_space = AddressSpace::new("guarded split");
+        let addr = addr_space
+            .add_mapping_guarded(memory(4 * P), 0, 4 * P, 1, RW)
+            .unwrap();
+        addr_space.protect(addr + P, P, R).unwrap();
+        addr_space.validate().unwrap();
+        let mut buf = [0; 1];
+        assert_eq!(
+            addr_space.read(addr - 1, &mut buf),
+            Err(MappingError::GuardPage { addr: addr - 1 })
+        );
+        addr_space.protect(addr + P, P, RW).unwrap();
+        assert_eq!(addr_space.coalesce(), 2);
+        assert_eq!(layout(&addr_space), vec![(addr, 4 * P, 0, RW)]);
+
+        addr_space.move_mapping(addr, va(10 * P)).unwrap();
+        assert_eq!(
+            addr_space.read(va(9 * P), &mut buf),
+            Err(MappingError::GuardPage { addr: va(9 * P) })
+        );
+        assert_eq!(
+            addr_space.read(addr - 1, &mut buf),
+            Err(MappingError::Fault { addr: addr - 1 })
+        );
+    }
+
+    #[test]
+    fn into_iter_consumes_the_space() {
+        let mut addr_space = AddressSpace::new("into iter");
+        let mut inserted: Vec<VirtualAddress> = three_mappings(&mut addr_space)
+            .into_iter()
+            .map(|(_, addr)| addr)
+            .collect();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(9 * P), RW)
+            .unwrap();
+        inserted.push(va(9 * P));
+        let src = memory(P);
+        addr_space
+            .add_mapping_at(src.clone(), 0, P, va(7 * P), R)
+            .unwrap();
+        inserted.push(va(7 * P));
+        inserted.sort_unstable();
+
+        let mut bases = Vec::new();
+        for mapping in addr_space {
+            if mapping.addr == va(7 * P) {
+                assert!(Arc::ptr_eq(mapping.source.as_ref().unwrap(), &src));
+            }
+            bases.push(mapping.addr);
+        }
+        assert_eq!(bases, inserted);
+        // the space is gone, so only our reference to the source is left
+        assert_eq!(Arc::strong_count(&src), 1);
+    }
+
+    #[test]
+    fn sequential_lookups_hit_the_cache() {
+        let mut addr_space = AddressSpace::new("cached");
+        for base in [0, 4 * P, 8 * P] {
+            addr_space
+                .add_mapping_at(memory(4 * P), 0, 4 * P, va(base), RW)
+                .unwrap();
+        }
+        let misses =
+            |addr_space: &AddressSpace| addr_space.lookup_cache.misses.load(Ordering::Relaxed);
+
+        for addr in (4 * P..8 * P).step_by(64) {
+            assert_eq!(addr_space.translate(va(addr)).unwrap().1, addr - 4 * P);
+        }
+        assert_eq!(misses(&addr_space), 1);
+        let mut buf = vec![0; P];
+        addr_space.read(va(5 * P), &mut buf).unwrap();
+        addr_space.write(va(6 * P), &buf).unwrap();
+        assert_eq!(misses(&addr_space), 1);
+
+        // another mapping misses once, then hits
+        addr_space.translate(va(9 * P)).unwrap();
+        addr_space.translate(va(10 * P)).unwrap();
+        assert_eq!(misses(&addr_space), 2);
+    }
+
+    #[test]
+    fn cache_survives_mutation() {
+        let mut addr_space = AddressSpace::new("stale cache");
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, 4 * P, va(0), RW)
+            .unwrap();
+        assert!(addr_space.contains(va(3 * P)));
+        addr_space.shrink_mapping(va(0), P).unwrap();
+        assert!(!addr_space.contains(va(3 * P)));
+        assert!(addr_space.contains(va(0)));
+        addr_space.unmap(va(0), P).unwrap();
+        assert!(addr_space.translate(va(0)).is_none());
+        addr_space
+            .add_mapping_at(memory(2 * P), P, P, va(0), R)
+            .unwrap();
+        assert_eq!(addr_space.translate(va(0)).unwrap().1, P);
+    }
+
+    #[test]
+    fn compact_removes_gaps() {
+        let mut addr_space = AddressSpace::new("compact").with_ceiling(32 * P);
+        let sources: Vec<Arc<dyn DataSource>> = (0..6).map(|_| memory(2 * P)).collect();
+        for src in &sources {
+            addr_space.add_mapping(src.clone(), 0, 2 * P, R).unwrap();
+        }
+        for base in [0, 4 * P, 8 * P] {
+            addr_space.remove_mapping_at(va(base)).unwrap();
+        }
+        let gap_before = addr_space.largest_free_gap();
+
+        assert_eq!(addr_space.compact(), 3);
+        assert_eq!(
+            layout(&addr_space),
+            vec![
+                (va(0), 2 * P, 0, R),
+                (va(2 * P), 2 * P, 0, R),
+                (va(4 * P), 2 * P, 0, R)
+            ]
+        );
+        assert!(addr_space.largest_free_gap() > gap_before);
+        assert_eq!(addr_space.largest_free_gap(), 26 * P);
+        // order is preserved
+        for (mapping, src) in addr_space.iter().zip([1, 3, 5]) {
+            assert!(Arc::ptr_eq(mapping.source.as_ref().unwrap(), &sources[src]));
+        }
+        assert_eq!(addr_space.compact(), 0);
+    }
+
+    #[test]
+    fn compact_keeps_guard_pages() {
+        let mut addr_space = AddressSpace::new("compact guarded");
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(8 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), R)
+            .unwrap();
+        addr_space
+            .add_mapping_guarded(memory(P), 0, P, 2, RW)
+            .unwrap();
+        addr_space.remove_mapping_at(va(0)).unwrap();
+        assert_eq!(addrs(&addr_space), vec![va(3 * P), va(8 * P)]);
+
+        assert_eq!(addr_space.compact(), 2);
+        assert_eq!(addrs(&addr_space), vec![va(2 * P), va(3 * P)]);
+        let mut buf = [0; 1];
+        assert_eq!(
+            addr_space.read(va(P), &mut buf),
+            Err(MappingError::GuardPage { addr: va(P) })
+        );
+    }
+
+    #[test]
+    fn compact_packs_around_pinned_mappings() {
+        let mut addr_space = AddressSpace::new("compact pinned").with_ceiling(32 * P);
+        for base in [2, 6, 10, 14] {
+            addr_space
+                .add_mapping_at(memory(P), 0, P, va(base * P), R)
+                .unwrap();
+        }
+        addr_space.pin(va(10 * P)).unwrap();
+        assert_eq!(addr_space.compact(), 3);
+        assert_eq!(
+            addrs(&addr_space),
+            vec![va(0), va(P), va(10 * P), va(11 * P)]
+        );
+
+        addr_space.unpin(va(10 * P)).unwrap();
+        assert_eq!(addr_space.compact(), 2);
+        assert_eq!(addrs(&addr_space), vec![va(0), va(P), va(2 * P), va(3 * P)]);
+        assert_eq!(
+            addr_space.pin(va(5 * P)),
+            Err(MappingError::NotFound { addr: va(5 * P) })
+        );
+    }
+
+    #[test]
+    fn compact_steps_over_reservations() {
+        let mut addr_space = AddressSpace::new("compact reserved")
+            .with_ceiling(64 * P)
+            .with_policy(AllocPolicy::NextFit);
+        addr_space.reserve_at(va(P), P).unwrap();
+        let bases: Vec<_> = (0..4)
+            .map(|_| addr_space.add_mapping(memory(P), 0, P, R).unwrap())
+            .collect();
+        assert_eq!(bases, [va(0), va(2 * P), va(3 * P), va(4 * P)]);
+        addr_space.define_segment("data", va(4 * P), P).unwrap();
+        addr_space.remove_mapping_at(va(0)).unwrap();
+        addr_space.remove_mapping_at(va(3 * P)).unwrap();
+
+        assert_eq!(addr_space.compact(), 2);
+        // the second mapping doesn't fit between the first and the reservation
+        assert_eq!(addrs(&addr_space), vec![va(0), va(2 * P)]);
+        assert_eq!(
+            addr_space
+                .segment("data")
+                .map(|segment| (segment.start, segment.span)),
+            Some((va(2 * P), P))
+        );
+        // placing carries on from the packed mappings, not from where they used to end
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(3 * P)));
+    }
+
+    #[test]
+    fn compact_moves_resident_pages() {
+        let (addr_space, faults) = demand_paged(va(16 * P), 4 * P);
+        let mut addr_space = addr_space.with_resident_budget(2 * P);
+        let page = |n: usize| va((16 + n) * P);
+        addr_space.read(page(0), &mut [0; 4]).unwrap();
+        addr_space.move_mapping(page(0), va(40 * P)).unwrap();
+        addr_space.read(page(1), &mut [0; 4]).unwrap();
+
+        // the faulted page stays in its reservation, and the moved one packs in after it
+        assert_eq!(addr_space.compact(), 1);
+        assert_eq!(addrs(&addr_space), vec![page(1), va(20 * P)]);
+        addr_space.read(va(20 * P), &mut [0; 4]).unwrap();
+        addr_space.read(page(2), &mut [0; 4]).unwrap();
+        assert_eq!(*faults.lock(), vec![page(0), page(1), page(2)]);
+        assert_eq!(addrs(&addr_space), vec![page(2), va(20 * P)]);
+        assert_eq!(addr_space.resident_bytes(), 2 * P);
+    }
+
+    #[test]
+    fn pinned_mappings_refuse_to_move() {
+        let mut addr_space = AddressSpace::new("pinned");
+        let library = memory(2 * P);
+        addr_space
+            .add_mapping_at(library.clone(), 0, P, va(4 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(library.clone(), P, P, va(6 * P), R)
+            .unwrap();
+        addr_space.pin(va(6 * P)).unwrap();
+        let pinned = Err(MappingError::Pinned { addr: va(6 * P) });
+        assert_eq!(addr_space.move_mapping(va(6 * P), va(8 * P)), pinned);
+        assert_eq!(
+            addr_space.move_mapping_auto(va(6 * P)),
+            pinned.map(|()| va(0))
+        );
+        assert_eq!(addr_space.rebase(&library, (P * 16).cast_signed()), pinned);
+        assert_eq!(addrs(&addr_space), vec![va(4 * P), va(6 * P)]);
+
+        // pieces of a pinned mapping stay pinned
+        addr_space.unmap(va(4 * P), P).unwrap();
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), R)
+            .unwrap();
+        addr_space.pin(va(0)).unwrap();
+        addr_space.protect(va(P), P, RW).unwrap();
+        assert_eq!(
+            addr_space.move_mapping(va(P), va(16 * P)),
+            Err(MappingError::Pinned { addr: va(P) })
+        );
+    }
+
+    /// A space with free gaps of 3, 1 and 5 pages, in that order, and then nothing.
+    fn gappy(policy: AllocPolicy) -> AddressSpace {
+        let mut addr_space = AddressSpace::new("gappy")
+            .with_ceiling(16 * P)
+            .with_policy(policy);
+        for (base, span) in [(3 * P, P), (5 * P, 3 * P), (13 * P, 3 * P)] {
+            addr_space
+                .add_mapping_at(memory(span), 0, span, va(base), R)
+                .unwrap();
+        }
+        addr_space
+    }
+
+    #[test]
+    fn builder_applies_every_option() {
+        let mut addr_space = AddressSpace::builder()
+            .name("built")
+            .ceiling(16 * P)
+            .policy(AllocPolicy::BestFit)
+            .direction(AllocDirection::TopDown)
+            .wx_policy(true)
+            .max_mappings(4)
+            .resident_budget(P)
+            .build();
+        assert_eq!(addr_space.name(), "built");
+        for (base, span) in [(3 * P, P), (5 * P, 3 * P), (13 * P, 3 * P)] {
+            addr_space
+                .add_mapping_at(memory(span), 0, span, va(base), R)
+                .unwrap();
+        }
+        // best fit picks [0, 3P) over [8P, 13P), and top-down the top of it
+        assert_eq!(
+            addr_space.add_mapping(memory(2 * P), 0, 2 * P, R),
+            Ok(va(P))
+        );
+        assert_eq!(
+            addr_space.add_mapping(memory(P), 0, P, R),
+            Err(MappingError::TooManyMappings)
+        );
+        addr_space.remove_mapping_at(va(P)).unwrap();
+        assert_eq!(
+            addr_space.add_mapping(memory(P), 0, P, RW | Permissions::EXECUTE),
+            Err(MappingError::WxViolation)
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(memory(P), 0, P, va(16 * P), R),
+            Err(MappingError::OutOfRange { addr: va(16 * P) })
+        );
+    }
+
+    #[test]
+    fn builder_defaults_match_new() {
+        let built = AddressSpace::builder().build();
+        assert_eq!(built.name(), "");
+        assert_eq!(built.largest_free_gap(), AddressSpace::MAX_ADDRESS);
+        assert_eq!(built.find_free(P), Some(va(0)));
+    }
+
+    /// A read of `[P, 5P)` in a space with `[2P, 3P)` mapped and guard pages below `[6P, 7P)`.
+    fn read_across_a_gap(policy: UnmappedReadPolicy) -> (Result<(), MappingError>, Vec<u8>) {
+        let mut addr_space = AddressSpace::new("gaps").with_unmapped_read_policy(policy);
+        addr_space
+            .add_mapping_at(Arc::new(MemorySource::new(vec![1; P])), 0, P, va(2 * P), R)
+            .unwrap();
+        let mut buf = vec![0xee; 4 * P];
+        let result = addr_space.read(va(P), &mut buf);
+        (result, buf)
+    }
+
+    #[test]
+    fn unmapped_reads_fault_by_default() {
+        let (result, buf) = read_across_a_gap(UnmappedReadPolicy::default());
+        assert_eq!(result, Err(MappingError::Fault { addr: va(P) }));
+        assert!(buf.iter().all(|&byte| byte == 0xee));
+    }
+
+    #[test]
+    fn unmapped_reads_can_be_zero_filled() {
+        let (result, buf) = read_across_a_gap(UnmappedReadPolicy::ZeroFill);
+        assert_eq!(result, Ok(()));
+        assert_eq!(buf[..P], [0; P]);
+        assert_eq!(buf[P..2 * P], [1; P]);
+        assert_eq!(buf[2 * P..], [0; 2 * P]);
+    }
+
+    #[test]
+    fn unmapped_reads_can_be_skipped() {
+        let (result, buf) = read_across_a_gap(UnmappedReadPolicy::Skip);
+        assert_eq!(result, Ok(()));
+        assert_eq!(buf[..P], [0xee; P]);
+        assert_eq!(buf[P..2 * P], [1; P]);
+        assert_eq!(buf[2 * P..], [0xee; 2 * P]);
+
+        // guard pages still fault
+        let mut addr_space =
+            AddressSpace::new("guarded").with_unmapped_read_policy(UnmappedReadPolicy::Skip);
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), R)
+            .unwrap();
+        let base = addr_space
+            .add_mapping_guarded(memory(P), 0, P, 1, R)
+            .unwrap();
+        assert_eq!(base, va(2 * P));
+        let mut buf = [0; 8];
+        assert_eq!(
+            addr_space.read(va(P - 4), &mut buf),
+            Err(MappingError::GuardPage { addr: va(P) })
+        );
+    }
+
+    #[test]
+    fn fragmentation_of_empty_and_full_spaces() {
+        assert_eq!(AddressSpace::new("empty").fragmentation(), 0.0);
+        let mut full = AddressSpace::new("full").with_ceiling(2 * P);
+        full.add_mapping(memory(2 * P), 0, 2 * P, R).unwrap();
+        assert_eq!(full.fragmentation(), 0.0);
+        // one gap, wherever it is
+        full.unmap(va(0), P).unwrap();
+        assert_eq!(full.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn scattered_holes_are_fragmented() {
+        let mut addr_space = AddressSpace::new("holes").with_ceiling(64 * P);
+        addr_space
+            .add_mapping(memory(64 * P), 0, 64 * P, R)
+            .unwrap();
+        for hole in 0..32 {
+            addr_space.unmap(va(2 * hole * P), P).unwrap();
+        }
+        let fragmentation = addr_space.fragmentation();
+        assert!(
+            (fragmentation - 31.0 / 32.0).abs() < 1e-9,
+            "{fragmentation}"
+        );
+
+        // two holes, one three times the size of the other
+        let mut addr_space = AddressSpace::new("two holes").with_ceiling(8 * P);
+        addr_space.add_mapping(memory(8 * P), 0, 8 * P, R).unwrap();
+        addr_space.unmap(va(0), P).unwrap();
+        addr_space.unmap(va(4 * P), 3 * P).unwrap();
+        assert!((addr_space.fragmentation() - 0.25).abs() < 1e-9);
+    }
+
+    #[test]
+    fn mappings_are_found_by_tag() {
+        let mut addr_space = AddressSpace::new("tagged");
+        let heap = addr_space
+            .add_mapping_tagged(memory(2 * P), 0, 2 * P, RW, "malloc")
+            .unwrap();
+        addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+        addr_space
+            .add_mapping_tagged(memory(P), 0, P, R, "loader")
+            .unwrap();
+        let arena = addr_space
+            .add_mapping_tagged(memory(P), 0, P, RW, "malloc")
+            .unwrap();
+
+        let found: Vec<_> = addr_space
+            .mappings_with_tag("malloc")
+            .map(|mapping| mapping.addr)
+            .collect();
+        assert_eq!(found, [heap, arena]);
+        assert_eq!(addr_space.mappings_with_tag("mmap").count(), 0);
+        assert_eq!(
+            addr_space.mapping_at(heap).unwrap().tag.as_deref(),
+            Some("malloc")
+        );
+        assert_eq!(
+            addr_space
+                .iter()
+                .filter(|mapping| mapping.tag.is_none())
+                .count(),
+            1
+        );
+
+        // both halves of a split keep the tag
+        addr_space.protect(heap + P, P, R).unwrap();
+        assert_eq!(addr_space.mappings_with_tag("malloc").count(), 3);
+    }
+
+    #[test]
+    fn aslr_is_random_but_reproducible() {
+        let ceiling = 64 * P;
+        let build = |seed| {
+            let mut addr_space = AddressSpace::new("aslr")
+                .with_ceiling(ceiling)
+                .with_aslr(seed);
+            for span in [P, 4 * P, 2 * P, 8 * P, P] {
+                addr_space.add_mapping(memory(span), 0, span, R).unwrap();
+            }
+            addr_space
+        };
+        for seed in 0..20 {
+            let addr_space = build(seed);
+            assert_eq!(layout(&addr_space), layout(&build(seed)));
+            assert_eq!(addr_space.validate(), Ok(()));
+            assert!(addr_space
+                .iter()
+                .all(|mapping| mapping.addr.is_aligned(P)
+                    && mapping.addr + mapping.span <= va(ceiling)));
+        }
+        let firsts: Vec<_> = (0..20).map(|seed| addrs(&build(seed))).collect();
+        assert!(firsts.iter().any(|addrs| *addrs != firsts[0]));
+
+        // with exactly one place left, that's where it goes
+        let mut full = AddressSpace::new("full").with_ceiling(4 * P).with_aslr(7);
+        full.add_mapping_at(memory(P), 0, P, va(0), R).unwrap();
+        full.add_mapping_at(memory(2 * P), 0, 2 * P, va(2 * P), R)
+            .unwrap();
+        assert_eq!(full.find_free(P), Some(va(P)));
+        assert_eq!(full.find_free(2 * P), None);
+        assert_eq!(full.find_free_aligned(P, 2 * P), None);
+    }
+
+    #[test]
+    fn policies_choose_different_gaps() {
+        assert_eq!(gappy(AllocPolicy::FirstFit).find_free(P), Some(va(0)));
+        assert_eq!(gappy(AllocPolicy::BestFit).find_free(P), Some(va(4 * P)));
+        assert_eq!(gappy(AllocPolicy::WorstFit).find_free(P), Some(va(8 * P)));
+
+        // the gaps that fit 2 pages are the first and last
+        assert_eq!(gappy(AllocPolicy::FirstFit).find_free(2 * P), Some(va(0)));
+        assert_eq!(gappy(AllocPolicy::BestFit).find_free(2 * P), Some(va(0)));
+        assert_eq!(
+            gappy(AllocPolicy::WorstFit).find_free(2 * P),
+            Some(va(8 * P))
+        );
+
+        for policy in [
+            AllocPolicy::FirstFit,
+            AllocPolicy::BestFit,
+            AllocPolicy::WorstFit,
+        ] {
+            assert_eq!(gappy(policy).find_free(6 * P), None);
+        }
+    }
+
+    #[test]
+    fn add_mapping_follows_policy() {
+        let mut addr_space = gappy(AllocPolicy::BestFit);
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(4 * P)));
+        // the 1-page gap is gone, so the 3-page one is now the best fit
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(0)));
+        assert_eq!(
+            gappy(AllocPolicy::WorstFit).add_mapping(memory(P), 0, P, R),
+            Ok(va(8 * P))
+        );
+    }
+
+    #[test]
+    fn top_down_starts_below_the_ceiling() {
+        let mut addr_space = AddressSpace::new("top down").with_direction(AllocDirection::TopDown);
+        let top = va(AddressSpace::MAX_ADDRESS);
+        assert_eq!(addr_space.add_mapping(memory(P), 0, 100, R), Ok(top - P));
+        assert_eq!(
+            addr_space.add_mapping(memory(2 * P), 0, 2 * P, R),
+            Ok(top - 3 * P)
+        );
+        assert_eq!(
+            addr_space.find_free_aligned(P, 1 << 21),
+            Some(top - (1 << 21))
+        );
+        addr_space.validate().unwrap();
+    }
+
+    #[test]
+    fn top_down_fills_gaps_from_the_top() {
+        let mut addr_space = gappy(AllocPolicy::FirstFit).with_direction(AllocDirection::TopDown);
+        // gaps are [0, 3), [4, 5) and [8, 13)
+        assert_eq!(addr_space.find_free(P), Some(va(12 * P)));
+        assert_eq!(addr_space.find_free(5 * P), Some(va(8 * P)));
+        assert_eq!(addr_space.find_free(6 * P), None);
+        addr_space = addr_space.with_policy(AllocPolicy::BestFit);
+        assert_eq!(addr_space.find_free(P), Some(va(4 * P)));
+        assert_eq!(addr_space.find_free(2 * P), Some(va(P)));
+        addr_space = addr_space.with_policy(AllocPolicy::WorstFit);
+        assert_eq!(addr_space.find_free(P), Some(va(12 * P)));
+    }
+
+    #[test]
+    fn top_down_in_a_full_space() {
+        let mut addr_space = AddressSpace::new("tiny top down")
+            .with_ceiling(2 * P)
+            .with_direction(AllocDirection::TopDown);
+        assert_eq!(
+            addr_space.add_mapping(memory(2 * P), 0, 2 * P, R),
+            Ok(va(0))
+        );
+        assert_eq!(addr_space.find_free(P), None);
+        assert_eq!(
+            AddressSpace::new("x")
+                .with_ceiling(P)
+                .with_direction(AllocDirection::TopDown)
+                .find_free(2 * P),
+            None
+        );
+    }
+
+    #[test]
+    fn reserve_then_commit() {
+        let mut addr_space = AddressSpace::new("reserve and commit");
+        let reserved = addr_space.reserve(3 * P).unwrap();
+        assert_eq!(reserved, va(0));
+        let mut buf = [0; 8];
+        assert_eq!(
+            addr_space.read(reserved + P, &mut buf),
+            Err(MappingError::Fault { addr: reserved + P })
+        );
+        // the allocator steers clear of the reservation
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(3 * P)));
+        assert_eq!(addr_space.reserve(P), Ok(va(4 * P)));
+
+        let src = memory(4 * P);
+        addr_space.commit(reserved, src.clone(), P, RW).unwrap();
+        addr_space.read(reserved + P, &mut buf).unwrap();
+        assert_eq!(buf.to_vec(), pattern(2 * P, 8));
+        assert_eq!(layout(&addr_space)[0], (va(0), 3 * P, P, RW));
+        assert_eq!(
+            addr_space.commit(reserved, src, 0, RW),
+            Err(MappingError::NotFound { addr: reserved })
+        );
+    }
+
+    #[test]
+    fn commit_needs_a_reservation() {
+        let mut addr_space = AddressSpace::new("commit nothing");
+        let base = addr_space.reserve(2 * P).unwrap();
+        assert_eq!(
+            addr_space.commit(base + P, memory(P), 0, R),
+            Err(MappingError::NotFound { addr: base + P })
+        );
+        assert_eq!(
+            addr_space.commit(va(4 * P), memory(P), 0, R),
+            Err(MappingError::NotFound { addr: va(4 * P) })
+        );
+        // part of the reservation is already mapped, so it stays reserved
+        addr_space
+            .add_mapping_at(memory(P), 0, P, base + P, R)
+            .unwrap();
+        assert_eq!(
+            addr_space.commit(base, memory(2 * P), 0, R),
+            Err(MappingError::Overlap { addr: base + P })
+        );
+        assert_eq!(addr_space.find_free(P), Some(va(2 * P)));
+    }
+
+    #[test]
+    fn with_ceiling_limits_mappings() {
+        let mut addr_space = AddressSpace::new("32-bit").with_ceiling(1 << 32);
+        assert_eq!(
+            addr_space.add_mapping(unbounded(), 0, 1 << 38, Permissions::READ),
+            Err(MappingError::NoSpace)
+        );
+        assert!(addr_space
+            .add_mapping(unbounded(), 0, 1 << 32, Permissions::READ)
+            .is_ok());
+        assert_eq!(
+            addr_space.add_mapping(unbounded(), 0, 1, Permissions::READ),
+            Err(MappingError::NoSpace)
+        );
+    }
+
+    #[test]
+    fn writes_dirty_only_the_pages_they_touch() {
+        let mut addr_space = AddressSpace::new("dirty");
+        let base = addr_space.add_mapping(memory(4 * P), 0, 4 * P, RW).unwrap();
+        assert_eq!(addr_space.dirty_pages(base), vec![]);
+
+        addr_space.write(base + P + 8, &[1; 4]).unwrap();
+        assert_eq!(addr_space.dirty_pages(base), vec![base + P]);
+        // straddling a page boundary dirties both pages
+        addr_space.write(base + 3 * P - 2, &[1; 4]).unwrap();
+        assert_eq!(
+            addr_space.dirty_pages(base),
+            vec![base + P, base + 2 * P, base + 3 * P]
+        );
+        // reads don't dirty anything
+        addr_space.read(base, &mut [0; 8]).unwrap();
+        assert_eq!(addr_space.dirty_pages(base).len(), 3);
+
+        addr_space.clear_dirty(base).unwrap();
+        assert_eq!(addr_space.dirty_pages(base), vec![]);
+        assert_eq!(
+            addr_space.clear_dirty(base + P),
+            Err(MappingError::NotFound { addr: base + P })
+        );
+        assert_eq!(addr_space.dirty_pages(base + P), vec![]);
+    }
+
+    /// Memory that records each range it is asked to flush.
+    struct Flushed {
+        memory: MemorySource,
+        flushes: Mutex<Vec<(usize, usize)>>,
+    }
+
+    impl DataSource for Flushed {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+            self.memory.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+            self.memory.write_at(offset, buf)
+        }
+        fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+            self.flushes.lock().push((offset, length));
+            Ok(())
+        }
+        fn len(&self) -> usize {
+            self.memory.len()
+        }
+    }
+
+    #[test]
+    fn flush_covers_runs_of_dirty_pages() {
+        let source = Arc::new(Flushed {
+            memory: MemorySource::new(vec![0; 4 * P + 10]),
+            flushes: Mutex::new(Vec::new()),
+        });
+        let mut addr_space = AddressSpace::new("flush");
+        let base = addr_space
+            .add_mapping(source.clone(), P, 3 * P + 10, RW)
+            .unwrap();
+        addr_space.flush(base, 4 * P).unwrap();
+        assert_eq!(*source.flushes.lock(), vec![]);
+
+        addr_space.write(base + 8, &[1; 4]).unwrap();
+        addr_space.write(base + 3 * P - 2, &[1; 4]).unwrap();
+        // an empty range holds no pages, dirty or not
+        addr_space.flush(base + 8, 0).unwrap();
+        assert_eq!(*source.flushes.lock(), vec![]);
+        addr_space.flush(base, 4 * P).unwrap();
+        assert_eq!(*source.flushes.lock(), vec![(P, P), (3 * P, P + 10)]);
+
+        source.flushes.lock().clear();
+        addr_space.flush(base + P, 2 * P).unwrap();
+        assert_eq!(*source.flushes.lock(), vec![(3 * P, P)]);
+
+        // the source ends 10 bytes into the mapping's last page
+        source.flushes.lock().clear();
+        addr_space.write(base + 3 * P, &[1; 4]).unwrap();
+        addr_space.flush(base + 3 * P, P).unwrap();
+        assert_eq!(*source.flushes.lock(), vec![(4 * P, 10)]);
+
+        source.flushes.lock().clear();
+        assert_eq!(
+            addr_space.flush(base, 5 * P),
+            Err(MappingError::NotFound { addr: base + 4 * P })
+        );
+        assert_eq!(*source.flushes.lock(), vec![]);
+    }
+
+    #[test]
+    fn resident_budget_evicts_least_recently_used_pages() {
+        let backing = Arc::new(Flushed {
+            memory: MemorySource::new(pattern(0, 8 * P)),
+            flushes: Mutex::new(Vec::new()),
+        });
+        let mut addr_space = AddressSpace::new("tiny")
+            .with_resident_budget(2 * P + 100)
+            .with_ceiling(64 * P);
+        addr_space.reserve_at(va(16 * P), 8 * P).unwrap();
+        let faults = Arc::new(Mutex::new(Vec::new()));
+        let seen = faults.clone();
+        let source: Arc<dyn DataSource> = backing.clone();
+        addr_space.on_fault(move |addr_space, addr| {
+            let page = addr.align_down(P);
+            seen.lock().push(page);
+            addr_space.add_mapping_at(source.clone(), page - va(16 * P), P, page, RW)
+        });
+        let page = |n| va((16 + n) * P);
+        let touch = |addr_space: &AddressSpace, n| {
+            let mut buf = [0; 4];
+            addr_space.read(page(n) + 8, &mut buf).unwrap();
+            assert_eq!(buf[..], pattern(n * P + 8, 4));
+            assert!(addr_space.resident_bytes() <= 2 * P);
+        };
+
+        addr_space.write(page(0), b"kept").unwrap();
+        touch(&addr_space, 1);
+        touch(&addr_space, 0);
+        // page 1 is now the least recently used, so it makes way for page 2
+        touch(&addr_space, 2);
+        assert!(addr_space.contains(page(0)));
+        assert!(!addr_space.contains(page(1)));
+        assert_eq!(*backing.flushes.lock(), vec![]);
+        // and then page 0 makes way for page 3, writing back what was written to it
+        touch(&addr_space, 3);
+        assert!(!addr_space.contains(page(0)));
+        assert_eq!(*backing.flushes.lock(), vec![(0, P)]);
+        assert_eq!(*faults.lock(), vec![page(0), page(1), page(2), page(3)]);
+
+        // evicted pages fault in again, with what was written to them
+        let mut buf = [0; 4];
+        addr_space.read(page(0), &mut buf).unwrap();
+        assert_eq!(&buf, b"kept");
+        assert_eq!(faults.lock().last(), Some(&page(0)));
+        assert_eq!(addr_space.resident_bytes(), 2 * P);
+        assert_eq!(addr_space.mapping_count(), 2);
+    }
+
+    #[test]
+    fn failed_writes_dirty_what_they_wrote() {
+        let mut addr_space = AddressSpace::new("dirty until fault");
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), RW)
+            .unwrap();
+        assert_eq!(
+            addr_space.write(va(P), &[1; 2 * P]),
+            Err(MappingError::Fault { addr: va(2 * P) })
+        );
+        assert_eq!(addr_space.dirty_pages(va(0)), vec![va(P)]);
+    }
+
+    #[test]
+    fn dirty_pages_follow_splits_and_merges() {
+        let mut addr_space = AddressSpace::new("dirty splits");
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, 4 * P, va(0), RW)
+            .unwrap();
+        addr_space.write(va(0), &[1]).unwrap();
+        addr_space.write(va(3 * P), &[1]).unwrap();
+
+        addr_space.protect(va(2 * P), P, R).unwrap();
+        assert_eq!(addr_space.dirty_pages(va(0)), vec![va(0)]);
+        assert_eq!(addr_space.dirty_pages(va(2 * P)), vec![]);
+        assert_eq!(addr_space.dirty_pages(va(3 * P)), vec![va(3 * P)]);
+
+        addr_space.protect(va(2 * P), P, RW).unwrap();
+        assert_eq!(addr_space.coalesce(), 2);
+        assert_eq!(addr_space.dirty_pages(va(0)), vec![va(0), va(3 * P)]);
+
+        addr_space.shrink_mapping(va(0), 2 * P).unwrap();
+        addr_space.grow_mapping(va(0), 4 * P).unwrap();
+        assert_eq!(addr_space.dirty_pages(va(0)), vec![va(0)]);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn access_stats_count_each_access() {
+        let mut addr_space = AddressSpace::new("stats");
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), RW)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(2 * P), RW)
+            .unwrap();
+        for _ in 0..5 {
+            addr_space.read(va(P), &mut [0; 16]).unwrap();
+        }
+        for _ in 0..3 {
+            addr_space.write(va(0), &[1; 16]).unwrap();
+        }
+        // one access across both mappings counts for each of them
+        addr_space.write(va(2 * P - 8), &[1; 16]).unwrap();
+        let _ = addr_space.translate(va(2 * P + 8));
+        let _ = addr_space.translate(va(3 * P));
+        assert_eq!(
+            addr_space.access_stats(),
+            vec![
+                (
+                    va(0),
+                    AccessStats {
+                        reads: 5,
+                        writes: 4,
+                        translates: 0
+                    }
+                ),
+                (
+                    va(2 * P),
+                    AccessStats {
+                        reads: 0,
+                        writes: 1,
+                        translates: 1
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn access_stats_survive_a_split_and_coalesce() {
+        let mut addr_space = AddressSpace::new("stats split");
+        addr_space
+            .add_mapping_at(memory(2 * P), 0, 2 * P, va(0), RW)
+            .unwrap();
+        for _ in 0..3 {
+            addr_space.read(va(P), &mut [0; 16]).unwrap();
+        }
+        addr_space.split_mapping(va(P)).unwrap();
+        addr_space.write(va(P), &[1; 16]).unwrap();
+        assert_eq!(addr_space.coalesce(), 1);
+        assert_eq!(
+            addr_space.access_stats(),
+            vec![(
+                va(0),
+                AccessStats {
+                    reads: 3,
+                    writes: 1,
+                    translates: 0
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn max_mappings_limits_adds_until_removal() {
+        let mut addr_space = AddressSpace::new("limited").with_max_mappings(3);
+        let first = addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+        addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(8 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.add_mapping(memory(P), 0, P, R),
+            Err(MappingError::TooManyMappings)
+        );
+        assert_eq!(
+            addr_space.add_mapping_at(memory(P), 0, P, va(4 * P), R),
+            Err(MappingError::TooManyMappings)
+        );
+        assert_eq!(
+            addr_space.add_mapping_guarded(memory(P), 0, P, 1, R),
+            Err(MappingError::TooManyMappings)
+        );
+        assert_eq!(addr_space.mapping_count(), 3);
+
+        addr_space.remove_mapping_at(first).unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(4 * P), R)
+            .unwrap();
+        addr_space.unmap(va(8 * P), P).unwrap();
+        addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+        assert_eq!(addr_space.mapping_count(), 3);
+    }
+
+    #[test]
+    fn coalescing_makes_room_under_max_mappings() {
+        let mut addr_space = AddressSpace::new("limited merge").with_max_mappings(2);
+        let src = memory(2 * P);
+        addr_space
+            .add_mapping_at(src.clone(), 0, P, va(0), R)
+            .unwrap();
+        addr_space.add_mapping_at(src, P, P, va(P), R).unwrap();
+        assert_eq!(
+            addr_space.add_mapping(memory(P), 0, P, R),
+            Err(MappingError::TooManyMappings)
+        );
+        assert_eq!(addr_space.coalesce(), 1);
+        addr_space.add_mapping(memory(P), 0, P, R).unwrap();
+    }
+
+    #[test]
+    fn huge_mappings_get_huge_aligned_bases() {
+        const H: usize = HUGE_PAGE_SIZE;
+        let mut addr_space = AddressSpace::new("huge");
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(0), R)
+            .unwrap();
+        // leaves a gap of exactly one huge page, but not an aligned one
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(H + P), R)
+            .unwrap();
+        let huge = addr_space.add_mapping_huge(memory(H), 0, 1, RW).unwrap();
+        assert_eq!(huge, va(2 * H));
+        assert!(huge.is_aligned(H));
+        assert_eq!(addr_space.mapping_at(huge).unwrap().span, H);
+        // small mappings still use the gaps the huge one skipped
+        assert_eq!(addr_space.add_mapping(memory(P), 0, P, R), Ok(va(P)));
+
+        assert_eq!(
+            addr_space.add_mapping_huge(memory(H / 2), 0, H / 2, R),
+            Err(MappingError::SourceTooSmall {
+                source_len: H / 2,
+                needed: H
+            })
+        );
+        let rendered = addr_space.to_string();
+        assert!(
+            rendered.contains("0x000000400000-0x000000600000 rw- offset=0x0 huge\n"),
+            "{rendered}"
+        );
+        assert!(
+            rendered.contains("0x000000000000-0x000000001000 r-- offset=0x0\n"),
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn try_add_mapping_at_suggests_a_free_base() {
+        let mut addr_space = AddressSpace::new("suggest").with_ceiling(16 * P);
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, 4 * P, va(2 * P), R)
+            .unwrap();
+        addr_space
+            .add_mapping_at(memory(P), 0, P, va(7 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.try_add_mapping_at(memory(P), 0, P, va(9 * P), R),
+            Ok(va(9 * P))
+        );
+        // [6P, 7P) is free but too small, so the next gap big enough is [10P, 16P)
+        assert_eq!(
+            addr_space.try_add_mapping_at(memory(2 * P), 0, 2 * P, va(3 * P), R),
+            Err((MappingError::Overlap { addr: va(2 * P) }, Some(va(10 * P))))
+        );
+        // the gap below `start` doesn't count
+        assert_eq!(
+            addr_space.try_add_mapping_at(memory(P), 0, P, va(5 * P), R),
+            Err((MappingError::Overlap { addr: va(2 * P) }, Some(va(6 * P))))
+        );
+        assert_eq!(addr_space.mapping_count(), 3);
+    }
+
+    #[test]
+    fn add_mapping_below_searches_down_from_the_hint() {
+        let mut addr_space = gappy(AllocPolicy::FirstFit);
+        // right at the hint, rounded down to a page
+        assert_eq!(
+            addr_space.add_mapping_below(memory(2 * P), 0, 2 * P, va(10 * P + 5), R),
+            Ok(va(10 * P))
+        );
+        // the rest of [8P, 13P) is too small for the base to be at the hint
+        assert_eq!(
+            addr_space.add_mapping_below(memory(2 * P), 0, 2 * P, va(12 * P), R),
+            Ok(va(8 * P))
+        );
+        // so is [4P, 5P), but not [0, 3P)
+        assert_eq!(
+            addr_space.add_mapping_below(memory(2 * P), 0, 2 * P, va(9 * P), R),
+            Ok(va(P))
+        );
+        assert_eq!(addr_space.validate(), Ok(()));
+        assert!(addr_space.iter().all(|mapping| mapping.addr.is_aligned(P)));
+        assert_eq!(
+            addr_space.add_mapping_below(memory(2 * P), 0, 2 * P, va(9 * P), R),
+            Err(MappingError::NoSpace)
+        );
+        assert_eq!(
+            addr_space.add_mapping_below(memory(P), 0, P, va(9 * P), R),
+            Ok(va(4 * P))
+        );
+        assert_eq!(addr_space.mapping_count(), 7);
+    }
+
+    #[test]
+    fn try_add_mapping_at_without_room_suggests_nothing() {
+        let mut addr_space = AddressSpace::new("no suggestion").with_ceiling(8 * P);
+        addr_space
+            .add_mapping_at(memory(4 * P), 0, 4 * P, va(4 * P), R)
+            .unwrap();
+        assert_eq!(
+            addr_space.try_add_mapping_at(memory(2 * P), 0, 2 * P, va(3 * P), R),
+            Err((MappingError::Overlap { addr: va(4 * P) }, None))
+        );
+        assert_eq!(
+            addr_space.try_add_mapping_at(memory(2 * P), 0, 2 * P, va(7 * P), R),
+            Err((MappingError::OutOfRange { addr: va(7 * P) }, None))
+        );
+        assert_eq!(
+            addr_space.try_add_mapping_at(memory(P), 0, P, va(1), R),
+            Err((MappingError::Unaligned { addr: va(1) }, None))
+        );
+    }
+
+    /// A `MemorySource` that counts how often it is read through `read_at`.
+    struct Sliced {
+        memory: MemorySource,
+        reads: AtomicUsize,
+    }
+
+    impl DataSource for Sliced {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+            self.memory.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+            self.memory.write_at(offset, buf)
+        }
+        fn with_slice(&self, offset: usize, length: usize, visit: &mut dyn FnMut(&[u8])) -> bool {
+            self.memory.with_slice(offset, length, visit)
+        }
+        fn len(&self) -> usize {
+            self.memory.len()
+        }
+    }
+
+    #[test]
+    fn large_reads_of_memory_are_correct() {
+        let mut addr_space = AddressSpace::new("large read");
+        let len = 256 * P + 17;
+        let bytes = pattern(0, len);
+        let addr = addr_space
+            .add_mapping(Arc::new(MemorySource::new(bytes.clone())), 0, len, R)
+            .unwrap();
+        assert_eq!(addr_space.read_vec(addr, len).unwrap(), bytes);
+        assert_eq!(
+            addr_space.read_vec(addr + P + 3, 5 * P).unwrap(),
+            &bytes[P + 3..6 * P + 3]
+        );
+    }
+
+    #[test]
+    fn reads_copy_from_slices_when_they_can() {
+        let memory = Arc::new(Sliced {
+            memory: MemorySource::new(pattern(0, 64 * P)),
+            reads: AtomicUsize::new(0),
+        });
+        let mut addr_space = AddressSpace::new("slices");
+        let addr = addr_space
+            .add_mapping(memory.clone(), 0, 64 * P, R)
+            .unwrap();
+        let mut buf = vec![0; 64 * P];
+        for _ in 0..100 {
+            addr_space.read(addr, &mut buf).unwrap();
+        }
+        assert_eq!(buf, pattern(0, 64 * P));
+        assert_eq!(memory.reads.load(Ordering::Relaxed), 0);
+
+        // the fallback still works for a source without slices
+        let flushed = Arc::new(Flushed {
+            memory: MemorySource::new(pattern(0, P)),
+            flushes: Mutex::new(Vec::new()),
+        });
+        let addr = addr_space.add_mapping(flushed, 0, P, R).unwrap();
+        assert_eq!(addr_space.read_vec(addr, P).unwrap(), pattern(0, P));
     }
 }
@@ -0,0 +1,177 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::address_space::{AddressSpace, PAGE_SIZE};
+use crate::data_source::DataSource;
+use crate::error::MappingError;
+use crate::permissions::Permissions;
+use crate::virtual_address::VirtualAddress;
+
+/// The state of an `AddressSpace` at some point, as taken by `AddressSpace::checkpoint`: its
+/// layout, and a copy of what every writable mapping held.
+#[derive(Clone)]
+pub struct Checkpoint {
+    addr_space: AddressSpace,
+    contents: Vec<Contents>,
+}
+
+/// What one writable mapping's source held, from the mapping's offset on.
+#[derive(Clone)]
+struct Contents {
+    addr: VirtualAddress,
+    source: Arc<dyn DataSource>,
+    offset: usize,
+    bytes: Vec<u8>,
+}
+
+impl AddressSpace {
+    /// Record the layout of this `AddressSpace` and the bytes behind each of its writable
+    /// mappings, so that `restore` can undo whatever happens to them next, e.g. to roll back
+    /// speculative writes. This copies every writable mapping in full.
+    ///
+    /// # Errors
+    /// `Source` naming a writable mapping whose source couldn't be read, or `SourceGone` naming
+    /// one whose source was held weakly and has been dropped.
+    pub fn checkpoint(&self) -> Result<Checkpoint, MappingError> {
+        let contents = self
+            .iter()
+            .filter(|mapping| mapping.perms.contains(Permissions::WRITE))
+            .map(|mapping| {
+                let source = mapping
+                    .source
+                    .ok_or(MappingError::SourceGone { addr: mapping.addr })?;
+                let mut bytes = vec![0; mapping.span];
+                let len = source
+                    .read_at(mapping.offset, &mut bytes)
+                    .map_err(|_| MappingError::Source { addr: mapping.addr })?;
+                bytes.truncate(len);
+                Ok(Contents {
+                    addr: mapping.addr,
+                    source,
+                    offset: mapping.offset,
+                    bytes,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Checkpoint {
+            addr_space: self.clone(),
+            contents,
+        })
+    }
+
+    /// Put this `AddressSpace` back the way it was when `checkpoint` was taken: its name, its
+    /// configuration and its mappings, and the bytes behind the mappings that were writable
+    /// then. Only the pages whose bytes have changed since are written back.
+    ///
+    /// # Errors
+    /// `Source` naming a mapping whose source couldn't be read or written, in which case the
+    /// layout has been restored but some of the data may not have been.
+    pub fn restore(&mut self, checkpoint: &Checkpoint) -> Result<(), MappingError> {
+        *self = checkpoint.addr_space.clone();
+        for contents in &checkpoint.contents {
+            let source_error = |_| MappingError::Source {
+                addr: contents.addr,
+            };
+            let mut current = vec![0; PAGE_SIZE];
+            for (page, saved) in contents.bytes.chunks(PAGE_SIZE).enumerate() {
+                let offset = contents.offset + page * PAGE_SIZE;
+                let current = &mut current[..saved.len()];
+                contents
+                    .source
+                    .read_at(offset, current)
+                    .map_err(source_error)?;
+                if current != saved {
+                    contents
+                        .source
+                        .write_at(offset, saved)
+                        .map_err(source_error)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::{MemorySource, ReadOnly};
+
+    const P: usize = PAGE_SIZE;
+    const RW: Permissions = Permissions::READ.union(Permissions::WRITE);
+
+    const fn va(addr: usize) -> VirtualAddress {
+        VirtualAddress::new(addr)
+    }
+
+    fn read(addr_space: &AddressSpace, addr: VirtualAddress, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        addr_space.read(addr, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn restore_undoes_writes() {
+        let mut addr_space = AddressSpace::new("speculative");
+        let addr = addr_space
+            .add_mapping(Arc::new(MemorySource::new(vec![0; 3 * P])), 0, 3 * P, RW)
+            .unwrap();
+        let pattern: Vec<u8> = (0..3 * P).map(|i| (i % 251) as u8).collect();
+        addr_space.write(addr, &pattern).unwrap();
+
+        let checkpoint = addr_space.checkpoint().unwrap();
+        addr_space.write(addr + P - 2, &[0xee; 8]).unwrap();
+        addr_space.unmap(addr + 2 * P, P).unwrap();
+        addr_space.rename("changed");
+
+        addr_space.restore(&checkpoint).unwrap();
+        assert_eq!(read(&addr_space, addr, 3 * P), pattern);
+        assert_eq!(addr_space.name(), "speculative");
+    }
+
+    #[test]
+    fn restore_leaves_unchanged_pages_alone() {
+        let mut addr_space = AddressSpace::new("read only");
+        // writable mappings of a read-only source can't be written back to, but there's
+        // nothing to write back unless they were written in the meantime
+        let source = Arc::new(ReadOnly::new(MemorySource::new(vec![7; P])));
+        addr_space.add_mapping_at(source, 0, P, va(0), RW).unwrap();
+        let scratch = Arc::new(MemorySource::new(vec![0; P]));
+        addr_space.add_mapping_at(scratch, 0, P, va(P), RW).unwrap();
+
+        let checkpoint = addr_space.checkpoint().unwrap();
+        addr_space.write(va(P), b"scratch").unwrap();
+        addr_space.restore(&checkpoint).unwrap();
+        assert_eq!(read(&addr_space, va(0), 2), [7, 7]);
+        assert_eq!(read(&addr_space, va(P), 7), [0; 7]);
+    }
+
+    #[test]
+    fn checkpoints_survive_copy_on_write() {
+        let mut parent = AddressSpace::new("parent");
+        let addr = parent
+            .add_mapping(Arc::new(MemorySource::new(vec![1; P])), 0, P, RW)
+            .unwrap();
+        let mut child = parent.fork();
+        let checkpoint = child.checkpoint().unwrap();
+        child.write(addr, b"child").unwrap();
+        child.restore(&checkpoint).unwrap();
+        assert_eq!(read(&child, addr, 5), [1; 5]);
+        assert_eq!(read(&parent, addr, 5), [1; 5]);
+        child.write(addr, b"again").unwrap();
+        assert_eq!(read(&parent, addr, 5), [1; 5]);
+    }
+
+    #[test]
+    fn dropped_source_is_not_checkpointed() {
+        let mut addr_space = AddressSpace::new("weak");
+        let source: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![1; P]));
+        let addr = addr_space.add_mapping_weak(&source, 0, P, RW).unwrap();
+        drop(source);
+        assert_eq!(
+            addr_space.checkpoint().err(),
+            Some(MappingError::SourceGone { addr })
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::sync::Mutex;
+
+/// How many address spaces share each page of a `DataSource` that `AddressSpace::fork` made
+/// copy-on-write. Every copy-on-write mapping of the source points here.
+pub(crate) struct Sharing(Mutex<Shares>);
+
+struct Shares {
+    /// The address spaces that hold the source.
+    spaces: usize,
+    /// How many of those have since copied each page (numbered by offset into the source) and
+    /// no longer see the original.
+    left: BTreeMap<usize, usize>,
+}
+
+impl Shares {
+    /// The number of address spaces that still see `page`.
+    fn count(&self, page: usize) -> usize {
+        self.spaces - self.left.get(&page).copied().unwrap_or(0)
+    }
+
+    fn leave(&mut self, page: usize) {
+        *self.left.entry(page).or_insert(0) += 1;
+    }
+
+    fn rejoin(&mut self, page: usize) {
+        if let Some(left) = self.left.get_mut(&page) {
+            *left -= 1;
+            if *left == 0 {
+                self.left.remove(&page);
+            }
+        }
+    }
+}
+
+/// The copy-on-write sources an `AddressSpace` holds, and which of their pages it has stopped
+/// sharing. Cloning one is another address space joining every count, and dropping one is an
+/// address space leaving them, so the counts don't depend on how a space came or went.
+///
+/// Counts are only ever too high (a space that unmaps a shared page still counts, for one),
+/// which costs an unneeded copy but never lets a write through to a page that someone else
+/// still sees.
+#[derive(Default)]
+pub(crate) struct CowShares(Vec<Share>);
+
+struct Share {
+    sharing: Arc<Sharing>,
+    left: BTreeSet<usize>,
+}
+
+impl CowShares {
+    /// Start counting a source that this address space is about to share with a fork.
+    pub fn share(&mut self) -> Arc<Sharing> {
+        let sharing = Arc::new(Sharing(Mutex::new(Shares {
+            spaces: 1,
+            left: BTreeMap::new(),
+        })));
+        self.0.push(Share {
+            sharing: sharing.clone(),
+            left: BTreeSet::new(),
+        });
+        sharing
+    }
+
+    /// Count this address space among those holding every page of `sharing`, e.g. because it
+    /// just took a copy-on-write mapping from another space.
+    pub fn join(&mut self, sharing: &Arc<Sharing>) {
+        if let Some(share) = self.find(sharing) {
+            let mut shares = share.sharing.0.lock();
+            for page in core::mem::take(&mut share.left) {
+                shares.rejoin(page);
+            }
+        } else {
+            sharing.0.lock().spaces += 1;
+            self.0.push(Share {
+                sharing: sharing.clone(),
+                left: BTreeSet::new(),
+            });
+        }
+    }
+
+    /// Whether nothing but this address space sees any of `pages` of `sharing`, so that it can
+    /// write them in place.
+    pub fn only_sharer(&self, sharing: &Arc<Sharing>, pages: Range<usize>) -> bool {
+        let shares = sharing.0.lock();
+        pages.into_iter().all(|page| shares.count(page) <= 1)
+    }
+
+    /// Record that this address space no longer sees `pages` of `sharing`.
+    pub fn leave(&mut self, sharing: &Arc<Sharing>, pages: impl IntoIterator<Item = usize>) {
+        let Some(share) = self.find(sharing) else {
+            return;
+        };
+        let mut shares = share.sharing.0.lock();
+        for page in pages {
+            if share.left.insert(page) {
+                shares.leave(page);
+            }
+        }
+    }
+
+    fn find(&mut self, sharing: &Arc<Sharing>) -> Option<&mut Share> {
+        self.0
+            .iter_mut()
+            .find(|share| Arc::ptr_eq(&share.sharing, sharing))
+    }
+}
+
+impl Clone for CowShares {
+    fn clone(&self) -> Self {
+        for share in &self.0 {
+            let mut shares = share.sharing.0.lock();
+            shares.spaces += 1;
+            for &page in &share.left {
+                shares.leave(page);
+            }
+        }
+        Self(
+            self.0
+                .iter()
+                .map(|share| Share {
+                    sharing: share.sharing.clone(),
+                    left: share.left.clone(),
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Drop for CowShares {
+    fn drop(&mut self) {
+        for share in &self.0 {
+            let mut shares = share.sharing.0.lock();
+            shares.spaces -= 1;
+            for &page in &share.left {
+                shares.rejoin(page);
+            }
+        }
+    }
+}
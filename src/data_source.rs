@@ -1,45 +1,88 @@
-use std::fs::File;
+use alloc::format;
+use alloc::string::String;
 
-pub trait DataSource {
+use crate::error::DataSourceError;
+
+/// Something that can back a mapping. Sources are shared between mappings and address spaces,
+/// possibly on different threads, so they must be `Send + Sync`.
+pub trait DataSource: Send + Sync {
     // constructors are left to each implementation, once you have one, you can:
     //
     // TODO: instead of taking a `flagbuilder`, should we turn it into some kind of convenient
     // format?
-    //
-    // TODO: add documentation for all these methods
-    fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str>;
-    fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str>;
-    fn flush(&self, offset: usize, length: usize) -> Result<(), &str>;
-}
-
-pub struct FileDataSource {
-    file_handle: File,
-    name: String,
-}
 
-impl FileDataSource {
-    /// Create a new `FileDataSource`.
+    /// Read as much of `buf` as the source holds from `offset` on, returning the number of
+    /// bytes read, which is less than `buf.len()` only at the end of the source.
     ///
     /// # Errors
-    /// If the file can't be opened.
-    pub fn new(name: &str) -> Result<Self, &str> {
-        File::open(name).map_or(Err("couldn't open {name}"), |file_handle| {
-            Ok(Self {
-                file_handle,
-                name: name.to_string(),
-            })
-        })
-    }
-}
-
-impl DataSource for FileDataSource {
+    /// `OutOfBounds` if `offset` is past the end of the source, or `Failed` if the read does.
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError>;
+    /// Write as much of `buf` as fits in the source from `offset` on, returning the number of
+    /// bytes written, which is less than `buf.len()` only at the end of the source.
+    ///
+    /// # Errors
+    /// `OutOfBounds` if `offset` is past the end of the source, `ReadOnly` if the source
+    /// doesn't take writes, or `Failed` if the write fails.
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError>;
+    /// Fill `buffer[..length]` with the `length` bytes starting at `offset`, failing unless
+    /// they all lie within the source. By default this is `read_at`, with a short read
+    /// counting as a failure.
     fn read(&self, offset: usize, length: usize, buffer: &mut [u8]) -> Result<(), &str> {
-        todo!()
+        match self.read_at(offset, &mut buffer[..length]) {
+            Ok(read) if read == length => Ok(()),
+            Ok(_) => Err("read past end of source"),
+            Err(_) => Err("read failed"),
+        }
     }
+    /// Store `buffer[..length]` as the `length` bytes starting at `offset`, failing unless they
+    /// all lie within the source. By default this is `write_at`, with a short write counting
+    /// as a failure.
     fn write(&self, offset: usize, length: usize, buffer: &[u8]) -> Result<(), &str> {
-        todo!()
+        match self.write_at(offset, &buffer[..length]) {
+            Ok(written) if written == length => Ok(()),
+            Ok(_) => Err("write past end of source"),
+            Err(_) => Err("write failed"),
+        }
     }
+    /// Make sure any writes to `[offset, offset + length)` have reached the underlying storage.
+    /// By default this does nothing, for sources like memory that have no other storage.
     fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
-        todo!()
+        Ok(())
+    }
+    /// Call `visit` on the `length` bytes starting at `offset`, without copying them, if the
+    /// source holds them contiguously in memory, returning whether it did. `read_at` is tried
+    /// when this returns `false`, which by default it always does.
+    ///
+    /// This takes a callback rather than handing out the slice because sources keep their
+    /// bytes behind a lock, which must be held while they're looked at.
+    fn with_slice(&self, offset: usize, length: usize, visit: &mut dyn FnMut(&[u8])) -> bool {
+        false
+    }
+    /// The number of bytes this source holds.
+    fn len(&self) -> usize;
+    /// Whether this source holds no bytes at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
     }
+    /// A name for this source that is stable enough to find it again, used in snapshots. By
+    /// default this is the source's address in memory, which only lasts as long as the source.
+    fn source_id(&self) -> String {
+        format!("{self:p}")
+    }
+}
+
+/// How many of `length` bytes starting at `offset` lie within a source of `len` bytes, for
+/// `read_at` and `write_at`; `OutOfBounds` if `offset` is past the end.
+pub(crate) fn clamp_to_len(
+    len: usize,
+    offset: usize,
+    length: usize,
+) -> Result<usize, DataSourceError> {
+    len.checked_sub(offset)
+        .map(|available| available.min(length))
+        .ok_or(DataSourceError::OutOfBounds { offset })
 }
+
+/// The original name of `FileSource`, kept for existing callers.
+#[cfg(feature = "std")]
+pub type FileDataSource = crate::sources::FileSource;
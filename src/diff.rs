@@ -0,0 +1,326 @@
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hash::{Hash, Hasher};
+
+use crate::address_space::{AddressSpace, PAGE_SIZE};
+use crate::error::MappingError;
+use crate::permissions::Permissions;
+use crate::virtual_address::VirtualAddress;
+
+/// One way in which the layouts of two `AddressSpace`s differ, as found by
+/// `AddressSpace::diff`. Mappings are matched up by base address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MappingDiff {
+    /// Only the first space has a mapping starting at `addr`.
+    OnlyInSelf { addr: VirtualAddress, span: usize },
+    /// Only the second space has a mapping starting at `addr`.
+    OnlyInOther { addr: VirtualAddress, span: usize },
+    /// Both spaces have a mapping starting at `addr`, but with a different span, offset or
+    /// permissions. Each pair holds the first space's value, then the second's.
+    Changed {
+        addr: VirtualAddress,
+        span: (usize, usize),
+        offset: (usize, usize),
+        perms: (Permissions, Permissions),
+    },
+}
+
+impl MappingDiff {
+    /// The base address of the mapping or mappings this difference is about.
+    #[must_use]
+    pub const fn addr(&self) -> VirtualAddress {
+        match *self {
+            Self::OnlyInSelf { addr, .. }
+            | Self::OnlyInOther { addr, .. }
+            | Self::Changed { addr, .. } => addr,
+        }
+    }
+}
+
+/// The 64-bit FNV-1a hash. Unlike `std`'s `DefaultHasher`, its output is fixed, so hashes can
+/// be compared between runs, and it's available without `std`.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    const fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl AddressSpace {
+    /// Compare the layout of this `AddressSpace` with `other`'s, in ascending address order.
+    /// Mappings that start at the same address with the same span, offset and permissions
+    /// count as the same, whichever `DataSource` backs them.
+    #[must_use]
+    pub fn diff(&self, other: &Self) -> Vec<MappingDiff> {
+        let mut theirs: BTreeMap<_, _> = other.iter().map(|info| (info.addr, info)).collect();
+        let mut diffs = Vec::new();
+        for ours in self.iter() {
+            let Some(their) = theirs.remove(&ours.addr) else {
+                diffs.push(MappingDiff::OnlyInSelf {
+                    addr: ours.addr,
+                    span: ours.span,
+                });
+                continue;
+            };
+            if (ours.span, ours.offset, ours.perms) != (their.span, their.offset, their.perms) {
+                diffs.push(MappingDiff::Changed {
+                    addr: ours.addr,
+                    span: (ours.span, their.span),
+                    offset: (ours.offset, their.offset),
+                    perms: (ours.perms, their.perms),
+                });
+            }
+        }
+        diffs.extend(theirs.into_values().map(|info| MappingDiff::OnlyInOther {
+            addr: info.addr,
+            span: info.span,
+        }));
+        diffs.sort_by_key(MappingDiff::addr);
+        diffs
+    }
+
+    /// Whether this `AddressSpace` has exactly the same mappings as `other`: the same bases,
+    /// spans, offsets and permissions, backed by the very same `DataSource`s. Unlike `diff`,
+    /// this tells sources apart by identity, so a mapping whose source has been dropped matches
+    /// nothing. Names and configuration are ignored.
+    #[must_use]
+    pub fn layout_eq(&self, other: &Self) -> bool {
+        self.mapping_count() == other.mapping_count()
+            && self.iter().zip(other.iter()).all(|(ours, theirs)| {
+                (ours.addr, ours.span, ours.offset, ours.perms)
+                    == (theirs.addr, theirs.span, theirs.offset, theirs.perms)
+                    && ours
+                        .source
+                        .zip(theirs.source)
+                        .is_some_and(|(ours, theirs)| Arc::ptr_eq(&ours, &theirs))
+            })
+    }
+
+    /// Whether this `AddressSpace` holds the same bytes as `other` at every mapped address,
+    /// e.g. to check that a fork or a restored checkpoint matches the space it came from. The
+    /// layouts must be the same as far as `diff` can tell; the sources behind them needn't be.
+    /// Bytes are compared a page at a time, straight from the sources, so permissions don't
+    /// get in the way.
+    ///
+    /// # Errors
+    /// `LayoutMismatch` naming the first mapping that `diff` finds different, `Source` naming
+    /// a mapping whose source couldn't be read, or `SourceGone` naming one whose source was
+    /// held weakly and has been dropped.
+    pub fn equal_contents(&self, other: &Self) -> Result<bool, MappingError> {
+        if let Some(diff) = self.diff(other).first() {
+            return Err(MappingError::LayoutMismatch { addr: diff.addr() });
+        }
+        let mut ours = vec![0; PAGE_SIZE];
+        let mut theirs = vec![0; PAGE_SIZE];
+        for (mine, their) in self.iter().zip(other.iter()) {
+            for done in (0..mine.span).step_by(PAGE_SIZE) {
+                let len = PAGE_SIZE.min(mine.span - done);
+                let (ours, theirs) = (&mut ours[..len], &mut theirs[..len]);
+                let source_error = |_| MappingError::Source {
+                    addr: mine.addr + done,
+                };
+                let read = mine
+                    .source
+                    .as_ref()
+                    .ok_or(MappingError::SourceGone { addr: mine.addr })?
+                    .read_at(mine.offset + done, ours)
+                    .map_err(source_error)?;
+                let their_read = their
+                    .source
+                    .as_ref()
+                    .ok_or(MappingError::SourceGone { addr: their.addr })?
+                    .read_at(their.offset + done, theirs)
+                    .map_err(source_error)?;
+                if read != their_read || ours != theirs {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// A hash of the base, span, offset and permissions of every mapping, for telling layouts
+    /// apart quickly: spaces whose mappings are the same in all of those have the same hash,
+    /// whatever their sources, and the hash is the same from one run to the next on the same
+    /// platform.
+    #[must_use]
+    pub fn layout_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        for mapping in self.iter() {
+            (mapping.addr, mapping.span, mapping.offset, mapping.perms).hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::PAGE_SIZE;
+    use crate::data_source::DataSource;
+    use crate::sources::MemorySource;
+
+    const P: usize = PAGE_SIZE;
+    const RW: Permissions = Permissions::READ.union(Permissions::WRITE);
+
+    const fn va(addr: usize) -> VirtualAddress {
+        VirtualAddress::new(addr)
+    }
+
+    fn parent() -> AddressSpace {
+        let mut addr_space = AddressSpace::new("parent");
+        for base in [0, 2 * P, 4 * P] {
+            addr_space
+                .add_mapping_at(Arc::new(MemorySource::new(vec![0; P])), 0, P, va(base), RW)
+                .unwrap();
+        }
+        addr_space
+    }
+
+    #[test]
+    fn fork_differs_only_where_changed() {
+        let mut parent = parent();
+        let mut child = parent.fork();
+        assert_eq!(parent.diff(&child), vec![]);
+
+        child.protect(va(2 * P), P, Permissions::READ).unwrap();
+        assert_eq!(
+            parent.diff(&child),
+            vec![MappingDiff::Changed {
+                addr: va(2 * P),
+                span: (P, P),
+                offset: (0, 0),
+                perms: (RW, Permissions::READ),
+            }]
+        );
+    }
+
+    #[test]
+    fn added_and_removed_mappings() {
+        let mut parent = parent();
+        let mut child = parent.fork();
+        child.unmap(va(0), P).unwrap();
+        child
+            .add_mapping_at(Arc::new(MemorySource::new(vec![0; P])), 0, P, va(8 * P), RW)
+            .unwrap();
+        assert_eq!(
+            parent.diff(&child),
+            vec![
+                MappingDiff::OnlyInSelf {
+                    addr: va(0),
+                    span: P
+                },
+                MappingDiff::OnlyInOther {
+                    addr: va(8 * P),
+                    span: P
+                },
+            ]
+        );
+        assert_eq!(
+            child.diff(&parent),
+            vec![
+                MappingDiff::OnlyInOther {
+                    addr: va(0),
+                    span: P
+                },
+                MappingDiff::OnlyInSelf {
+                    addr: va(8 * P),
+                    span: P
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn layout_eq_needs_the_same_sources() {
+        let text: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![0; 2 * P]));
+        let data: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![0; P]));
+        let build = |text_span| {
+            let mut addr_space = AddressSpace::new("built");
+            addr_space
+                .add_mapping_at(text.clone(), 0, text_span, va(0), Permissions::READ)
+                .unwrap();
+            addr_space
+                .add_mapping_at(data.clone(), 0, P, va(4 * P), RW)
+                .unwrap();
+            addr_space
+        };
+        let mut first = build(2 * P);
+        first.rename("first");
+        assert!(first.layout_eq(&build(2 * P)));
+        assert!(!first.layout_eq(&build(P)));
+        assert!(!first.layout_eq(&AddressSpace::new("empty")));
+
+        // the same layout over different sources
+        assert!(!first.layout_eq(&parent()));
+        assert!(parent().diff(&parent()).is_empty());
+        assert!(!parent().layout_eq(&parent()));
+        let copy = first.clone();
+        assert!(first.layout_eq(&copy));
+    }
+
+    #[test]
+    fn forks_have_equal_contents_until_written() {
+        let mut parent = parent();
+        parent.write(va(2 * P), b"parent").unwrap();
+        let mut child = parent.fork();
+        assert_eq!(parent.equal_contents(&child), Ok(true));
+        // a fresh copy of the layout, with none of the writes
+        assert_eq!(parent.equal_contents(&self::parent()), Ok(false));
+
+        child.write(va(4 * P + 9), b"child").unwrap();
+        assert_eq!(parent.equal_contents(&child), Ok(false));
+        assert_eq!(child.equal_contents(&parent), Ok(false));
+        parent.write(va(4 * P + 9), b"child").unwrap();
+        assert_eq!(parent.equal_contents(&child), Ok(true));
+
+        child.unmap(va(2 * P), P).unwrap();
+        assert_eq!(
+            parent.equal_contents(&child),
+            Err(MappingError::LayoutMismatch { addr: va(2 * P) })
+        );
+    }
+
+    #[test]
+    fn layout_hash_depends_only_on_the_layout() {
+        let hash = parent().layout_hash();
+        assert_eq!(parent().layout_hash(), hash);
+        assert_eq!(parent().fork().layout_hash(), hash);
+        assert_ne!(AddressSpace::new("empty").layout_hash(), hash);
+
+        let mut wider = AddressSpace::new("wider");
+        for (base, span) in [(0, P), (2 * P, P), (4 * P, P + 1)] {
+            wider
+                .add_mapping_at(
+                    Arc::new(MemorySource::new(vec![0; 2 * P])),
+                    0,
+                    span,
+                    va(base),
+                    RW,
+                )
+                .unwrap();
+        }
+        assert_ne!(wider.layout_hash(), hash);
+
+        let mut changed = parent();
+        changed.protect(va(4 * P), P, Permissions::READ).unwrap();
+        assert_ne!(changed.layout_hash(), hash);
+        changed.protect(va(4 * P), P, RW).unwrap();
+        assert_eq!(changed.layout_hash(), hash);
+    }
+}
@@ -0,0 +1,131 @@
+use alloc::string::String;
+use core::fmt;
+
+use crate::virtual_address::VirtualAddress;
+
+/// Why an operation on an `AddressSpace`'s mappings failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MappingError {
+    /// There is no free region large enough for the requested mapping.
+    NoSpace,
+    /// The requested range overlaps the mapping (or segment) starting at `addr`.
+    Overlap { addr: VirtualAddress },
+    /// There is no mapping at `addr`.
+    NotFound { addr: VirtualAddress },
+    /// `addr` is not suitably aligned.
+    Unaligned { addr: VirtualAddress },
+    /// `addr` lies outside the address space, or beyond the end of the source it would map.
+    OutOfRange { addr: VirtualAddress },
+    /// The mapping at `addr` is backed by a different `DataSource` than the one given.
+    SourceMismatch { addr: VirtualAddress },
+    /// An access touched `addr`, which is not mapped.
+    Fault { addr: VirtualAddress },
+    /// The mapping containing `addr` does not permit the attempted access.
+    PermissionDenied { addr: VirtualAddress },
+    /// An instruction fetch touched `addr`, whose mapping is not executable.
+    NotExecutable { addr: VirtualAddress },
+    /// The `DataSource` backing `addr` failed to complete an access.
+    Source { addr: VirtualAddress },
+    /// Line `line` (counting from 1) of a textual memory map could not be parsed.
+    Parse { line: usize },
+    /// The requested permissions are both writable and executable, which the address space's
+    /// W^X policy forbids.
+    WxViolation,
+    /// `addr` is in the guard pages below a mapping.
+    GuardPage { addr: VirtualAddress },
+    /// The requested span is zero.
+    InvalidSpan,
+    /// The requested mapping would need the first `needed` bytes of its source, but the source
+    /// only holds `source_len`.
+    SourceTooSmall { source_len: usize, needed: usize },
+    /// The address space already has as many mappings as it is allowed.
+    TooManyMappings,
+    /// There is no segment with the given name.
+    UnknownSegment,
+    /// The mapping at `addr` is pinned, so it can't be moved.
+    Pinned { addr: VirtualAddress },
+    /// The requested mapping would show some of the same source bytes as the mapping at
+    /// `addr`, and the address space doesn't allow aliasing.
+    Aliased { addr: VirtualAddress },
+    /// The two address spaces being compared lay out their mappings differently, starting
+    /// with the mapping at `addr`.
+    LayoutMismatch { addr: VirtualAddress },
+    /// The mapping containing `addr` refers to its source weakly, and the source has since
+    /// been dropped.
+    SourceGone { addr: VirtualAddress },
+}
+
+impl fmt::Display for MappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSpace => write!(f, "no free region is large enough for the mapping"),
+            Self::Overlap { addr } => write!(f, "range overlaps the mapping at {addr:#x}"),
+            Self::NotFound { addr } => write!(f, "no mapping at {addr:#x}"),
+            Self::Unaligned { addr } => write!(f, "address {addr:#x} is not aligned"),
+            Self::OutOfRange { addr } => {
+                write!(f, "address {addr:#x} is outside the address space")
+            }
+            Self::SourceMismatch { addr } => {
+                write!(f, "mapping at {addr:#x} has a different data source")
+            }
+            Self::Fault { addr } => write!(f, "access to unmapped address {addr:#x}"),
+            Self::PermissionDenied { addr } => {
+                write!(f, "access to {addr:#x} not permitted by its mapping")
+            }
+            Self::NotExecutable { addr } => {
+                write!(f, "instruction fetch from non-executable address {addr:#x}")
+            }
+            Self::Source { addr } => write!(f, "data source backing {addr:#x} failed"),
+            Self::Parse { line } => write!(f, "line {line} is not a valid memory map entry"),
+            Self::WxViolation => write!(f, "mapping would be both writable and executable"),
+            Self::GuardPage { addr } => write!(f, "access to guard page at {addr:#x}"),
+            Self::InvalidSpan => write!(f, "span is empty"),
+            Self::SourceTooSmall { source_len, needed } => write!(
+                f,
+                "mapping needs {needed} bytes of a source that holds only {source_len}"
+            ),
+            Self::TooManyMappings => write!(f, "too many mappings"),
+            Self::UnknownSegment => write!(f, "no segment with that name"),
+            Self::Pinned { addr } => write!(f, "mapping at {addr:#x} is pinned"),
+            Self::Aliased { addr } => {
+                write!(
+                    f,
+                    "mapping would alias source bytes of the mapping at {addr:#x}"
+                )
+            }
+            Self::LayoutMismatch { addr } => {
+                write!(f, "layouts differ at the mapping at {addr:#x}")
+            }
+            Self::SourceGone { addr } => {
+                write!(f, "data source backing {addr:#x} has been dropped")
+            }
+        }
+    }
+}
+
+impl core::error::Error for MappingError {}
+
+/// Why a `DataSource` failed to complete an access.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DataSourceError {
+    /// `offset` is past the end of the source.
+    OutOfBounds { offset: usize },
+    /// The source does not accept writes.
+    ReadOnly,
+    /// The source reported an error of its own.
+    Failed(String),
+}
+
+impl fmt::Display for DataSourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OutOfBounds { offset } => {
+                write!(f, "offset {offset:#x} is past the end of the source")
+            }
+            Self::ReadOnly => write!(f, "source is read-only"),
+            Self::Failed(reason) => write!(f, "source failed: {reason}"),
+        }
+    }
+}
+
+impl core::error::Error for DataSourceError {}
@@ -1,16 +1,57 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![allow(dead_code, unused_variables)]
 
+extern crate alloc;
+#[cfg(all(test, not(feature = "std")))]
+#[macro_use]
+extern crate std;
+
 mod address_space;
 mod cacher;
+mod checkpoint;
+mod cow;
 mod data_source;
+mod diff;
+mod error;
+mod maps;
+mod permissions;
+mod report;
+mod resident;
+mod shared;
+mod snapshot;
+mod sources;
+mod stats;
+mod sync;
+mod virtual_address;
 
-pub use address_space::AddressSpace;
-pub use data_source::{DataSource, FileDataSource};
+pub use address_space::{
+    AddressSpace, AddressSpaceBuilder, AllocDirection, AllocPolicy, FlagBuilder, IntoMappings,
+    MapEntryView, MappingInfo, Segment, UnmappedReadPolicy, HUGE_PAGE_SIZE, PAGE_SIZE,
+};
+pub use checkpoint::Checkpoint;
+pub use data_source::DataSource;
+#[cfg(feature = "std")]
+pub use data_source::FileDataSource;
+pub use diff::MappingDiff;
+pub use error::{DataSourceError, MappingError};
+pub use maps::parse_maps;
+pub use permissions::Permissions;
+pub use report::AddressSpaceReport;
+pub use shared::SharedAddressSpace;
+pub use snapshot::{AddressSpaceSnapshot, MappingSnapshot};
+#[cfg(feature = "std")]
+pub use sources::FileSource;
+pub use sources::{AnonymousSource, ConcatSource, MemorySource, ReadOnly, SubRange};
+#[cfg(feature = "stats")]
+pub use stats::AccessStats;
+pub use virtual_address::VirtualAddress;
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::Arc;
 
+    #[cfg(feature = "std")]
     #[test]
     fn constructors() {
         let _a = AddressSpace::new("my first address space");
@@ -21,15 +62,21 @@ mod tests {
     // address space has what we expect in it after each operation
 
     // test if mapping has been added
+    #[cfg(feature = "std")]
     #[test]
     fn test_add_mapping() {
-        let addr_space = AddressSpace::new("Test address space");
-        let data_source: FileDataSource = FileDataSource::new("Cargo.toml").unwrap();
+        let mut addr_space = AddressSpace::new("Test address space");
+        let data_source = Arc::new(FileDataSource::new("Cargo.toml").unwrap());
         let offset: usize = 0;
         let length: usize = 1;
 
-        let addr = addr_space.add_mapping(&data_source, offset, length).unwrap();
-        assert!(addr != 0);
+        addr_space
+            .add_mapping(data_source.clone(), offset, length, Permissions::READ)
+            .unwrap();
+        let addr = addr_space
+            .add_mapping(data_source, offset, length, Permissions::READ)
+            .unwrap();
+        assert!(addr != VirtualAddress::new(0));
 
         // we should move these tests into addr_space, since they access non-public internals of the structure:
         // assert_eq!(addr_space.mappings.is_empty(), false);
@@ -37,4 +84,31 @@ mod tests {
         // assert_eq!(addr_space.mappings.front().offset, offset);
         // assert_eq!(addr_space.mappings.front().span, length);
     }
+
+    // `cargo test --no-default-features` builds this, and everything else here, against a
+    // `no_std` crate.
+    #[test]
+    fn core_works_without_std() {
+        let mut addr_space = AddressSpace::new("no_std");
+        let rw = Permissions::READ | Permissions::WRITE;
+        let addr = addr_space
+            .add_mapping(
+                Arc::new(AnonymousSource::new(2 * PAGE_SIZE)),
+                0,
+                2 * PAGE_SIZE,
+                rw,
+            )
+            .unwrap();
+        addr_space.write(addr + PAGE_SIZE - 2, b"abcd").unwrap();
+        let shared = SharedAddressSpace::new(addr_space);
+        let mut buf = [0; 4];
+        shared.read(addr + PAGE_SIZE - 2, &mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        assert_eq!(
+            shared
+                .translate(addr + 2 * PAGE_SIZE)
+                .map(|(_, offset)| offset),
+            None
+        );
+    }
 }
@@ -0,0 +1,183 @@
+use alloc::sync::Arc;
+
+use crate::address_space::AddressSpace;
+use crate::data_source::DataSource;
+use crate::error::MappingError;
+use crate::permissions::Permissions;
+use crate::virtual_address::VirtualAddress;
+
+/// Rebuild the layout described by a `/proc/<pid>/maps` dump, one mapping per line:
+///
+/// ```text
+/// 55d0c8a00000-55d0c8a02000 r--p 00000000 08:01 1234   /usr/bin/cat
+/// 7ffd3c1f0000-7ffd3c211000 rw-p 00000000 00:00 0      [stack]
+/// ```
+///
+/// `resolver` is called with each line's path (which is empty for anonymous mappings) to find
+/// the `DataSource` behind it. Real maps use the whole 64-bit range, so the resulting
+/// `AddressSpace` has no ceiling below `usize::MAX`. Blank lines are skipped.
+///
+/// # Errors
+/// `Parse` naming the first line that isn't a well-formed entry, or any error from
+/// `AddressSpace::add_mapping_at`, e.g. if two lines overlap.
+pub fn parse_maps(
+    input: &str,
+    resolver: impl Fn(&str) -> Arc<dyn DataSource>,
+) -> Result<AddressSpace, MappingError> {
+    let mut addr_space = AddressSpace::new("maps").with_ceiling(usize::MAX);
+    for (index, text) in input.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        let line = parse_line(text).ok_or(MappingError::Parse { line: index + 1 })?;
+        addr_space.add_mapping_at(
+            resolver(line.path),
+            line.offset,
+            line.end - line.start,
+            line.start,
+            line.perms,
+        )?;
+    }
+    Ok(addr_space)
+}
+
+struct MapsLine<'a> {
+    start: VirtualAddress,
+    end: VirtualAddress,
+    perms: Permissions,
+    offset: usize,
+    path: &'a str,
+}
+
+fn parse_line(text: &str) -> Option<MapsLine<'_>> {
+    // address perms offset dev inode [path], where the path may itself contain spaces
+    let mut fields = text
+        .splitn(6, char::is_whitespace)
+        .filter(|f| !f.is_empty());
+    let (start, end) = fields.next()?.split_once('-')?;
+    let start = VirtualAddress::new(usize::from_str_radix(start, 16).ok()?);
+    let end = VirtualAddress::new(usize::from_str_radix(end, 16).ok()?);
+    let perms = parse_perms(fields.next()?)?;
+    let offset = usize::from_str_radix(fields.next()?, 16).ok()?;
+    let _device = fields.next()?;
+    let _inode = fields.next()?;
+    let path = fields.next().map_or("", str::trim);
+    (start < end).then_some(MapsLine {
+        start,
+        end,
+        perms,
+        offset,
+        path,
+    })
+}
+
+/// Parse a permissions column like `r-xp`. The final shared/private flag is ignored.
+fn parse_perms(text: &str) -> Option<Permissions> {
+    let flags = text.as_bytes();
+    if flags.len() != 4 || !matches!(flags[3], b'p' | b's') {
+        return None;
+    }
+    let mut perms = Permissions::NONE;
+    for (&flag, (letter, perm)) in flags.iter().zip([
+        (b'r', Permissions::READ),
+        (b'w', Permissions::WRITE),
+        (b'x', Permissions::EXECUTE),
+    ]) {
+        match flag {
+            b'-' => {}
+            _ if flag == letter => perms = perms | perm,
+            _ => return None,
+        }
+    }
+    Some(perms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::AnonymousSource;
+    use std::collections::HashMap;
+    use std::string::{String, ToString};
+    use std::vec::Vec;
+
+    const FIXTURE: &str = "\
+55d0c8a00000-55d0c8a02000 r--p 00000000 08:01 1234                       /usr/bin/cat
+55d0c8a02000-55d0c8a07000 r-xp 00002000 08:01 1234                       /usr/bin/cat
+55d0c8a0c000-55d0c8a0d000 rw-p 0000b000 08:01 1234                       /usr/bin/cat
+
+7f1e2b400000-7f1e2b421000 rw-p 00000000 00:00 0
+7ffd3c1f0000-7ffd3c211000 rw-p 00000000 00:00 0                          [stack]
+ffffffffff600000-ffffffffff601000 --xp 00000000 00:00 0                  [vsyscall]
+";
+
+    fn resolver() -> impl Fn(&str) -> Arc<dyn DataSource> {
+        let mut sources: HashMap<String, Arc<dyn DataSource>> = HashMap::new();
+        for path in ["/usr/bin/cat", "", "[stack]", "[vsyscall]"] {
+            sources.insert(path.to_string(), Arc::new(AnonymousSource::new(1 << 20)));
+        }
+        move |path| sources[path].clone()
+    }
+
+    #[test]
+    fn parse_fixture() {
+        let resolve = resolver();
+        let addr_space = parse_maps(FIXTURE, &resolve).unwrap();
+        let rw = Permissions::READ | Permissions::WRITE;
+        let layout: Vec<_> = addr_space
+            .iter()
+            .map(|m| (m.addr.as_usize(), m.span, m.perms, m.offset))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                (0x55d0_c8a0_0000, 0x2000, Permissions::READ, 0),
+                (
+                    0x55d0_c8a0_2000,
+                    0x5000,
+                    Permissions::READ | Permissions::EXECUTE,
+                    0x2000
+                ),
+                (0x55d0_c8a0_c000, 0x1000, rw, 0xb000),
+                (0x7f1e_2b40_0000, 0x21000, rw, 0),
+                (0x7ffd_3c1f_0000, 0x21000, rw, 0),
+                (0xffff_ffff_ff60_0000, 0x1000, Permissions::EXECUTE, 0),
+            ]
+        );
+        let (binary, _) = addr_space
+            .translate(VirtualAddress::new(0x55d0_c8a0_2000))
+            .unwrap();
+        assert!(Arc::ptr_eq(&binary, &resolve("/usr/bin/cat")));
+        let (stack, _) = addr_space
+            .translate(VirtualAddress::new(0x7ffd_3c1f_0000))
+            .unwrap();
+        assert!(Arc::ptr_eq(&stack, &resolve("[stack]")));
+    }
+
+    #[test]
+    fn bad_lines_are_reported_by_number() {
+        for (input, line) in [
+            ("zzzz-1000 r--p 00000000 00:00 0", 1),
+            ("1000-2000 r--p 00000000 00:00 0\n2000 r--p 0 00:00 0", 2),
+            ("1000-2000 rwz- 00000000 00:00 0", 1),
+            ("1000-2000 r--p 00000000 00:00 0\n\n2000-3000 r--p", 3),
+            ("2000-1000 r--p 00000000 00:00 0", 1),
+        ] {
+            assert_eq!(
+                parse_maps(input, resolver()).err(),
+                Some(MappingError::Parse { line }),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn overlapping_lines_are_rejected() {
+        let input = "1000-3000 r--p 00000000 00:00 0\n2000-4000 r--p 00000000 00:00 0";
+        assert_eq!(
+            parse_maps(input, resolver()).err(),
+            Some(MappingError::Overlap {
+                addr: VirtualAddress::new(0x1000)
+            })
+        );
+    }
+}
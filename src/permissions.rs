@@ -0,0 +1,109 @@
+use core::fmt;
+use core::ops::{BitAnd, BitOr, Sub};
+
+/// Protection bits for a mapping.
+///
+/// Combine permissions with `|`:
+/// ```
+/// # use reedos_address_space::Permissions;
+/// let rw = Permissions::READ | Permissions::WRITE;
+/// assert!(rw.contains(Permissions::READ));
+/// assert!(!rw.contains(Permissions::EXECUTE));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct Permissions(u8);
+
+impl Permissions {
+    /// No access at all.
+    pub const NONE: Self = Self(0);
+    pub const READ: Self = Self(1 << 0);
+    pub const WRITE: Self = Self(1 << 1);
+    pub const EXECUTE: Self = Self(1 << 2);
+
+    /// The permissions in either `self` or `other`; a `const` version of `|`.
+    #[must_use]
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// Whether every permission in `other` is also in `self`.
+    #[must_use]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether no permissions are set.
+    #[must_use]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Permissions {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        self.union(rhs)
+    }
+}
+
+impl BitAnd for Permissions {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl Sub for Permissions {
+    type Output = Self;
+
+    /// The permissions in `self` that are not in `rhs`.
+    fn sub(self, rhs: Self) -> Self {
+        Self(self.0 & !rhs.0)
+    }
+}
+
+impl fmt::Display for Permissions {
+    /// Formats like the permission column of `/proc/<pid>/maps`, e.g. `r-x`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (perm, flag) in [(Self::READ, 'r'), (Self::WRITE, 'w'), (Self::EXECUTE, 'x')] {
+            write!(f, "{}", if self.contains(perm) { flag } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::ToString;
+
+    #[test]
+    fn combine_and_remove() {
+        let rwx = Permissions::READ | Permissions::WRITE | Permissions::EXECUTE;
+        assert!(rwx.contains(Permissions::READ | Permissions::EXECUTE));
+        assert_eq!(
+            rwx - Permissions::WRITE,
+            Permissions::READ | Permissions::EXECUTE
+        );
+        assert_eq!(rwx & Permissions::WRITE, Permissions::WRITE);
+        assert!((Permissions::READ - Permissions::READ).is_empty());
+        assert!(Permissions::READ.contains(Permissions::NONE));
+    }
+
+    #[test]
+    fn display_like_proc_maps() {
+        assert_eq!(Permissions::NONE.to_string(), "---");
+        assert_eq!(
+            (Permissions::READ | Permissions::EXECUTE).to_string(),
+            "r-x"
+        );
+        assert_eq!(
+            (Permissions::READ | Permissions::WRITE | Permissions::EXECUTE).to_string(),
+            "rwx"
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::address_space::{AddressSpace, MappingInfo};
+use crate::virtual_address::VirtualAddress;
+
+/// A summary of an `AddressSpace`, as returned by `AddressSpace::describe`: the same facts as
+/// its `Display` output, for programs rather than people.
+#[derive(Clone)]
+pub struct AddressSpaceReport {
+    pub name: String,
+    pub ceiling: VirtualAddress,
+    pub mapping_count: usize,
+    pub total_mapped_bytes: usize,
+    pub largest_free_gap: usize,
+    /// The mappings, in address order.
+    pub mappings: Vec<MappingInfo>,
+}
+
+impl AddressSpace {
+    /// Summarize this `AddressSpace`: its name and ceiling, how much of it is mapped, and
+    /// each of its mappings.
+    #[must_use]
+    pub fn describe(&self) -> AddressSpaceReport {
+        AddressSpaceReport {
+            name: self.name().to_string(),
+            ceiling: self.ceiling(),
+            mapping_count: self.mapping_count(),
+            total_mapped_bytes: self.total_mapped_bytes(),
+            largest_free_gap: self.largest_free_gap(),
+            mappings: self.iter().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::PAGE_SIZE;
+    use crate::data_source::DataSource;
+    use crate::permissions::Permissions;
+    use crate::sources::MemorySource;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    const P: usize = PAGE_SIZE;
+
+    #[test]
+    fn report_matches_the_space() {
+        let mut addr_space = AddressSpace::new("reported").with_ceiling(16 * P);
+        let text = Arc::new(MemorySource::new(vec![0; 2 * P]));
+        let data: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![0; 4 * P]));
+        addr_space
+            .add_mapping_at(
+                text.clone(),
+                0,
+                2 * P,
+                VirtualAddress::new(P),
+                Permissions::READ,
+            )
+            .unwrap();
+        addr_space
+            .add_mapping_at(
+                data.clone(),
+                P,
+                3 * P,
+                VirtualAddress::new(8 * P),
+                Permissions::READ | Permissions::WRITE,
+            )
+            .unwrap();
+
+        let report = addr_space.describe();
+        assert_eq!(report.name, "reported");
+        assert_eq!(report.ceiling, VirtualAddress::new(16 * P));
+        assert_eq!(report.mapping_count, 2);
+        assert_eq!(report.total_mapped_bytes, 5 * P);
+        assert_eq!(report.largest_free_gap, 5 * P);
+        let layout: Vec<_> = report
+            .mappings
+            .iter()
+            .map(|mapping| (mapping.addr, mapping.span, mapping.offset, mapping.perms))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                (VirtualAddress::new(P), 2 * P, 0, Permissions::READ),
+                (
+                    VirtualAddress::new(8 * P),
+                    3 * P,
+                    P,
+                    Permissions::READ | Permissions::WRITE
+                ),
+            ]
+        );
+        assert!(Arc::ptr_eq(
+            report.mappings[1].source.as_ref().unwrap(),
+            &data
+        ));
+        assert_eq!(
+            AddressSpace::new("empty").describe().largest_free_gap,
+            AddressSpace::MAX_ADDRESS
+        );
+    }
+}
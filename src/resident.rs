@@ -0,0 +1,101 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::virtual_address::VirtualAddress;
+
+/// The pages that the fault handler has brought into an `AddressSpace`, oldest use first, and
+/// how many of them may be resident at once. See `AddressSpace::with_resident_budget`.
+#[derive(Clone)]
+pub(crate) struct ResidentSet {
+    budget: usize, // in pages
+    last_use: BTreeMap<VirtualAddress, u64>,
+    by_age: BTreeMap<u64, VirtualAddress>,
+    clock: u64,
+}
+
+impl ResidentSet {
+    pub const fn new() -> Self {
+        Self {
+            budget: usize::MAX,
+            last_use: BTreeMap::new(),
+            by_age: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn set_budget(&mut self, pages: usize) {
+        self.budget = pages;
+    }
+
+    /// Stop tracking every page, keeping the budget.
+    pub fn clear(&mut self) {
+        self.last_use.clear();
+        self.by_age.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.last_use.len()
+    }
+
+    pub fn over_budget(&self) -> bool {
+        self.len() > self.budget
+    }
+
+    pub fn contains(&self, page: VirtualAddress) -> bool {
+        self.last_use.contains_key(&page)
+    }
+
+    pub fn pages(&self) -> impl Iterator<Item = VirtualAddress> + '_ {
+        self.last_use.keys().copied()
+    }
+
+    /// Make `page` the most recently used, starting to track it if it isn't already.
+    pub fn insert(&mut self, page: VirtualAddress) {
+        self.remove(page);
+        self.clock += 1;
+        self.last_use.insert(page, self.clock);
+        self.by_age.insert(self.clock, page);
+    }
+
+    /// Make every tracked page in `pages` the most recently used.
+    pub fn touch(&mut self, pages: Range<VirtualAddress>) {
+        let touched: Vec<_> = self.last_use.range(pages).map(|(&page, _)| page).collect();
+        for page in touched {
+            self.insert(page);
+        }
+    }
+
+    pub fn remove(&mut self, page: VirtualAddress) {
+        if let Some(used) = self.last_use.remove(&page) {
+            self.by_age.remove(&used);
+        }
+    }
+
+    /// Follow mappings that have moved: each tracked page in one of the `(from, to)` ranges
+    /// moves to the same place relative to `to`, keeping when it was last used. The ranges
+    /// moved from must not overlap, but may overlap where the pages move to.
+    pub fn relocate(&mut self, moves: &[(Range<VirtualAddress>, VirtualAddress)]) {
+        let mut moved = Vec::new();
+        for (from, to) in moves {
+            let pages: Vec<_> = self
+                .last_use
+                .range(from.clone())
+                .map(|(&page, &used)| (page, used))
+                .collect();
+            for (page, used) in pages {
+                self.last_use.remove(&page);
+                moved.push((*to + (page - from.start), used));
+            }
+        }
+        for (page, used) in moved {
+            self.last_use.insert(page, used);
+            self.by_age.insert(used, page);
+        }
+    }
+
+    /// The least recently used page other than `keep`.
+    pub fn oldest_except(&self, keep: VirtualAddress) -> Option<VirtualAddress> {
+        self.by_age.values().copied().find(|&page| page != keep)
+    }
+}
@@ -0,0 +1,177 @@
+use alloc::sync::Arc;
+
+use crate::address_space::AddressSpace;
+use crate::data_source::DataSource;
+use crate::error::MappingError;
+use crate::permissions::Permissions;
+use crate::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::virtual_address::VirtualAddress;
+
+/// A handle to an `AddressSpace` that several threads can use at once.
+///
+/// Cloning the handle gives another reference to the same `AddressSpace`. Lookups and reads
+/// take a shared lock, so they run concurrently with each other; anything that may change the
+/// mappings (including a write, which can resolve a copy-on-write fault) takes an exclusive one.
+#[derive(Clone)]
+pub struct SharedAddressSpace {
+    inner: Arc<RwLock<AddressSpace>>,
+}
+
+impl SharedAddressSpace {
+    #[must_use]
+    pub fn new(addr_space: AddressSpace) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(addr_space)),
+        }
+    }
+
+    /// See `AddressSpace::add_mapping`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::add_mapping`.
+    pub fn add_mapping(
+        &self,
+        source: Arc<dyn DataSource>,
+        offset: usize,
+        span: usize,
+        perms: Permissions,
+    ) -> Result<VirtualAddress, MappingError> {
+        self.write_lock().add_mapping(source, offset, span, perms)
+    }
+
+    /// See `AddressSpace::translate`.
+    #[must_use]
+    pub fn translate(&self, addr: VirtualAddress) -> Option<(Arc<dyn DataSource>, usize)> {
+        self.read_lock().translate(addr)
+    }
+
+    /// See `AddressSpace::read`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::read`.
+    pub fn read(&self, addr: VirtualAddress, buf: &mut [u8]) -> Result<(), MappingError> {
+        self.read_lock().read(addr, buf)
+    }
+
+    /// See `AddressSpace::write`.
+    ///
+    /// # Errors
+    /// As for `AddressSpace::write`.
+    pub fn write(&self, addr: VirtualAddress, buf: &[u8]) -> Result<(), MappingError> {
+        self.write_lock().write(addr, buf)
+    }
+
+    fn read_lock(&self) -> RwLockReadGuard<'_, AddressSpace> {
+        self.inner.read()
+    }
+
+    fn write_lock(&self) -> RwLockWriteGuard<'_, AddressSpace> {
+        self.inner.write()
+    }
+}
+
+impl From<AddressSpace> for SharedAddressSpace {
+    fn from(addr_space: AddressSpace) -> Self {
+        Self::new(addr_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::PAGE_SIZE;
+    use crate::sources::MemorySource;
+    use std::thread;
+    use std::vec::Vec;
+
+    fn memory(len: usize) -> Arc<dyn DataSource> {
+        Arc::new(MemorySource::new((0..len).map(|i| i as u8).collect()))
+    }
+
+    #[test]
+    fn concurrent_translates_during_adds() {
+        let shared = SharedAddressSpace::new(AddressSpace::new("shared"));
+        let first = shared
+            .add_mapping(memory(PAGE_SIZE), 0, PAGE_SIZE, Permissions::READ)
+            .unwrap();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let shared = shared.clone();
+                scope.spawn(move || {
+                    for i in 0..1000 {
+                        let (_, offset) = shared.translate(first + i % PAGE_SIZE).unwrap();
+                        assert_eq!(offset, i % PAGE_SIZE);
+                        let mut buf = [0; 1];
+                        shared.read(first + 7, &mut buf).unwrap();
+                        assert_eq!(buf, [7]);
+                    }
+                });
+            }
+            let shared = shared.clone();
+            scope.spawn(move || {
+                for _ in 0..50 {
+                    let addr = shared
+                        .add_mapping(memory(PAGE_SIZE), 0, PAGE_SIZE, Permissions::READ)
+                        .unwrap();
+                    assert!(shared.translate(addr).is_some());
+                    thread::yield_now();
+                }
+            });
+        });
+
+        let mappings: Vec<_> = shared.read_lock().iter().map(|m| m.addr).collect();
+        assert_eq!(mappings.len(), 51);
+        assert!(mappings.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn concurrent_faulting_reads() {
+        let mut addr_space = AddressSpace::new("demand paged");
+        let base = addr_space.reserve(8 * PAGE_SIZE).unwrap();
+        let backing = memory(8 * PAGE_SIZE);
+        addr_space.on_fault(move |addr_space, addr| {
+            let page = addr.align_down(PAGE_SIZE);
+            addr_space.add_mapping_at(
+                backing.clone(),
+                page - base,
+                PAGE_SIZE,
+                page,
+                Permissions::READ,
+            )
+        });
+        let shared = SharedAddressSpace::new(addr_space);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                let shared = shared.clone();
+                scope.spawn(move || {
+                    for page in 0..8 {
+                        let mut buf = [0; 1];
+                        shared.read(base + page * PAGE_SIZE + 3, &mut buf).unwrap();
+                        assert_eq!(buf, [3]);
+                    }
+                });
+            }
+        });
+
+        // each page was mapped once, however many reads faulted on it
+        assert_eq!(shared.read_lock().mapping_count(), 8);
+    }
+
+    #[test]
+    fn writes_are_visible_to_other_handles() {
+        let shared = SharedAddressSpace::from(AddressSpace::new("shared writes"));
+        let rw = Permissions::READ | Permissions::WRITE;
+        let addr = shared
+            .add_mapping(memory(PAGE_SIZE), 0, PAGE_SIZE, rw)
+            .unwrap();
+        let other = shared.clone();
+        thread::spawn(move || other.write(addr, &[0xaa; 8]).unwrap())
+            .join()
+            .unwrap();
+        let mut buf = [0; 8];
+        shared.read(addr, &mut buf).unwrap();
+        assert_eq!(buf, [0xaa; 8]);
+    }
+}
@@ -0,0 +1,227 @@
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::address_space::AddressSpace;
+use crate::data_source::DataSource;
+use crate::error::MappingError;
+use crate::permissions::Permissions;
+use crate::virtual_address::VirtualAddress;
+
+/// The layout of one mapping, with its `DataSource` replaced by that source's `source_id`, so
+/// that it can be saved and the space rebuilt later. With the `serde` feature it can be
+/// serialized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MappingSnapshot {
+    pub addr: VirtualAddress,
+    pub span: usize,
+    pub offset: usize,
+    pub perms: Permissions,
+    pub source_id: String,
+}
+
+/// The name and layout of a whole `AddressSpace`, as recorded by `AddressSpace::to_snapshot`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AddressSpaceSnapshot {
+    pub name: String,
+    /// The mappings, in address order.
+    pub mappings: Vec<MappingSnapshot>,
+}
+
+impl AddressSpace {
+    /// Record the name of this `AddressSpace` and the layout of every mapping.
+    ///
+    /// # Errors
+    /// `SourceGone` naming the first mapping whose source was held weakly and has been dropped,
+    /// since there is no source left to record.
+    pub fn to_snapshot(&self) -> Result<AddressSpaceSnapshot, MappingError> {
+        let mappings = self
+            .iter()
+            .map(|mapping| {
+                let source = mapping
+                    .source
+                    .ok_or(MappingError::SourceGone { addr: mapping.addr })?;
+                Ok(MappingSnapshot {
+                    addr: mapping.addr,
+                    span: mapping.span,
+                    offset: mapping.offset,
+                    perms: mapping.perms,
+                    source_id: source.source_id(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(AddressSpaceSnapshot {
+            name: self.name().to_string(),
+            mappings,
+        })
+    }
+
+    /// Rebuild an `AddressSpace` from a layout recorded by `to_snapshot`, calling `resolver` to
+    /// find the `DataSource` for each mapping's `source_id`. Mappings that shared a source when
+    /// the snapshot was taken only share one again if `resolver` returns the same `Arc` for them.
+    ///
+    /// # Errors
+    /// As for `add_mapping_at`, if the snapshot's mappings don't describe a valid layout.
+    pub fn from_snapshot(
+        snapshot: &AddressSpaceSnapshot,
+        resolver: impl Fn(&str) -> Arc<dyn DataSource>,
+    ) -> Result<Self, MappingError> {
+        let mut addr_space = Self::new(&snapshot.name);
+        for snap in &snapshot.mappings {
+            let source = resolver(&snap.source_id);
+            // the recorded span is whole pages; map no more than the source has, which rounds
+            // back up to the same span
+            let span = snap.span.min(source.len().saturating_sub(snap.offset));
+            addr_space.add_mapping_at(source, snap.offset, span, snap.addr, snap.perms)?;
+        }
+        Ok(addr_space)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::PAGE_SIZE;
+    #[cfg(feature = "std")]
+    use crate::sources::FileSource;
+    use crate::sources::MemorySource;
+    #[cfg(feature = "std")]
+    use std::collections::HashMap;
+
+    const P: usize = PAGE_SIZE;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn round_trip() {
+        let file: Arc<dyn DataSource> = Arc::new(FileSource::new("Cargo.toml").unwrap());
+        let memory: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![7; 4 * P]));
+        let mut original = AddressSpace::new("original");
+        original
+            .add_mapping_at(
+                file.clone(),
+                0,
+                100,
+                VirtualAddress::new(P),
+                Permissions::READ,
+            )
+            .unwrap();
+        original
+            .add_mapping_at(
+                memory.clone(),
+                P,
+                3 * P,
+                VirtualAddress::new(4 * P),
+                Permissions::READ | Permissions::WRITE,
+            )
+            .unwrap();
+        original.unmap(VirtualAddress::new(5 * P), P).unwrap();
+
+        let snapshot = original.to_snapshot().unwrap();
+        assert_eq!(snapshot.name, "original");
+        let snaps = &snapshot.mappings;
+        assert_eq!(snaps.len(), 3);
+        assert_eq!(snaps[0].source_id, "Cargo.toml");
+        assert_eq!(snaps[1].source_id, snaps[2].source_id);
+
+        let sources = HashMap::from([
+            (file.source_id(), file.clone()),
+            (memory.source_id(), memory.clone()),
+        ]);
+        let rebuilt = AddressSpace::from_snapshot(&snapshot, |id| sources[id].clone()).unwrap();
+        assert_eq!(rebuilt.to_snapshot().unwrap(), snapshot);
+        for snap in snaps {
+            let (source, offset) = rebuilt.translate(snap.addr).unwrap();
+            assert_eq!(offset, snap.offset);
+            assert!(Arc::ptr_eq(&source, &sources[&snap.source_id]));
+        }
+    }
+
+    #[test]
+    fn name_survives_round_trip() {
+        let memory: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![0; P]));
+        let mut original = AddressSpace::new("original");
+        original
+            .add_mapping(memory.clone(), 0, P, Permissions::READ)
+            .unwrap();
+        original.rename("renamed");
+
+        let snapshot = original.to_snapshot().unwrap();
+        assert_eq!(snapshot.name, "renamed");
+        let rebuilt = AddressSpace::from_snapshot(&snapshot, |_| memory.clone()).unwrap();
+        assert_eq!(rebuilt.name(), "renamed");
+        assert!(rebuilt.to_string().starts_with("renamed:\n"));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        let memory: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![3; 4 * P]));
+        let mut original = AddressSpace::new("saved");
+        original
+            .add_mapping_at(
+                memory.clone(),
+                0,
+                2 * P,
+                VirtualAddress::new(P),
+                Permissions::READ,
+            )
+            .unwrap();
+        original
+            .add_mapping_at(
+                memory.clone(),
+                2 * P,
+                2 * P,
+                VirtualAddress::new(8 * P),
+                Permissions::READ | Permissions::WRITE,
+            )
+            .unwrap();
+
+        let json = serde_json::to_string(&original.to_snapshot().unwrap()).unwrap();
+        let snapshot: AddressSpaceSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(snapshot, original.to_snapshot().unwrap());
+        let rebuilt = AddressSpace::from_snapshot(&snapshot, |_| memory.clone()).unwrap();
+        assert_eq!(rebuilt.name(), "saved");
+        assert_eq!(rebuilt.to_snapshot().unwrap(), snapshot);
+        let (source, offset) = rebuilt.translate(VirtualAddress::new(9 * P)).unwrap();
+        assert_eq!(offset, 3 * P);
+        assert!(Arc::ptr_eq(&source, &memory));
+    }
+
+    #[test]
+    fn dropped_source_is_not_recorded() {
+        let memory: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![0; P]));
+        let mut original = AddressSpace::new("weak");
+        let addr = original
+            .add_mapping_weak(&memory, 0, P, Permissions::READ)
+            .unwrap();
+        drop(memory);
+        assert_eq!(
+            original.to_snapshot(),
+            Err(MappingError::SourceGone { addr })
+        );
+    }
+
+    #[test]
+    fn overlapping_snapshot_is_rejected() {
+        let memory: Arc<dyn DataSource> = Arc::new(MemorySource::new(vec![0; 2 * P]));
+        let snap = |addr| MappingSnapshot {
+            addr,
+            span: 2 * P,
+            offset: 0,
+            perms: Permissions::READ,
+            source_id: memory.source_id(),
+        };
+        let snapshot = AddressSpaceSnapshot {
+            name: "overlapping".to_string(),
+            mappings: vec![snap(VirtualAddress::new(0)), snap(VirtualAddress::new(P))],
+        };
+        assert_eq!(
+            AddressSpace::from_snapshot(&snapshot, |_| memory.clone()).err(),
+            Some(MappingError::Overlap {
+                addr: VirtualAddress::new(0)
+            })
+        );
+    }
+}
@@ -0,0 +1,183 @@
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use core::ops::Range;
+
+use crate::address_space::PAGE_SIZE;
+use crate::data_source::{clamp_to_len, DataSource};
+use crate::error::DataSourceError;
+use crate::sync::RwLock;
+
+/// Anonymous, zero-filled memory, like `mmap(MAP_ANONYMOUS)`.
+///
+/// Pages are only allocated when first written, so any part of an `AnonymousSource` that hasn't
+/// been written reads as zeros without taking up memory.
+pub struct AnonymousSource {
+    len: usize,
+    /// Pages that have been written, keyed by page number.
+    pages: RwLock<BTreeMap<usize, Box<[u8]>>>,
+}
+
+impl AnonymousSource {
+    /// Create a new `AnonymousSource` of `len` bytes, all zero.
+    #[must_use]
+    pub const fn new(len: usize) -> Self {
+        Self {
+            len,
+            pages: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Check that `[offset, offset + length)` is within this source.
+    fn check_bounds(&self, offset: usize, length: usize) -> Result<(), &str> {
+        if offset
+            .checked_add(length)
+            .is_some_and(|end| end <= self.len)
+        {
+            Ok(())
+        } else {
+            Err("access past end of anonymous memory")
+        }
+    }
+}
+
+/// What every page that hasn't been written holds.
+static ZEROS: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// Split `[offset, offset + length)` into pieces that each lie within one page, yielding the
+/// page number, the piece's range within that page, and its range relative to `offset`.
+fn pages(
+    offset: usize,
+    length: usize,
+) -> impl Iterator<Item = (usize, Range<usize>, Range<usize>)> {
+    let mut done = 0;
+    core::iter::from_fn(move || {
+        if done == length {
+            return None;
+        }
+        let pos = offset + done;
+        let in_page = pos % PAGE_SIZE;
+        let len = (PAGE_SIZE - in_page).min(length - done);
+        let piece = (pos / PAGE_SIZE, in_page..in_page + len, done..done + len);
+        done += len;
+        Some(piece)
+    })
+}
+
+impl DataSource for AnonymousSource {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+        let length = clamp_to_len(self.len, offset, buf.len())?;
+        let pages_written = self.pages.read();
+        for (page, in_page, in_buffer) in pages(offset, length) {
+            match pages_written.get(&page) {
+                Some(data) => buf[in_buffer].copy_from_slice(&data[in_page]),
+                None => buf[in_buffer].fill(0),
+            }
+        }
+        Ok(length)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+        let length = clamp_to_len(self.len, offset, buf.len())?;
+        let mut pages_written = self.pages.write();
+        for (page, in_page, in_buffer) in pages(offset, length) {
+            pages_written
+                .entry(page)
+                .or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice())[in_page]
+                .copy_from_slice(&buf[in_buffer]);
+        }
+        Ok(length)
+    }
+    /// Only ranges within a single page are held contiguously.
+    fn with_slice(&self, offset: usize, length: usize, visit: &mut dyn FnMut(&[u8])) -> bool {
+        if self.check_bounds(offset, length).is_err() {
+            return false;
+        }
+        let mut pieces = pages(offset, length);
+        let (Some((page, in_page, _)), None) = (pieces.next(), pieces.next()) else {
+            return false;
+        };
+        match self.pages.read().get(&page) {
+            Some(data) => visit(&data[in_page]),
+            None => visit(&ZEROS[in_page]),
+        }
+        true
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressSpace, Permissions};
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    #[test]
+    fn reads_zero_until_written() {
+        let mut addr_space = AddressSpace::new("anonymous");
+        let rw = Permissions::READ | Permissions::WRITE;
+        let addr = addr_space
+            .add_mapping(
+                Arc::new(AnonymousSource::new(3 * PAGE_SIZE)),
+                0,
+                3 * PAGE_SIZE,
+                rw,
+            )
+            .unwrap();
+
+        let mut buf = vec![0xff; 3 * PAGE_SIZE];
+        addr_space.read(addr, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+
+        let pattern: Vec<u8> = (0..=255).cycle().take(PAGE_SIZE + 10).collect();
+        addr_space.write(addr + PAGE_SIZE - 5, &pattern).unwrap();
+
+        let mut back = vec![0; pattern.len()];
+        addr_space.read(addr + PAGE_SIZE - 5, &mut back).unwrap();
+        assert_eq!(back, pattern);
+
+        // the bytes around the write are still zero
+        let mut edges = [0xff; 2];
+        addr_space
+            .read(addr + PAGE_SIZE - 6, &mut edges[..1])
+            .unwrap();
+        addr_space
+            .read(addr + 2 * PAGE_SIZE + 5, &mut edges[1..])
+            .unwrap();
+        assert_eq!(edges, [0, 0]);
+    }
+
+    #[test]
+    fn only_written_pages_are_allocated() {
+        let source = AnonymousSource::new(1 << 30);
+        source.write(5 * PAGE_SIZE + 1, 2, &[1, 2]).unwrap();
+        assert_eq!(source.pages.read().len(), 1);
+        let mut buf = [0xff; 4];
+        source.read(5 * PAGE_SIZE, 4, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn with_slice_covers_one_page_at_a_time() {
+        let source = AnonymousSource::new(2 * PAGE_SIZE);
+        source.write(PAGE_SIZE - 1, 2, &[1, 2]).unwrap();
+        let mut seen = Vec::new();
+        assert!(source.with_slice(PAGE_SIZE - 2, 2, &mut |bytes| seen.extend_from_slice(bytes)));
+        assert!(source.with_slice(PAGE_SIZE, 2, &mut |bytes| seen.extend_from_slice(bytes)));
+        assert_eq!(seen, [0, 1, 2, 0]);
+        assert!(!source.with_slice(PAGE_SIZE - 1, 2, &mut |_| unreachable!()));
+        assert!(!source.with_slice(2 * PAGE_SIZE, 1, &mut |_| unreachable!()));
+    }
+
+    #[test]
+    fn accesses_past_the_end_fail() {
+        let source = AnonymousSource::new(PAGE_SIZE);
+        assert_eq!(source.len(), PAGE_SIZE);
+        let mut buf = [0; 8];
+        assert!(source.read(PAGE_SIZE - 4, 8, &mut buf).is_err());
+        assert!(source.write(PAGE_SIZE, 1, &buf).is_err());
+        assert!(source.read(usize::MAX, 8, &mut buf).is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::data_source::{clamp_to_len, DataSource};
+use crate::error::DataSourceError;
+
+/// Several sources laid end to end and accessed as one, e.g. a segment assembled from more than
+/// one backing store. Accesses that straddle the boundary between two parts are split between
+/// them.
+pub struct ConcatSource {
+    parts: Vec<Arc<dyn DataSource>>,
+}
+
+impl ConcatSource {
+    #[must_use]
+    pub const fn new(parts: Vec<Arc<dyn DataSource>>) -> Self {
+        Self { parts }
+    }
+
+    /// Check that `[offset, offset + length)` is within this source.
+    fn check_bounds(&self, offset: usize, length: usize) -> Result<(), &str> {
+        if offset
+            .checked_add(length)
+            .is_some_and(|end| end <= self.len())
+        {
+            Ok(())
+        } else {
+            Err("access past end of concatenated sources")
+        }
+    }
+
+    /// Split `[offset, offset + length)` into pieces that each lie within one part, yielding
+    /// the part, the offset of the piece within it, and the piece's range relative to `offset`.
+    fn pieces(
+        &self,
+        offset: usize,
+        length: usize,
+    ) -> impl Iterator<Item = (&dyn DataSource, usize, Range<usize>)> {
+        let end = offset + length;
+        let mut part_end = 0;
+        self.parts.iter().filter_map(move |part| {
+            let part_start = part_end;
+            part_end += part.len();
+            let (lo, hi) = (offset.max(part_start), end.min(part_end));
+            (lo < hi).then(|| (&**part, lo - part_start, lo - offset..hi - offset))
+        })
+    }
+}
+
+impl DataSource for ConcatSource {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+        let length = clamp_to_len(self.len(), offset, buf.len())?;
+        for (part, part_offset, piece) in self.pieces(offset, length) {
+            let read = part.read_at(part_offset, &mut buf[piece.clone()])?;
+            // a part that comes up short ends the read there
+            if read < piece.len() {
+                return Ok(piece.start + read);
+            }
+        }
+        Ok(length)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+        let length = clamp_to_len(self.len(), offset, buf.len())?;
+        for (part, part_offset, piece) in self.pieces(offset, length) {
+            let written = part.write_at(part_offset, &buf[piece.clone()])?;
+            if written < piece.len() {
+                return Ok(piece.start + written);
+            }
+        }
+        Ok(length)
+    }
+    fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+        self.check_bounds(offset, length)?;
+        for (part, part_offset, piece) in self.pieces(offset, length) {
+            part.flush(part_offset, piece.len())?;
+        }
+        Ok(())
+    }
+    fn len(&self) -> usize {
+        self.parts.iter().map(|part| part.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::MemorySource;
+
+    /// Parts of 3, 5 and 2 bytes, holding 0..3, 10..15 and 20..22.
+    fn three_parts() -> (ConcatSource, Vec<Arc<dyn DataSource>>) {
+        let parts: Vec<Arc<dyn DataSource>> = vec![
+            Arc::new(MemorySource::new(vec![0, 1, 2])),
+            Arc::new(MemorySource::new(vec![10, 11, 12, 13, 14])),
+            Arc::new(MemorySource::new(vec![20, 21])),
+        ];
+        (ConcatSource::new(parts.clone()), parts)
+    }
+
+    #[test]
+    fn read_across_boundaries() {
+        let (source, _) = three_parts();
+        assert_eq!(source.len(), 10);
+        let mut buf = [0; 10];
+        source.read(0, 10, &mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 10, 11, 12, 13, 14, 20, 21]);
+
+        let mut buf = [0; 7];
+        assert_eq!(source.read_at(2, &mut buf), Ok(7));
+        assert_eq!(buf, [2, 10, 11, 12, 13, 14, 20]);
+        assert_eq!(source.read_at(7, &mut buf), Ok(3));
+        assert_eq!(&buf[..3], &[14, 20, 21]);
+        assert!(source.read(8, 3, &mut buf).is_err());
+    }
+
+    #[test]
+    fn writes_land_in_the_right_part() {
+        let (source, parts) = three_parts();
+        source.write(4, 5, &[0xa, 0xb, 0xc, 0xd, 0xe]).unwrap();
+        let mut buf = [0; 5];
+        parts[1].read(0, 5, &mut buf).unwrap();
+        assert_eq!(buf, [10, 0xa, 0xb, 0xc, 0xd]);
+        let mut buf = [0; 2];
+        parts[2].read(0, 2, &mut buf).unwrap();
+        assert_eq!(buf, [0xe, 21]);
+        parts[0].read(1, 2, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2]);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::data_source::{clamp_to_len, DataSource};
+use crate::error::DataSourceError;
+
+/// A file, accessed relative to the offset of whatever mapping it backs.
+pub struct FileSource {
+    /// Reads and writes each seek before they access the file, so they take turns.
+    file_handle: Mutex<File>,
+    name: String,
+    writable: bool,
+}
+
+impl FileSource {
+    /// Open the file at `name` read-only. Writes to the resulting source fail.
+    ///
+    /// # Errors
+    /// A message naming the file if it can't be opened.
+    pub fn new(name: &str) -> Result<Self, String> {
+        File::open(name)
+            .map(|file| Self::from_file(file, name, false))
+            .map_err(|_| format!("couldn't open {name}"))
+    }
+
+    /// Open the file at `name` for reading and writing.
+    ///
+    /// # Errors
+    /// A message naming the file if it can't be opened for writing.
+    pub fn new_writable(name: &str) -> Result<Self, String> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(name)
+            .map(|file| Self::from_file(file, name, true))
+            .map_err(|_| format!("couldn't open {name} for writing"))
+    }
+
+    fn from_file(file: File, name: &str, writable: bool) -> Self {
+        Self {
+            file_handle: Mutex::new(file),
+            name: name.to_string(),
+            writable,
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, File>, DataSourceError> {
+        self.file_handle
+            .lock()
+            .map_err(|_| DataSourceError::Failed("file poisoned".to_string()))
+    }
+
+    /// The path this source was opened from.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl DataSource for FileSource {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+        let length = clamp_to_len(self.len(), offset, buf.len())?;
+        let mut file = self.lock()?;
+        file.seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| file.read_exact(&mut buf[..length]))
+            .map_err(|err| DataSourceError::Failed(err.to_string()))?;
+        Ok(length)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+        if !self.writable {
+            return Err(DataSourceError::ReadOnly);
+        }
+        let length = clamp_to_len(self.len(), offset, buf.len())?;
+        let mut file = self.lock()?;
+        file.seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| file.write_all(&buf[..length]))
+            .map_err(|err| DataSourceError::Failed(err.to_string()))?;
+        Ok(length)
+    }
+    fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+        self.file_handle
+            .lock()
+            .map_err(|_| "file poisoned")?
+            .sync_data()
+            .map_err(|_| "couldn't flush file")
+    }
+    fn len(&self) -> usize {
+        self.file_handle
+            .lock()
+            .ok()
+            .and_then(|file| file.metadata().ok())
+            .map_or(0, |metadata| {
+                usize::try_from(metadata.len()).unwrap_or(usize::MAX)
+            })
+    }
+
+    fn source_id(&self) -> String {
+        self.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AddressSpace, MappingError, Permissions, PAGE_SIZE};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// A scratch file holding `contents`, removed when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("reedos-file-source-{}-{name}", std::process::id()));
+            std::fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn contents() -> Vec<u8> {
+        (0..=255).cycle().take(3 * PAGE_SIZE).collect()
+    }
+
+    #[test]
+    fn open_errors_name_the_file() {
+        let scratch = Scratch::new("missing", &[]);
+        let missing = scratch.path().to_string();
+        drop(scratch);
+        assert_eq!(
+            FileSource::new(&missing).err(),
+            Some(format!("couldn't open {missing}"))
+        );
+        assert_eq!(
+            FileSource::new_writable(&missing).err(),
+            Some(format!("couldn't open {missing} for writing"))
+        );
+    }
+
+    #[test]
+    fn read_through_mapping() {
+        let scratch = Scratch::new("read", &contents());
+        let source: Arc<dyn DataSource> = Arc::new(FileSource::new(scratch.path()).unwrap());
+        assert_eq!(source.len(), 3 * PAGE_SIZE);
+
+        let mut addr_space = AddressSpace::new("file");
+        let addr = addr_space
+            .add_mapping(source.clone(), PAGE_SIZE, PAGE_SIZE, Permissions::READ)
+            .unwrap();
+
+        let (found, offset) = addr_space.translate(addr + 10).unwrap();
+        assert!(Arc::ptr_eq(&found, &source));
+        assert_eq!(offset, PAGE_SIZE + 10);
+
+        let mut buf = [0; 32];
+        addr_space.read(addr + 10, &mut buf).unwrap();
+        assert_eq!(buf[..], contents()[offset..offset + 32]);
+    }
+
+    #[test]
+    fn read_only_file_rejects_writes() {
+        let scratch = Scratch::new("read-only", &contents());
+        let mut addr_space = AddressSpace::new("read-only file");
+        let rw = Permissions::READ | Permissions::WRITE;
+        let addr = addr_space
+            .add_mapping(
+                Arc::new(FileSource::new(scratch.path()).unwrap()),
+                0,
+                16,
+                rw,
+            )
+            .unwrap();
+        assert_eq!(
+            addr_space.write(addr, b"nope"),
+            Err(MappingError::Source { addr })
+        );
+        assert_eq!(std::fs::read(&scratch.0).unwrap(), contents());
+    }
+
+    #[test]
+    fn writable_file_takes_writes() {
+        let scratch = Scratch::new("writable", &contents());
+        let mut addr_space = AddressSpace::new("writable file");
+        let rw = Permissions::READ | Permissions::WRITE;
+        let source = FileSource::new_writable(scratch.path()).unwrap();
+        let addr = addr_space
+            .add_mapping(Arc::new(source), PAGE_SIZE, PAGE_SIZE, rw)
+            .unwrap();
+        addr_space.write(addr + 4, b"written").unwrap();
+
+        let mut expected = contents();
+        expected[PAGE_SIZE + 4..PAGE_SIZE + 11].copy_from_slice(b"written");
+        assert_eq!(std::fs::read(&scratch.0).unwrap(), expected);
+    }
+
+    #[test]
+    fn flushed_writes_reach_the_file() {
+        let scratch = Scratch::new("flush", &contents());
+        let mut addr_space = AddressSpace::new("flushed file");
+        let rw = Permissions::READ | Permissions::WRITE;
+        let source = FileSource::new_writable(scratch.path()).unwrap();
+        let addr = addr_space
+            .add_mapping(Arc::new(source), 0, 3 * PAGE_SIZE, rw)
+            .unwrap();
+        addr_space.write(addr + PAGE_SIZE - 3, b"flushed").unwrap();
+        addr_space.flush(addr, 3 * PAGE_SIZE).unwrap();
+
+        let reopened = FileSource::new(scratch.path()).unwrap();
+        let mut buf = [0; 7];
+        reopened.read(PAGE_SIZE - 3, 7, &mut buf).unwrap();
+        assert_eq!(&buf, b"flushed");
+    }
+}
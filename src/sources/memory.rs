@@ -0,0 +1,114 @@
+use alloc::vec::Vec;
+
+use crate::data_source::{clamp_to_len, DataSource};
+use crate::error::DataSourceError;
+use crate::sync::RwLock;
+
+/// A fixed-size buffer of bytes held in memory.
+pub struct MemorySource {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemorySource {
+    /// Create a new `MemorySource` holding `bytes`. Its length never changes.
+    #[must_use]
+    pub const fn new(bytes: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(bytes),
+        }
+    }
+}
+
+impl DataSource for MemorySource {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+        let data = self.data.read();
+        let length = clamp_to_len(data.len(), offset, buf.len())?;
+        buf[..length].copy_from_slice(&data[offset..offset + length]);
+        Ok(length)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+        let mut data = self.data.write();
+        let length = clamp_to_len(data.len(), offset, buf.len())?;
+        data[offset..offset + length].copy_from_slice(&buf[..length]);
+        Ok(length)
+    }
+    fn with_slice(&self, offset: usize, length: usize, visit: &mut dyn FnMut(&[u8])) -> bool {
+        let data = self.data.read();
+        let Some(bytes) = offset
+            .checked_add(length)
+            .and_then(|end| data.get(offset..end))
+        else {
+            return false;
+        };
+        visit(bytes);
+        true
+    }
+    fn len(&self) -> usize {
+        self.data.read().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_past_len_fails() {
+        let source = MemorySource::new(vec![1, 2, 3, 4]);
+        assert_eq!(source.len(), 4);
+        let mut buf = [0; 4];
+        source.read(0, 4, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert!(source.read(1, 4, &mut buf).is_err());
+        assert!(source.read(5, 0, &mut buf).is_err());
+        assert!(source.read(usize::MAX, 2, &mut buf).is_err());
+        assert!(source.write(3, 2, &buf).is_err());
+    }
+
+    #[test]
+    fn writes_are_visible_to_reads() {
+        let source = MemorySource::new(vec![0; 8]);
+        source.write(2, 3, b"abc").unwrap();
+        let mut buf = [0; 5];
+        source.read(1, 5, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0abc\0");
+    }
+
+    #[test]
+    fn with_slice_sees_the_bytes_in_place() {
+        let source = MemorySource::new(vec![1, 2, 3, 4, 5]);
+        let mut seen = Vec::new();
+        assert!(source.with_slice(1, 3, &mut |bytes| seen.extend_from_slice(bytes)));
+        assert_eq!(seen, [2, 3, 4]);
+        assert!(!source.with_slice(3, 3, &mut |_| unreachable!()));
+    }
+
+    #[test]
+    fn read_at_stops_at_the_tail() {
+        let source = MemorySource::new(vec![1, 2, 3, 4, 5]);
+        let mut buf = [0; 4];
+        assert_eq!(source.read_at(0, &mut buf), Ok(4));
+        assert_eq!(buf, [1, 2, 3, 4]);
+        assert_eq!(source.read_at(3, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], &[4, 5]);
+        assert_eq!(source.read_at(5, &mut buf), Ok(0));
+        assert_eq!(
+            source.read_at(6, &mut buf),
+            Err(DataSourceError::OutOfBounds { offset: 6 })
+        );
+    }
+
+    #[test]
+    fn write_at_stops_at_the_tail() {
+        let source = MemorySource::new(vec![0; 6]);
+        assert_eq!(source.write_at(4, b"abc"), Ok(2));
+        assert_eq!(source.write_at(6, b"abc"), Ok(0));
+        assert_eq!(
+            source.write_at(usize::MAX, b"abc"),
+            Err(DataSourceError::OutOfBounds { offset: usize::MAX })
+        );
+        let mut buf = [0; 6];
+        source.read_at(0, &mut buf).unwrap();
+        assert_eq!(&buf, b"\0\0\0\0ab");
+    }
+}
@@ -0,0 +1,17 @@
+// Concrete `DataSource`s that an `AddressSpace` can map.
+
+mod anonymous;
+mod concat;
+#[cfg(feature = "std")]
+mod file;
+mod memory;
+mod read_only;
+mod sub_range;
+
+pub use anonymous::AnonymousSource;
+pub use concat::ConcatSource;
+#[cfg(feature = "std")]
+pub use file::FileSource;
+pub use memory::MemorySource;
+pub use read_only::ReadOnly;
+pub use sub_range::SubRange;
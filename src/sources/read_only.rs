@@ -0,0 +1,82 @@
+use alloc::string::String;
+
+use crate::data_source::DataSource;
+use crate::error::DataSourceError;
+
+/// Wraps another `DataSource` so that it can't be written through, however it is mapped.
+pub struct ReadOnly<D>(D);
+
+impl<D: DataSource> ReadOnly<D> {
+    #[must_use]
+    pub const fn new(source: D) -> Self {
+        Self(source)
+    }
+
+    /// Unwrap the source, making it writable again.
+    pub fn into_inner(self) -> D {
+        self.0
+    }
+}
+
+impl<D: DataSource> DataSource for ReadOnly<D> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+        self.0.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+        Err(DataSourceError::ReadOnly)
+    }
+    fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+        // nothing can have been written through this wrapper
+        Ok(())
+    }
+    fn with_slice(&self, offset: usize, length: usize, visit: &mut dyn FnMut(&[u8])) -> bool {
+        self.0.with_slice(offset, length, visit)
+    }
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+    fn source_id(&self) -> String {
+        self.0.source_id()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_space::{AddressSpace, PAGE_SIZE};
+    use crate::error::MappingError;
+    use crate::permissions::Permissions;
+    use crate::sources::MemorySource;
+    use std::sync::Arc;
+
+    #[test]
+    fn reads_pass_through_and_writes_fail() {
+        let source = ReadOnly::new(MemorySource::new(vec![1, 2, 3]));
+        let mut buf = [0; 3];
+        assert_eq!(source.read_at(0, &mut buf), Ok(3));
+        assert_eq!(buf, [1, 2, 3]);
+        assert_eq!(source.write_at(0, &buf), Err(DataSourceError::ReadOnly));
+        assert!(source.write(0, 3, &buf).is_err());
+        assert_eq!(source.len(), 3);
+
+        let inner = source.into_inner();
+        inner.write(0, 1, &[9]).unwrap();
+    }
+
+    #[test]
+    fn writable_mapping_of_read_only_source() {
+        let mut addr_space = AddressSpace::new("read-only source");
+        let source = Arc::new(ReadOnly::new(MemorySource::new(vec![7; PAGE_SIZE])));
+        let addr = addr_space
+            .add_mapping(source, 0, PAGE_SIZE, Permissions::READ | Permissions::WRITE)
+            .unwrap();
+        assert_eq!(addr_space.check_access(addr, Permissions::WRITE), Ok(()));
+        assert_eq!(
+            addr_space.write(addr + 1, &[0; 4]),
+            Err(MappingError::Source { addr: addr + 1 })
+        );
+        let mut buf = [0; 4];
+        addr_space.read(addr, &mut buf).unwrap();
+        assert_eq!(buf, [7; 4]);
+    }
+}
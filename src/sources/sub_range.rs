@@ -0,0 +1,119 @@
+use alloc::sync::Arc;
+
+use crate::data_source::{clamp_to_len, DataSource};
+use crate::error::DataSourceError;
+
+/// A window of `len` bytes into another source, starting at `start`, so that a mapping of the
+/// window can use offsets relative to it.
+pub struct SubRange {
+    inner: Arc<dyn DataSource>,
+    start: usize,
+    len: usize,
+}
+
+impl SubRange {
+    /// The window `[start, start + len)` of `inner`. It may extend past the end of `inner`,
+    /// but accesses to that part fail.
+    #[must_use]
+    pub fn new(inner: Arc<dyn DataSource>, start: usize, len: usize) -> Self {
+        Self { inner, start, len }
+    }
+
+    /// The offset into the inner source of `[offset, offset + length)`, if that lies within
+    /// the window and the inner offset doesn't overflow.
+    fn inner_offset(&self, offset: usize, length: usize) -> Result<usize, &str> {
+        if offset
+            .checked_add(length)
+            .is_some_and(|end| end <= self.len)
+        {
+            self.start
+                .checked_add(offset)
+                .ok_or("sub-range offset overflows")
+        } else {
+            Err("access outside sub-range")
+        }
+    }
+}
+
+impl DataSource for SubRange {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize, DataSourceError> {
+        let length = clamp_to_len(self.len, offset, buf.len())?;
+        let inner = self
+            .inner_offset(offset, length)
+            .map_err(|_| DataSourceError::OutOfBounds { offset })?;
+        self.inner.read_at(inner, &mut buf[..length])
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize, DataSourceError> {
+        let length = clamp_to_len(self.len, offset, buf.len())?;
+        let inner = self
+            .inner_offset(offset, length)
+            .map_err(|_| DataSourceError::OutOfBounds { offset })?;
+        self.inner.write_at(inner, &buf[..length])
+    }
+    fn flush(&self, offset: usize, length: usize) -> Result<(), &str> {
+        let offset = self.inner_offset(offset, length)?;
+        self.inner.flush(offset, length)
+    }
+    fn with_slice(&self, offset: usize, length: usize, visit: &mut dyn FnMut(&[u8])) -> bool {
+        self.inner_offset(offset, length)
+            .is_ok_and(|offset| self.inner.with_slice(offset, length, visit))
+    }
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sources::MemorySource;
+
+    fn window() -> (SubRange, Arc<dyn DataSource>) {
+        let inner: Arc<dyn DataSource> = Arc::new(MemorySource::new((0..16).collect()));
+        (SubRange::new(inner.clone(), 4, 8), inner)
+    }
+
+    #[test]
+    fn reads_are_relative_to_start() {
+        let (source, _) = window();
+        assert_eq!(source.len(), 8);
+        let mut buf = [0; 4];
+        source.read(0, 4, &mut buf).unwrap();
+        assert_eq!(buf, [4, 5, 6, 7]);
+        assert_eq!(source.read_at(6, &mut buf), Ok(2));
+        assert_eq!(&buf[..2], &[10, 11]);
+    }
+
+    #[test]
+    fn accesses_outside_the_window_fail() {
+        let (source, inner) = window();
+        let mut buf = [0; 4];
+        assert!(source.read(6, 4, &mut buf).is_err());
+        assert!(source.write(usize::MAX, 1, &buf).is_err());
+        assert_eq!(
+            source.read_at(9, &mut buf),
+            Err(DataSourceError::OutOfBounds { offset: 9 })
+        );
+
+        source.write(0, 2, &[0xff, 0xff]).unwrap();
+        inner.read(3, 4, &mut buf).unwrap();
+        assert_eq!(buf, [3, 0xff, 0xff, 6]);
+    }
+
+    #[test]
+    fn window_past_usize_max_fails() {
+        let inner: Arc<dyn DataSource> = Arc::new(MemorySource::new((0..16).collect()));
+        let source = SubRange::new(inner, usize::MAX - 1, 8);
+        let mut buf = [0; 4];
+        assert_eq!(
+            source.read_at(4, &mut buf),
+            Err(DataSourceError::OutOfBounds { offset: 4 })
+        );
+        assert_eq!(
+            source.write_at(2, &buf),
+            Err(DataSourceError::OutOfBounds { offset: 2 })
+        );
+        assert!(source.flush(4, 1).is_err());
+        assert!(!source.with_slice(4, 1, &mut |_| ()));
+    }
+}
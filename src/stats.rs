@@ -0,0 +1,96 @@
+/// How many times a mapping has been accessed, as returned by `AddressSpace::access_stats`.
+///
+/// Each `read` or `write` counts once for every mapping it touches, and each `translate` once
+/// for the mapping it finds.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AccessStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub translates: u64,
+}
+
+#[cfg(feature = "stats")]
+mod counting {
+    use core::sync::atomic::{AtomicU64, Ordering};
+
+    use super::AccessStats;
+
+    /// The live counters behind a mapping's `AccessStats`. They're atomic so that accesses
+    /// through `&AddressSpace` can count too.
+    #[derive(Default)]
+    pub struct AccessCounters {
+        reads: AtomicU64,
+        writes: AtomicU64,
+        translates: AtomicU64,
+    }
+
+    impl AccessCounters {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn record_read(&self) {
+            self.reads.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn record_write(&self) {
+            self.writes.fetch_add(1, Ordering::Relaxed);
+        }
+
+        pub fn record_translate(&self) {
+            self.translates.fetch_add(1, Ordering::Relaxed);
+        }
+
+        /// Add `other`'s counts to these, for when two mappings are merged into one.
+        pub fn absorb(&mut self, other: &Self) {
+            let other = other.get();
+            *self.reads.get_mut() += other.reads;
+            *self.writes.get_mut() += other.writes;
+            *self.translates.get_mut() += other.translates;
+        }
+
+        pub fn get(&self) -> AccessStats {
+            AccessStats {
+                reads: self.reads.load(Ordering::Relaxed),
+                writes: self.writes.load(Ordering::Relaxed),
+                translates: self.translates.load(Ordering::Relaxed),
+            }
+        }
+    }
+
+    impl Clone for AccessCounters {
+        fn clone(&self) -> Self {
+            let stats = self.get();
+            Self {
+                reads: AtomicU64::new(stats.reads),
+                writes: AtomicU64::new(stats.writes),
+                translates: AtomicU64::new(stats.translates),
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "stats"))]
+mod counting {
+    /// Stands in for the counters when the `stats` feature is off: it has no state, and
+    /// recording does nothing.
+    #[derive(Clone)]
+    pub struct AccessCounters;
+
+    impl AccessCounters {
+        pub const fn new() -> Self {
+            Self
+        }
+
+        pub fn record_read(&self) {}
+
+        pub fn record_write(&self) {}
+
+        pub fn record_translate(&self) {}
+
+        pub fn absorb(&mut self, other: &Self) {}
+    }
+}
+
+pub(crate) use counting::AccessCounters;
@@ -0,0 +1,223 @@
+use core::fmt;
+use core::ops::{Add, AddAssign, Sub};
+
+/// An address in an `AddressSpace`.
+///
+/// This is deliberately a different type from the `usize` offsets into a `DataSource`, so the
+/// two can't be mixed up:
+/// ```compile_fail
+/// # use reedos_address_space::{AddressSpace, MemorySource, Permissions};
+/// # use std::sync::Arc;
+/// let mut addr_space = AddressSpace::new("typed");
+/// let offset: usize = 0x1000;
+/// let source = Arc::new(MemorySource::new(vec![0; 0x2000]));
+/// // the start of a mapping must be a `VirtualAddress`, not an offset
+/// addr_space.add_mapping_at(source, 0, 0x1000, offset, Permissions::READ);
+/// ```
+/// ```compile_fail
+/// # use reedos_address_space::{AddressSpace, MemorySource, Permissions};
+/// # use std::sync::Arc;
+/// let mut addr_space = AddressSpace::new("typed");
+/// let source = Arc::new(MemorySource::new(vec![0; 0x2000]));
+/// let addr = addr_space.add_mapping(source, 0, 0x1000, Permissions::READ).unwrap();
+/// // and an address is not an offset into a source either
+/// let offset: usize = addr;
+/// ```
+///
+/// Adding or subtracting a byte count gives another address, and subtracting two addresses
+/// gives the distance between them:
+/// ```
+/// # use reedos_address_space::VirtualAddress;
+/// let base = VirtualAddress::new(0x1000);
+/// let addr = base + 0x234;
+/// assert_eq!(addr - base, 0x234);
+/// assert_eq!(addr.align_up(0x1000), VirtualAddress::new(0x2000));
+/// assert_eq!(usize::from(addr), 0x1234);
+/// ```
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct VirtualAddress(usize);
+
+impl VirtualAddress {
+    #[must_use]
+    pub const fn new(addr: usize) -> Self {
+        Self(addr)
+    }
+
+    /// This address as a plain number.
+    #[must_use]
+    pub const fn as_usize(self) -> usize {
+        self.0
+    }
+
+    /// The address `bytes` bytes above this one; the same as `self + bytes`.
+    #[must_use]
+    pub const fn offset(self, bytes: usize) -> Self {
+        Self(self.0 + bytes)
+    }
+
+    /// The address `bytes` bytes above this one, or `None` if that would overflow.
+    #[must_use]
+    pub const fn checked_add(self, bytes: usize) -> Option<Self> {
+        match self.0.checked_add(bytes) {
+            Some(addr) => Some(Self(addr)),
+            None => None,
+        }
+    }
+
+    /// The address `bytes` bytes below this one, or `None` if that would go below zero.
+    #[must_use]
+    pub const fn checked_sub(self, bytes: usize) -> Option<Self> {
+        match self.0.checked_sub(bytes) {
+            Some(addr) => Some(Self(addr)),
+            None => None,
+        }
+    }
+
+    /// The lowest multiple of `align` at or above this address. `align` must be a power of
+    /// two.
+    #[must_use]
+    pub const fn align_up(self, align: usize) -> Self {
+        Self((self.0 + align - 1) & !(align - 1))
+    }
+
+    /// The lowest multiple of `align` at or above this address, or `None` if that would
+    /// overflow. `align` must be a power of two.
+    #[must_use]
+    pub const fn checked_align_up(self, align: usize) -> Option<Self> {
+        match self.0.checked_next_multiple_of(align) {
+            Some(addr) => Some(Self(addr)),
+            None => None,
+        }
+    }
+
+    /// The highest multiple of `align` at or below this address. `align` must be a power of
+    /// two.
+    #[must_use]
+    pub const fn align_down(self, align: usize) -> Self {
+        Self(self.0 & !(align - 1))
+    }
+
+    /// Whether this address is a multiple of `align`.
+    #[must_use]
+    pub const fn is_aligned(self, align: usize) -> bool {
+        self.0.is_multiple_of(align)
+    }
+}
+
+impl From<usize> for VirtualAddress {
+    fn from(addr: usize) -> Self {
+        Self(addr)
+    }
+}
+
+impl From<VirtualAddress> for usize {
+    fn from(addr: VirtualAddress) -> Self {
+        addr.0
+    }
+}
+
+impl Add<usize> for VirtualAddress {
+    type Output = Self;
+
+    fn add(self, bytes: usize) -> Self {
+        self.offset(bytes)
+    }
+}
+
+impl AddAssign<usize> for VirtualAddress {
+    fn add_assign(&mut self, bytes: usize) {
+        *self = *self + bytes;
+    }
+}
+
+impl Sub<usize> for VirtualAddress {
+    type Output = Self;
+
+    fn sub(self, bytes: usize) -> Self {
+        Self(self.0 - bytes)
+    }
+}
+
+impl Sub for VirtualAddress {
+    type Output = usize;
+
+    /// The number of bytes from `rhs` up to `self`.
+    fn sub(self, rhs: Self) -> usize {
+        self.0 - rhs.0
+    }
+}
+
+impl fmt::Debug for VirtualAddress {
+    /// Addresses are always shown in hex, e.g. `0x1000`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+impl fmt::LowerHex for VirtualAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::UpperHex for VirtualAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::UpperHex::fmt(&self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic() {
+        let addr = VirtualAddress::new(0x1800);
+        assert_eq!(addr + 0x800, VirtualAddress::new(0x2000));
+        assert_eq!(addr - 0x800, VirtualAddress::new(0x1000));
+        assert_eq!(addr - VirtualAddress::new(0x1000), 0x800);
+        assert_eq!(addr.offset(1), addr + 1);
+        assert_eq!(addr.checked_add(usize::MAX), None);
+        assert_eq!(addr.checked_sub(0x1800), Some(VirtualAddress::new(0)));
+        assert_eq!(addr.checked_sub(0x1801), None);
+        assert_eq!(
+            VirtualAddress::new(usize::MAX - 1).checked_add(1),
+            Some(VirtualAddress::new(usize::MAX))
+        );
+        let mut moving = addr;
+        moving += 0x10;
+        assert_eq!(moving.as_usize(), 0x1810);
+    }
+
+    #[test]
+    fn alignment() {
+        let addr = VirtualAddress::new(0x1800);
+        assert_eq!(addr.align_up(0x1000), VirtualAddress::new(0x2000));
+        assert_eq!(addr.align_down(0x1000), VirtualAddress::new(0x1000));
+        assert_eq!(
+            addr.checked_align_up(0x1000),
+            Some(VirtualAddress::new(0x2000))
+        );
+        assert_eq!(
+            VirtualAddress::new(usize::MAX).checked_align_up(0x1000),
+            None
+        );
+        assert!(addr.is_aligned(0x800));
+        assert!(!addr.is_aligned(0x1000));
+        assert_eq!(
+            VirtualAddress::new(0x2000).align_up(0x1000).as_usize(),
+            0x2000
+        );
+    }
+
+    #[test]
+    fn formats_as_hex() {
+        let addr = VirtualAddress::from(0xbeef);
+        assert_eq!(format!("{addr:#x}"), "0xbeef");
+        assert_eq!(format!("{addr:08X}"), "0000BEEF");
+        assert_eq!(format!("{addr:?}"), "0xbeef");
+        assert_eq!(usize::from(addr), 0xbeef);
+    }
+}
@@ -0,0 +1,28 @@
+// These tests map files, so they need `std`.
+#![cfg(feature = "std")]
+
+use std::sync::Arc;
+
+use reedos_address_space::{AddressSpace, DataSource, FileDataSource, MappingError, Permissions};
+
+#[test]
+fn add_two_remove_one() {
+    let mut addr_space = AddressSpace::new("integration");
+    let first: Arc<dyn DataSource> = Arc::new(FileDataSource::new("Cargo.toml").unwrap());
+    let second: Arc<dyn DataSource> = Arc::new(FileDataSource::new("Cargo.toml").unwrap());
+
+    let first_addr = addr_space
+        .add_mapping(first.clone(), 0, 16, Permissions::READ)
+        .unwrap();
+    let second_addr = addr_space
+        .add_mapping(second.clone(), 0, 16, Permissions::READ)
+        .unwrap();
+    assert_ne!(first_addr, second_addr);
+
+    addr_space.remove_mapping(&*first, first_addr).unwrap();
+    assert_eq!(
+        addr_space.remove_mapping(&*first, first_addr),
+        Err(MappingError::NotFound { addr: first_addr })
+    );
+    addr_space.remove_mapping(&*second, second_addr).unwrap();
+}